@@ -6,18 +6,18 @@ use core::task::{Context, Poll};
 
 use lazy_static::*;
 use riscv::register::sie;
-use spin::Mutex;
 use woke::waker_ref;
 
 use crate::async_rt::TaskId;
+use crate::sync::IrqMutex;
 use crate::syscall::sys_send_msg;
 
 use super::task::KernelTask;
 
 lazy_static! {
-    pub static ref KERNEL_TASK_QUEUE: Arc<Mutex<Box<KernelTaskQueue>>> =
+    pub static ref KERNEL_TASK_QUEUE: Arc<IrqMutex<Box<KernelTaskQueue>>> =
         Arc::new(
-            Mutex::new(
+            IrqMutex::new(
                 Box::new(
                     KernelTaskQueue {
                         queue: VecDeque::new()
@@ -53,8 +53,9 @@ impl KernelTaskQueue {
 pub fn run_until_idle() {
     loop {
         ext_int_off();
-        let mut queue = KERNEL_TASK_QUEUE.lock();
-        let task = queue.peek_task();
+        // only the future's own lock is held across `poll`, a future that
+        // blocks comes back here through `schedule`
+        let task = KERNEL_TASK_QUEUE.lock().peek_task();
         // debug!("running, queue len: {}, task: {:?}", queue.queue.len(), task.is_none());
         ext_int_on();
         match task {
@@ -65,28 +66,31 @@ pub fn run_until_idle() {
                 let mut context = Context::from_waker(&*waker);
 
                 let r = task.reactor.clone();
-                let mut r = r.lock();
+                let (ready, known) = {
+                    let r = r.lock();
+                    (r.is_ready(task.id), r.contains_task(task.id))
+                };
 
-                if r.is_ready(task.id) {
-                    let mut future = task.future.lock();
-                    match future.as_mut().poll(&mut context) {
+                if ready {
+                    let poll = task.future.lock().as_mut().poll(&mut context);
+                    match poll {
                         Poll::Ready(_) => {
                             // 任务完成
-                            r.finish_task(task.id);
+                            r.lock().finish_task(task.id);
                             let msg = task.user_task_id + 1 + usize::MAX / 2;
                             sys_send_msg(task.pid, msg);
                             return
                         }
                         Poll::Pending => {
-                            r.add_task(task.id);
+                            r.lock().add_task(task.id);
                         }
                     }
-                } else if r.contains_task(task.id) {
-                    r.add_task(task.id);
+                } else if known {
+                    r.lock().add_task(task.id);
                 } else {
-                    let mut future = task.future.lock();
                     debug!("first poll");
-                    match future.as_mut().poll(&mut context) {
+                    let poll = task.future.lock().as_mut().poll(&mut context);
+                    match poll {
                         Poll::Ready(_) => {
                             // 任务完成
                             debug!("task completed");
@@ -95,7 +99,7 @@ pub fn run_until_idle() {
                             return
                         }
                         Poll::Pending => {
-                            r.register(task.id);
+                            r.lock().register(task.id);
                         }
                     }
                 }
@@ -4,12 +4,13 @@ use alloc::sync::Arc;
 use core::mem;
 
 use lazy_static::*;
-use spin::Mutex;
+
+use crate::sync::IrqMutex;
 
 use super::TaskId;
 
 lazy_static! {
-    pub static ref REACTOR: Arc<Mutex<Box<Reactor>>> = Reactor::new();
+    pub static ref REACTOR: Arc<IrqMutex<Box<Reactor>>> = Reactor::new();
 }
 
 pub enum TaskState {
@@ -23,8 +24,8 @@ pub struct Reactor {
 }
 
 impl Reactor {
-    pub(crate) fn new() -> Arc<Mutex<Box<Self>>> {
-        let reactor = Arc::new(Mutex::new(Box::new(Reactor {
+    pub(crate) fn new() -> Arc<IrqMutex<Box<Self>>> {
+        let reactor = Arc::new(IrqMutex::new(Box::new(Reactor {
             tasks: BTreeMap::new(),
         })));
         reactor
@@ -5,9 +5,8 @@ use core::pin::Pin;
 use core::sync::atomic::{AtomicUsize, Ordering};
 use core::task::{Context, Poll};
 
-use spin::Mutex;
-
 use crate::async_rt::TaskState;
+use crate::sync::IrqMutex;
 use crate::syscall::sys_send_msg;
 
 use super::reactor::Reactor;
@@ -18,11 +17,11 @@ pub struct KernelTask {
     // 调用进程
     pub pid: usize,
     // 调用进程的Reactor
-    pub reactor: Arc<Mutex<Box<Reactor>>>,
+    pub reactor: Arc<IrqMutex<Box<Reactor>>>,
     // 用户任务id
     pub user_task_id: usize,
     // 调用的任务内容
-    pub future: Mutex<Pin<Box<dyn Future<Output=isize> + 'static + Send + Sync>>>, // 用UnsafeCell代替Mutex会好一点
+    pub future: IrqMutex<Pin<Box<dyn Future<Output=isize> + 'static + Send + Sync>>>, // 用UnsafeCell代替Mutex会好一点
 }
 
 #[derive(Eq, PartialEq, Debug, Clone, Copy, Hash, Ord, PartialOrd)]
@@ -42,7 +41,7 @@ impl TaskId {
 }
 
 impl KernelTask {
-    pub fn new(reactor: Arc<Mutex<Box<Reactor>>>, pid: usize, user_task_id: usize, fut: Mutex<Pin<Box<dyn Future<Output=isize> + 'static + Send + Sync>>>) -> Self {
+    pub fn new(reactor: Arc<IrqMutex<Box<Reactor>>>, pid: usize, user_task_id: usize, fut: IrqMutex<Pin<Box<dyn Future<Output=isize> + 'static + Send + Sync>>>) -> Self {
         KernelTask {
             id: TaskId::generate(),
             pid,
@@ -77,12 +76,14 @@ impl Future for KernelTask {
             r.add_task(self.id);
             Poll::Pending
         } else {
+            // not held while the future runs, it may block
+            drop(r);
             let mut f = self.future.lock();
             debug!("first poll for future in KernelTask");
             match f.as_mut().poll(cx) {
                 Poll::Ready(_) => Poll::Ready(0),
                 Poll::Pending => {
-                    r.register(self.id); // fixme
+                    self.reactor.lock().register(self.id); // fixme
                     Poll::Pending
                 }
             }
@@ -0,0 +1,30 @@
+//! Block devices, addressed in units of `BLOCK_SIZE` bytes.
+
+use crate::sync::IrqMutex;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::*;
+
+pub const BLOCK_SIZE: usize = 512;
+
+pub trait BlockDevice: Send + Sync {
+    fn num_blocks(&self) -> usize;
+    /// `buf` is exactly one block. May yield the current task while waiting.
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), isize>;
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), isize>;
+}
+
+lazy_static! {
+    static ref BLOCK_DEVICES: IrqMutex<Vec<Arc<dyn BlockDevice>>> = IrqMutex::new(Vec::new());
+}
+
+/// Returns the id the device is known by, in probe order.
+pub fn register_block_device(device: Arc<dyn BlockDevice>) -> usize {
+    let mut devices = BLOCK_DEVICES.lock();
+    devices.push(device);
+    devices.len() - 1
+}
+
+pub fn block_device(id: usize) -> Option<Arc<dyn BlockDevice>> {
+    BLOCK_DEVICES.lock().get(id).cloned()
+}
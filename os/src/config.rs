@@ -1,6 +1,9 @@
 pub const USER_STACK_SIZE: usize = 0x4000;
 pub const KERNEL_STACK_SIZE: usize = 0x4000;
 pub const KERNEL_HEAP_SIZE: usize = 0x20_0000;
+pub const KERNEL_HEAP_GROW_SIZE: usize = 0x8_0000;
+
+// board defaults, overridden by the device tree, see `fdt::board`
 
 #[cfg(feature = "board_qemu")]
 pub const MEMORY_END: usize = 0x80800000;
@@ -10,10 +13,17 @@ pub const MEMORY_END: usize = 0x100800000;
 
 pub const PAGE_SIZE: usize = 0x1000;
 pub const PAGE_SIZE_BITS: usize = 0xc;
+pub const HUGE_PAGE_SIZE: usize = 0x20_0000;
 
 pub const TRAMPOLINE: usize = usize::MAX - PAGE_SIZE + 1;
 pub const TRAP_CONTEXT: usize = TRAMPOLINE - PAGE_SIZE;
 pub const USER_TRAP_BUFFER: usize = TRAP_CONTEXT - PAGE_SIZE;
+pub const TIME_PAGE: usize = USER_TRAP_BUFFER - PAGE_SIZE;
+
+/// End of the lower half of Sv39, where ELF segments and the user stack go.
+pub const USER_SPACE_END: usize = 1 << 38;
+/// Load bias of position-independent executables.
+pub const ELF_DYN_BASE: usize = 0x10_0000_0000;
 
 #[cfg(feature = "board_qemu")]
 pub const CLOCK_FREQ: usize = 12500000;
@@ -21,4 +31,5 @@ pub const CLOCK_FREQ: usize = 12500000;
 #[cfg(feature = "board_lrv")]
 pub const CLOCK_FREQ: usize = 10_000_000;
 
+/// Most harts the kernel supports, sizes the per-hart tables and boot stacks.
 pub const CPU_NUM: usize = 4;
@@ -1,9 +1,9 @@
 use crate::sbi::console_putchar;
 use core::fmt::{self, Write};
 
+use crate::sync::IrqMutex;
 use alloc::sync::Arc;
 use lazy_static::*;
-use spin::Mutex;
 
 struct Stderr;
 
@@ -17,7 +17,7 @@ impl Write for Stderr {
 }
 
 lazy_static! {
-    static ref STDERR: Arc<Mutex<Stderr>> = Arc::new(Mutex::new(Stderr {}));
+    static ref STDERR: Arc<IrqMutex<Stderr>> = Arc::new(IrqMutex::new(Stderr {}));
 }
 
 /// Use ANSICON to format colorized string
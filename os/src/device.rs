@@ -0,0 +1,100 @@
+//! Registry of PLIC devices, and which of them user processes may claim.
+
+use crate::errno::{EBUSY, ENODEV, EPERM};
+use crate::sync::IrqMutex;
+use crate::trap::USER_EXT_INT_MAP;
+use alloc::vec::Vec;
+use lazy_static::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClaimPolicy {
+    /// Never delegated, e.g. the console.
+    KernelOnly,
+    /// Held by one process at a time, released when it exits.
+    Exclusive,
+}
+
+#[derive(Debug, Clone)]
+pub struct Device {
+    pub name: &'static str,
+    pub irq: u16,
+    /// `[start, end)` physical ranges mapped into the claiming process.
+    pub mmio: Vec<(usize, usize)>,
+    pub policy: ClaimPolicy,
+    /// Called from the PLIC handler while no process holds the device.
+    pub handler: Option<fn(u16)>,
+}
+
+lazy_static! {
+    static ref DEVICES: IrqMutex<Vec<Device>> = IrqMutex::new(Vec::new());
+}
+
+pub fn register_device(device: Device) {
+    let mut devices = DEVICES.lock();
+    assert!(
+        devices.iter().all(|d| d.irq != device.irq),
+        "irq {} registered twice",
+        device.irq
+    );
+    debug!(
+        "[device] {} irq {} mmio {:x?} {:?}",
+        device.name, device.irq, device.mmio, device.policy
+    );
+    devices.push(device);
+}
+
+pub fn irqs() -> Vec<u16> {
+    DEVICES.lock().iter().map(|d| d.irq).collect()
+}
+
+pub fn get_device(irq: u16) -> Option<Device> {
+    DEVICES.lock().iter().find(|d| d.irq == irq).cloned()
+}
+
+/// Record `pid` as the owner of the device at `irq`. Claiming a device
+/// already held by `pid` succeeds again, the process may have lost its
+/// mappings to an exec.
+pub fn claim_device(irq: u16, pid: usize) -> Result<Device, isize> {
+    let device = get_device(irq).ok_or(ENODEV)?;
+    if device.policy == ClaimPolicy::KernelOnly {
+        return Err(EPERM);
+    }
+    let mut map = USER_EXT_INT_MAP.lock();
+    match map.get(&irq) {
+        Some(owner) if *owner != pid => Err(EBUSY),
+        Some(_) => Ok(device),
+        None => {
+            map.insert(irq, pid);
+            Ok(device)
+        }
+    }
+}
+
+/// Undo a `claim_device` by `pid`.
+pub fn release_device(irq: u16, pid: usize) {
+    let mut map = USER_EXT_INT_MAP.lock();
+    if map.get(&irq) == Some(&pid) {
+        map.remove(&irq);
+    }
+}
+
+/// Whether a process holds the device at `irq`.
+pub fn is_claimed(irq: u16) -> bool {
+    USER_EXT_INT_MAP.lock().contains_key(&irq)
+}
+
+/// Run the kernel handler of `irq`, returns whether there was one.
+pub fn handle_irq(irq: u16) -> bool {
+    let handler = DEVICES
+        .lock()
+        .iter()
+        .find(|d| d.irq == irq)
+        .and_then(|d| d.handler);
+    match handler {
+        Some(handler) => {
+            handler(irq);
+            true
+        }
+        None => false,
+    }
+}
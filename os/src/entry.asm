@@ -1,7 +1,7 @@
     .section .text.entry
     .globl _start
 _start:
-    # a0: hart id
+    # a0: hart id, a1: device tree (boot hart only)
     mv tp, a0
     la sp, boot_stack
     # li t1, 4096 * 16 # t1 = 4096 * 16 64KB
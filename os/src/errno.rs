@@ -0,0 +1,29 @@
+//! Negative error numbers returned by syscalls, following the Linux values.
+
+pub const EPERM: isize = -1;
+pub const ENOENT: isize = -2;
+pub const ESRCH: isize = -3;
+pub const EINTR: isize = -4;
+pub const EIO: isize = -5;
+pub const E2BIG: isize = -7;
+pub const ENOEXEC: isize = -8;
+pub const EBADF: isize = -9;
+pub const EAGAIN: isize = -11;
+pub const ENOMEM: isize = -12;
+pub const EFAULT: isize = -14;
+pub const EBUSY: isize = -16;
+pub const ENODEV: isize = -19;
+pub const EINVAL: isize = -22;
+pub const ENOTTY: isize = -25;
+pub const EROFS: isize = -30;
+pub const EPIPE: isize = -32;
+pub const ELOOP: isize = -40;
+pub const ENOTSOCK: isize = -88;
+pub const EDESTADDRREQ: isize = -89;
+pub const EMSGSIZE: isize = -90;
+pub const EOPNOTSUPP: isize = -95;
+pub const EADDRINUSE: isize = -98;
+pub const ENETDOWN: isize = -100;
+pub const EISCONN: isize = -106;
+pub const ENOTCONN: isize = -107;
+pub const ECONNREFUSED: isize = -111;
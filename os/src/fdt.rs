@@ -0,0 +1,312 @@
+//! Board discovery from the flattened device tree the SBI passes in a1.
+//!
+//! Only what the kernel needs is pulled out, into a `BoardInfo` that outlives
+//! the blob: the frame allocator is free to reuse its memory afterwards.
+
+use crate::config::{CLOCK_FREQ, CPU_NUM, MEMORY_END};
+use crate::plic::PLIC_BASE;
+use crate::uart::{
+    SERIAL_ADDRESS_STRIDE, SERIAL_BASE_ADDRESS, SERIAL_COMPATIBLE, SERIAL_IRQ_BASE, SERIAL_NUM,
+};
+use spin::Once;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+const MAX_DEPTH: usize = 16;
+pub const MAX_UARTS: usize = 8;
+pub const MAX_VIRTIO: usize = 8;
+
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceInfo {
+    pub base: usize,
+    pub irq: u16,
+}
+
+#[derive(Debug)]
+pub struct BoardInfo {
+    pub memory_end: usize,
+    /// Harts `0..hart_count` are brought up, at most `CPU_NUM`.
+    pub hart_count: usize,
+    pub clock_freq: usize,
+    /// Only checked against `plic::PLIC_BASE`, the PLIC is not relocatable.
+    pub plic_base: usize,
+    /// UARTs left to the kernel, the firmware console excluded.
+    pub uarts: heapless::Vec<DeviceInfo, MAX_UARTS>,
+    /// virtio-mmio slots, each one page, possibly empty.
+    pub virtio: heapless::Vec<DeviceInfo, MAX_VIRTIO>,
+}
+
+/// The compile-time board configuration, used without a device tree.
+impl Default for BoardInfo {
+    fn default() -> Self {
+        let mut uarts = heapless::Vec::new();
+        for i in 0..SERIAL_NUM {
+            let _ = uarts.push(DeviceInfo {
+                base: SERIAL_BASE_ADDRESS + i * SERIAL_ADDRESS_STRIDE,
+                irq: SERIAL_IRQ_BASE + i as u16,
+            });
+        }
+        BoardInfo {
+            memory_end: MEMORY_END,
+            hart_count: CPU_NUM,
+            clock_freq: CLOCK_FREQ,
+            plic_base: PLIC_BASE,
+            uarts,
+            virtio: heapless::Vec::new(),
+        }
+    }
+}
+
+static BOARD: Once<BoardInfo> = Once::new();
+
+pub fn board() -> &'static BoardInfo {
+    BOARD.call_once(BoardInfo::default)
+}
+
+/// Parse the device tree at physical address `dtb`. Must run on the boot hart
+/// before paging is enabled and before anything reads `board()`.
+pub fn init(dtb: usize) {
+    let board = BOARD.call_once(|| match unsafe { parse(dtb) } {
+        Some(board) => board,
+        None => {
+            warn!(
+                "[fdt] no device tree at {:#x}, using built-in board config",
+                dtb
+            );
+            BoardInfo::default()
+        }
+    });
+    info!("[fdt] {:x?}", board);
+    // PLIC discovery is out of scope: `Plic` and the user-space driver take
+    // the base as a const generic of rv-plic, so a board with the PLIC
+    // elsewhere needs a rebuild with another `PLIC_BASE`
+    assert_eq!(
+        board.plic_base, PLIC_BASE,
+        "[fdt] PLIC at {:#x}, but the kernel is built for {:#x}",
+        board.plic_base, PLIC_BASE
+    );
+}
+
+fn be32(bytes: &[u8], offset: usize) -> u32 {
+    let mut word = [0; 4];
+    word.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_be_bytes(word)
+}
+
+/// A number made of `cells` big-endian u32s.
+fn read_cells(bytes: &[u8], offset: usize, cells: usize) -> usize {
+    (0..cells).fold(0, |acc, i| {
+        (acc << 32) | be32(bytes, offset + i * 4) as usize
+    })
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+/// NUL-terminated string at the start of `bytes`.
+fn c_str(bytes: &[u8]) -> &[u8] {
+    let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    &bytes[..len]
+}
+
+fn has_compatible(list: &[u8], compatible: &[&str]) -> bool {
+    list.split(|b| *b == 0)
+        .any(|c| compatible.iter().any(|s| s.as_bytes() == c))
+}
+
+/// Properties of the node being walked, kept for every level on the path.
+#[derive(Clone, Copy)]
+struct Node<'a> {
+    name: &'a [u8],
+    device_type: &'a [u8],
+    compatible: &'a [u8],
+    /// First `reg` entry, decoded with the parent's cell sizes.
+    reg: Option<(usize, usize)>,
+    irq: Option<u32>,
+    disabled: bool,
+    timebase: Option<usize>,
+    stdout_path: &'a [u8],
+    /// Cell sizes for the children.
+    address_cells: usize,
+    size_cells: usize,
+}
+
+impl<'a> Node<'a> {
+    fn new(name: &'a [u8]) -> Self {
+        Node {
+            name,
+            device_type: &[],
+            compatible: &[],
+            reg: None,
+            irq: None,
+            disabled: false,
+            timebase: None,
+            stdout_path: &[],
+            address_cells: 2,
+            size_cells: 1,
+        }
+    }
+}
+
+unsafe fn parse(dtb: usize) -> Option<BoardInfo> {
+    if dtb == 0 || dtb % 4 != 0 {
+        return None;
+    }
+    let header = core::slice::from_raw_parts(dtb as *const u8, 40);
+    if be32(header, 0) != FDT_MAGIC {
+        return None;
+    }
+    let blob = core::slice::from_raw_parts(dtb as *const u8, be32(header, 4) as usize);
+    let structs = &blob[be32(header, 8) as usize..];
+    let strings = &blob[be32(header, 12) as usize..];
+
+    let mut memory: Option<(usize, usize)> = None;
+    let mut hart_count = 0;
+    let mut clock_freq = None;
+    let mut plic_base = None;
+    let mut uarts: heapless::Vec<DeviceInfo, MAX_UARTS> = heapless::Vec::new();
+    let mut virtio: heapless::Vec<DeviceInfo, MAX_VIRTIO> = heapless::Vec::new();
+    let mut stdout_path: &[u8] = &[];
+
+    let mut stack = [Node::new(&[]); MAX_DEPTH];
+    let mut depth = 0;
+    let mut offset = 0;
+    loop {
+        let token = be32(structs, offset);
+        offset += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let name = c_str(&structs[offset..]);
+                offset = align4(offset + name.len() + 1);
+                if depth + 1 >= MAX_DEPTH {
+                    warn!("[fdt] nested too deep");
+                    return None;
+                }
+                depth += 1;
+                stack[depth] = Node::new(name);
+            }
+            FDT_END_NODE => {
+                if depth == 0 {
+                    return None;
+                }
+                let node = &stack[depth];
+                let is_memory = node.device_type == b"memory" || node.name.starts_with(b"memory@");
+                if is_memory && memory.is_none() {
+                    memory = node.reg;
+                } else if node.device_type == b"cpu" && !node.disabled {
+                    hart_count += 1;
+                    clock_freq = clock_freq.or(node.timebase);
+                } else if node.name == b"cpus" {
+                    clock_freq = node.timebase.or(clock_freq);
+                } else if node.name == b"chosen" {
+                    stdout_path = node.stdout_path;
+                } else if has_compatible(node.compatible, &["riscv,plic0", "sifive,plic-1.0.0"]) {
+                    plic_base = node.reg.map(|(base, _)| base);
+                } else if has_compatible(node.compatible, SERIAL_COMPATIBLE) && !node.disabled {
+                    if let (Some((base, _)), Some(irq)) = (node.reg, node.irq) {
+                        if uarts
+                            .push(DeviceInfo {
+                                base,
+                                irq: irq as u16,
+                            })
+                            .is_err()
+                        {
+                            warn!("[fdt] too many UARTs, ignoring {:#x}", base);
+                        }
+                    }
+                } else if has_compatible(node.compatible, &["virtio,mmio"]) && !node.disabled {
+                    if let (Some((base, _)), Some(irq)) = (node.reg, node.irq) {
+                        if virtio
+                            .push(DeviceInfo {
+                                base,
+                                irq: irq as u16,
+                            })
+                            .is_err()
+                        {
+                            warn!("[fdt] too many virtio slots, ignoring {:#x}", base);
+                        }
+                    }
+                }
+                depth -= 1;
+            }
+            FDT_PROP => {
+                let len = be32(structs, offset) as usize;
+                let name = c_str(&strings[be32(structs, offset + 4) as usize..]);
+                let value = &structs[offset + 8..offset + 8 + len];
+                offset = align4(offset + 8 + len);
+                let (address_cells, size_cells) = {
+                    let parent = &stack[depth.saturating_sub(1)];
+                    (parent.address_cells, parent.size_cells)
+                };
+                let node = &mut stack[depth];
+                match name {
+                    b"device_type" => node.device_type = c_str(value),
+                    b"compatible" => node.compatible = value,
+                    b"reg" if len >= (address_cells + size_cells) * 4 => {
+                        node.reg = Some((
+                            read_cells(value, 0, address_cells),
+                            read_cells(value, address_cells * 4, size_cells),
+                        ));
+                    }
+                    b"interrupts" if len >= 4 => node.irq = Some(be32(value, 0)),
+                    b"status" => node.disabled = c_str(value) == b"disabled",
+                    b"timebase-frequency" if len >= 4 => {
+                        node.timebase = Some(read_cells(value, 0, len.min(8) / 4));
+                    }
+                    b"stdout-path" => node.stdout_path = c_str(value),
+                    b"#address-cells" if len >= 4 => {
+                        node.address_cells = be32(value, 0) as usize;
+                    }
+                    b"#size-cells" if len >= 4 => node.size_cells = be32(value, 0) as usize,
+                    _ => {}
+                }
+            }
+            FDT_NOP => {}
+            FDT_END => break,
+            _ => {
+                warn!("[fdt] bad token {:#x} at {:#x}", token, offset - 4);
+                return None;
+            }
+        }
+    }
+
+    // the firmware keeps its console, e.g. "/soc/serial@10000000:115200"
+    let console = unit_address(stdout_path);
+    let mut uarts: heapless::Vec<DeviceInfo, MAX_UARTS> = uarts
+        .iter()
+        .filter(|uart| Some(uart.base) != console)
+        .cloned()
+        .collect();
+    uarts.sort_unstable_by_key(|uart| uart.base);
+    virtio.sort_unstable_by_key(|dev| dev.base);
+
+    let defaults = BoardInfo::default();
+    if hart_count > CPU_NUM {
+        warn!("[fdt] {} harts, only {} supported", hart_count, CPU_NUM);
+    }
+    Some(BoardInfo {
+        memory_end: memory.map_or(defaults.memory_end, |(base, size)| base + size),
+        hart_count: match hart_count {
+            0 => defaults.hart_count,
+            n => n.min(CPU_NUM),
+        },
+        clock_freq: clock_freq.unwrap_or(defaults.clock_freq),
+        plic_base: plic_base.unwrap_or(defaults.plic_base),
+        uarts,
+        virtio,
+    })
+}
+
+/// The hex unit address in a node path like "/soc/serial@10000000:115200".
+fn unit_address(path: &[u8]) -> Option<usize> {
+    let at = path.iter().rposition(|b| *b == b'@')?;
+    let digits = path[at + 1..].split(|b| *b == b':').next()?;
+    let digits = core::str::from_utf8(digits).ok()?;
+    usize::from_str_radix(digits, 16).ok()
+}
@@ -1,8 +1,8 @@
+use crate::sync::IrqMutex;
 use alloc::{
     collections::VecDeque,
     sync::{Arc, Weak},
 };
-use spin::Mutex;
 
 use crate::mm::UserBuffer;
 use crate::task::suspend_current_and_run_next;
@@ -13,17 +13,17 @@ const MAIL_BUFFER_SIZE: usize = 256;
 const MAILBOX_SIZE: usize = 16;
 
 pub struct MailBox {
-    inner: Mutex<MailBoxInner>,
+    inner: IrqMutex<MailBoxInner>,
 }
 
 pub struct MailBoxInner {
-    mails: VecDeque<Arc<Mutex<MailRingBuffer>>>,
+    mails: VecDeque<Arc<IrqMutex<MailRingBuffer>>>,
 }
 
 impl MailBox {
     pub fn new() -> Self {
         Self {
-            inner: Mutex::new(MailBoxInner {
+            inner: IrqMutex::new(MailBoxInner {
                 mails: VecDeque::new(),
             }),
         }
@@ -31,7 +31,7 @@ impl MailBox {
 
     pub fn create_socket(&self) -> Arc<Socket> {
         debug!("create socket");
-        let buffer = Arc::new(Mutex::new(MailRingBuffer::new()));
+        let buffer = Arc::new(IrqMutex::new(MailRingBuffer::new()));
         let write_end = Arc::new(Socket::write_end_with_buffer(buffer.clone()));
         buffer.lock().set_write_end(&write_end);
         self.inner.lock().mails.push_back(buffer);
@@ -98,11 +98,11 @@ impl File for MailBox {
 
 pub struct Socket {
     writable: bool,
-    mail: Arc<Mutex<MailRingBuffer>>,
+    mail: Arc<IrqMutex<MailRingBuffer>>,
 }
 
 impl Socket {
-    pub fn write_end_with_buffer(buffer: Arc<Mutex<MailRingBuffer>>) -> Self {
+    pub fn write_end_with_buffer(buffer: Arc<IrqMutex<MailRingBuffer>>) -> Self {
         Self {
             writable: true,
             mail: buffer,
@@ -1,14 +1,42 @@
 mod mail;
+pub mod packfs;
 mod pipe;
-mod serial;
+pub mod serial;
 pub mod stdio;
+pub mod tty;
 
+use crate::errno::{EINVAL, ENOTTY};
 use crate::mm::UserBuffer;
+use crate::net::NetSocket;
 
 pub use mail::{MailBox, Socket};
+
+pub const O_RDWR: u32 = 0o2;
+/// Reads and writes fail with EAGAIN instead of blocking.
+pub const O_NONBLOCK: u32 = 0o4000;
+
 pub trait File: Send + Sync {
     fn read(&self, buf: UserBuffer) -> Result<usize, isize>;
     fn write(&self, buf: UserBuffer) -> Result<usize, isize>;
+    /// Device specific requests, only terminals have any so far.
+    fn ioctl(&self, _request: usize, _arg: usize) -> Result<isize, isize> {
+        Err(ENOTTY)
+    }
+    /// Status flags for `fcntl`, only `O_NONBLOCK` so far.
+    fn status_flags(&self) -> u32 {
+        0
+    }
+    fn set_status_flags(&self, flags: u32) -> Result<(), isize> {
+        if flags & O_NONBLOCK != 0 {
+            Err(EINVAL)
+        } else {
+            Ok(())
+        }
+    }
+    /// For the socket syscalls.
+    fn as_socket(&self) -> Option<&NetSocket> {
+        None
+    }
 }
 
 pub use pipe::{make_pipe, Pipe};
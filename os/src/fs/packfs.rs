@@ -0,0 +1,184 @@
+//! The read-only image built by `packfs` on the host: a superblock, a flat
+//! directory of 64-byte entries from block 1, then the data of each file
+//! starting on a block boundary. All fields are little endian.
+
+use super::File;
+use crate::block::{block_device, BlockDevice, BLOCK_SIZE};
+use crate::errno::{EBADF, EINVAL, ENOENT};
+use crate::mm::UserBuffer;
+use crate::sync::IrqMutex;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use spin::Once;
+
+/// "RNFS"
+const MAGIC: u32 = 0x5346_4e52;
+const VERSION: u32 = 1;
+const NAME_LEN: usize = 48;
+const ENTRY_SIZE: usize = 64;
+
+#[derive(Debug, Clone)]
+struct DirEntry {
+    name: String,
+    /// First block of the data.
+    start: usize,
+    size: usize,
+}
+
+struct PackFs {
+    device: Arc<dyn BlockDevice>,
+    entries: Vec<DirEntry>,
+}
+
+static PACKFS: Once<PackFs> = Once::new();
+
+fn le32(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+fn le64(buf: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(buf[at..at + 8].try_into().unwrap())
+}
+
+/// Mount the image on the first block device, if there is one.
+pub fn init() {
+    let device = match block_device(0) {
+        Some(device) => device,
+        None => return,
+    };
+    match PackFs::mount(device) {
+        Ok(fs) => {
+            info!("[packfs] {} files", fs.entries.len());
+            PACKFS.call_once(|| fs);
+        }
+        Err(errno) => warn!("[packfs] block device 0 holds no image: {}", errno),
+    }
+}
+
+impl PackFs {
+    fn mount(device: Arc<dyn BlockDevice>) -> Result<Self, isize> {
+        let mut block = [0u8; BLOCK_SIZE];
+        device.read_block(0, &mut block)?;
+        if le32(&block, 0) != MAGIC || le32(&block, 4) != VERSION {
+            return Err(EINVAL);
+        }
+        let count = le32(&block, 8) as usize;
+        let data_start = 1 + (count * ENTRY_SIZE + BLOCK_SIZE - 1) / BLOCK_SIZE;
+        if data_start > device.num_blocks() {
+            return Err(EINVAL);
+        }
+        let mut entries = Vec::with_capacity(count);
+        for i in 0..count {
+            let offset = i * ENTRY_SIZE % BLOCK_SIZE;
+            if offset == 0 {
+                device.read_block(1 + i * ENTRY_SIZE / BLOCK_SIZE, &mut block)?;
+            }
+            let raw = &block[offset..offset + ENTRY_SIZE];
+            let name_len = raw[..NAME_LEN]
+                .iter()
+                .position(|&b| b == 0)
+                .unwrap_or(NAME_LEN);
+            let name = core::str::from_utf8(&raw[..name_len]).map_err(|_| EINVAL)?;
+            let entry = DirEntry {
+                name: name.into(),
+                start: le64(raw, NAME_LEN) as usize,
+                size: le64(raw, NAME_LEN + 8) as usize,
+            };
+            let blocks = entry.size / BLOCK_SIZE + (entry.size % BLOCK_SIZE != 0) as usize;
+            match entry.start.checked_add(blocks) {
+                Some(end) if entry.start >= data_start && end <= device.num_blocks() => {}
+                _ => return Err(EINVAL),
+            }
+            entries.push(entry);
+        }
+        Ok(PackFs { device, entries })
+    }
+
+    fn lookup(&self, path: &str) -> Option<&DirEntry> {
+        let name = path.trim_start_matches('/');
+        self.entries.iter().find(|entry| entry.name == name)
+    }
+
+    /// Returns the bytes read, short at the end of the file.
+    fn read_at(&self, entry: &DirEntry, offset: usize, buf: &mut [u8]) -> Result<usize, isize> {
+        let end = entry.size.min(offset.saturating_add(buf.len()));
+        let mut block = [0u8; BLOCK_SIZE];
+        let mut pos = offset;
+        while pos < end {
+            self.device
+                .read_block(entry.start + pos / BLOCK_SIZE, &mut block)?;
+            let in_block = pos % BLOCK_SIZE;
+            let n = (BLOCK_SIZE - in_block).min(end - pos);
+            buf[pos - offset..][..n].copy_from_slice(&block[in_block..][..n]);
+            pos += n;
+        }
+        Ok(end.saturating_sub(offset))
+    }
+}
+
+fn find(path: &str) -> Result<(&'static PackFs, &'static DirEntry), isize> {
+    let fs = PACKFS.get().ok_or(ENOENT)?;
+    let entry = fs.lookup(path).ok_or(ENOENT)?;
+    Ok((fs, entry))
+}
+
+/// The whole file at `path`, `ENOENT` also when nothing is mounted.
+pub fn read_all(path: &str) -> Result<Vec<u8>, isize> {
+    let (fs, entry) = find(path)?;
+    let mut data = vec![0; entry.size];
+    fs.read_at(entry, 0, &mut data)?;
+    Ok(data)
+}
+
+/// Names and sizes of the files in the image.
+pub fn list() -> Vec<(String, usize)> {
+    PACKFS
+        .get()
+        .map(|fs| {
+            fs.entries
+                .iter()
+                .map(|entry| (entry.name.clone(), entry.size))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// A file of the image opened for reading.
+pub struct PackFile {
+    fs: &'static PackFs,
+    entry: &'static DirEntry,
+    offset: IrqMutex<usize>,
+}
+
+pub fn open(path: &str) -> Result<PackFile, isize> {
+    let (fs, entry) = find(path)?;
+    Ok(PackFile {
+        fs,
+        entry,
+        offset: IrqMutex::new(0),
+    })
+}
+
+impl File for PackFile {
+    fn read(&self, mut buf: UserBuffer) -> Result<usize, isize> {
+        // not held across the block reads, which may yield
+        let offset = *self.offset.lock();
+        let mut read_size = 0;
+        for slice in buf.buffers.iter_mut() {
+            let n = self.fs.read_at(self.entry, offset + read_size, slice)?;
+            read_size += n;
+            if n < slice.len() {
+                break;
+            }
+        }
+        *self.offset.lock() = offset + read_size;
+        Ok(read_size)
+    }
+
+    fn write(&self, _buf: UserBuffer) -> Result<usize, isize> {
+        Err(EBADF)
+    }
+}
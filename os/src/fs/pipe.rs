@@ -1,24 +1,24 @@
 use super::File;
 use crate::mm::UserBuffer;
+use crate::sync::IrqMutex;
 use crate::task::suspend_current_and_run_next;
 use alloc::sync::{Arc, Weak};
-use spin::Mutex;
 
 pub struct Pipe {
     pub(crate) readable: bool,
     pub(crate) writable: bool,
-    pub(crate) buffer: Arc<Mutex<PipeRingBuffer>>,
+    pub(crate) buffer: Arc<IrqMutex<PipeRingBuffer>>,
 }
 
 impl Pipe {
-    pub fn read_end_with_buffer(buffer: Arc<Mutex<PipeRingBuffer>>) -> Self {
+    pub fn read_end_with_buffer(buffer: Arc<IrqMutex<PipeRingBuffer>>) -> Self {
         Self {
             readable: true,
             writable: false,
             buffer,
         }
     }
-    pub fn write_end_with_buffer(buffer: Arc<Mutex<PipeRingBuffer>>) -> Self {
+    pub fn write_end_with_buffer(buffer: Arc<IrqMutex<PipeRingBuffer>>) -> Self {
         Self {
             readable: false,
             writable: true,
@@ -97,7 +97,7 @@ impl PipeRingBuffer {
 
 /// Return (read_end, write_end)
 pub fn make_pipe() -> (Arc<Pipe>, Arc<Pipe>) {
-    let buffer = Arc::new(Mutex::new(PipeRingBuffer::new()));
+    let buffer = Arc::new(IrqMutex::new(PipeRingBuffer::new()));
     let read_end = Arc::new(Pipe::read_end_with_buffer(buffer.clone()));
     let write_end = Arc::new(Pipe::write_end_with_buffer(buffer.clone()));
     buffer.lock().set_write_end(&write_end);
@@ -1,25 +1,163 @@
-use super::File;
-use crate::mm::UserBuffer;
-use crate::uart::{serial_getchar, serial_putchar};
+use super::{File, O_NONBLOCK, O_RDWR};
+use crate::device::is_claimed;
+use crate::errno::{EAGAIN, EBUSY, EINTR, EINVAL, ENODEV, ENOENT, ENOTTY};
+use crate::fdt::board;
+use crate::mm::{copy_from_user, copy_to_user, UserBuffer};
+use crate::task::{
+    block_current_and_run_next, current_signal_pending, current_task, current_user_token,
+};
+use crate::uart::{serial_write, SerialConfig, BUFFERED_SERIAL};
+use core::sync::atomic::{AtomicBool, Ordering};
+use embedded_hal::serial::{Read, Write};
 
-pub struct Serial<const N: usize>;
+/// `tcdrain` with a nonzero argument, which is all it does here.
+pub const TCSBRK: usize = 0x5409;
+/// Get and set the `SerialConfig` of the port, not in Linux.
+pub const SERIAL_GET_CONFIG: usize = 0x5480;
+pub const SERIAL_SET_CONFIG: usize = 0x5481;
+/// A trap record with the argument as message once what was written so far
+/// is sent, for the async write path. EAGAIN while too many are pending.
+pub const SERIAL_NOTIFY_TX: usize = 0x5482;
 
-impl<const N: usize> File for Serial<N> {
+/// A UART other than the console, `/dev/ttyS<id>`.
+pub struct Serial {
+    id: usize,
+    nonblock: AtomicBool,
+}
+
+impl Serial {
+    pub fn new(id: usize) -> Self {
+        Serial {
+            id,
+            nonblock: AtomicBool::new(false),
+        }
+    }
+
+    /// Sleeps until the last byte written left the shift register (LSR_TEMT).
+    fn drain(&self) -> Result<(), isize> {
+        let serial = BUFFERED_SERIAL.get(self.id).ok_or(ENODEV)?;
+        loop {
+            let mut serial = serial.lock();
+            if current_signal_pending() {
+                serial.tx_waiters.remove_current();
+                return Err(EINTR);
+            }
+            if serial.try_flush().is_ok() {
+                return Ok(());
+            }
+            serial.tx_waiters.add_current();
+            drop(serial);
+            block_current_and_run_next();
+        }
+    }
+}
+
+/// Opens `ttyS<id>` under `/dev`. Serial 0 is the console, and a port a
+/// process claimed for its own driver is busy. Only read-only or read-write
+/// opens, optionally `O_NONBLOCK`, are supported.
+pub fn open(name: &str, flags: u32) -> Result<Serial, isize> {
+    if flags & !(O_RDWR | O_NONBLOCK) != 0 {
+        return Err(EINVAL);
+    }
+    let id = name
+        .strip_prefix("ttyS")
+        .and_then(|id| id.parse::<usize>().ok())
+        .filter(|&id| id < BUFFERED_SERIAL.len())
+        .ok_or(ENOENT)?;
+    if id == 0 || is_claimed(board().uarts[id].irq) {
+        return Err(EBUSY);
+    }
+    let serial = Serial::new(id);
+    serial.set_status_flags(flags)?;
+    Ok(serial)
+}
+
+impl File for Serial {
+    /// Returns what arrived so far, sleeping until the receive interrupt if
+    /// nothing did.
     fn read(&self, mut user_buf: UserBuffer) -> Result<usize, isize> {
-        assert_eq!(user_buf.len(), 1);
-        // busy loop
-        let ch = serial_getchar(N);
-        unsafe {
-            user_buf.buffers[0].as_mut_ptr().write_volatile(ch);
+        let serial = BUFFERED_SERIAL.get(self.id).ok_or(ENODEV)?;
+        if user_buf.len() == 0 {
+            return Ok(0);
+        }
+        loop {
+            let mut serial = serial.lock();
+            if current_signal_pending() {
+                serial.rx_waiters.remove_current();
+                return Err(EINTR);
+            }
+            let mut read_size = 0;
+            'copy: for slice in user_buf.buffers.iter_mut() {
+                for byte in slice.iter_mut() {
+                    match serial.try_read() {
+                        Ok(ch) => {
+                            *byte = ch;
+                            read_size += 1;
+                        }
+                        Err(_) => break 'copy,
+                    }
+                }
+            }
+            if read_size > 0 {
+                return Ok(read_size);
+            }
+            if self.nonblock.load(Ordering::Relaxed) {
+                serial.rx_waiters.remove_current();
+                return Err(EAGAIN);
+            }
+            serial.rx_waiters.add_current();
+            drop(serial);
+            block_current_and_run_next();
         }
-        Ok(1)
     }
+    /// Sleeps while the Tx buffer is full.
     fn write(&self, user_buf: UserBuffer) -> Result<usize, isize> {
+        let mut written = 0;
         for buffer in user_buf.buffers.iter() {
-            for char in buffer.iter() {
-                serial_putchar(N, *char);
+            match serial_write(self.id, buffer) {
+                Ok(sent) => {
+                    written += sent;
+                    if sent < buffer.len() {
+                        break;
+                    }
+                }
+                Err(errno) if written == 0 => return Err(errno),
+                Err(_) => break,
+            }
+        }
+        Ok(written)
+    }
+    fn ioctl(&self, request: usize, arg: usize) -> Result<isize, isize> {
+        let serial = BUFFERED_SERIAL.get(self.id).ok_or(ENODEV)?;
+        let token = current_user_token();
+        match request {
+            TCSBRK if arg != 0 => self.drain()?,
+            SERIAL_GET_CONFIG => {
+                let config = serial.lock().config;
+                copy_to_user(token, arg as *mut SerialConfig, &config)?;
+            }
+            SERIAL_SET_CONFIG => {
+                let config = copy_from_user(token, arg as *const SerialConfig)?;
+                serial.lock().configure(config)?;
+            }
+            SERIAL_NOTIFY_TX => {
+                let pid = current_task().unwrap().getpid();
+                serial.lock().notify_tx(pid, arg)?;
             }
+            _ => return Err(ENOTTY),
         }
-        Ok(user_buf.len())
+        Ok(0)
+    }
+    fn status_flags(&self) -> u32 {
+        if self.nonblock.load(Ordering::Relaxed) {
+            O_NONBLOCK
+        } else {
+            0
+        }
+    }
+    fn set_status_flags(&self, flags: u32) -> Result<(), isize> {
+        self.nonblock
+            .store(flags & O_NONBLOCK != 0, Ordering::Relaxed);
+        Ok(())
     }
 }
@@ -1,37 +1,51 @@
-use super::File;
+use super::tty::console;
+use super::{File, O_NONBLOCK};
 use crate::mm::UserBuffer;
-use crate::print;
-use crate::uart::{serial_getchar, serial_putchar};
+use crate::uart::serial_putchar;
 use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
 
-pub struct Stdin;
+/// Both ends of the console terminal, like every fd open on a tty.
+#[derive(Default)]
+pub struct Stdin {
+    nonblock: AtomicBool,
+}
 
 pub struct Stdout;
 
 impl File for Stdin {
-    fn read(&self, mut user_buf: UserBuffer) -> Result<usize, isize> {
-        assert_eq!(user_buf.len(), 1);
-        // busy loop
-        let ch = serial_getchar(0);
-        unsafe {
-            user_buf.buffers[0].as_mut_ptr().write_volatile(ch);
+    fn read(&self, user_buf: UserBuffer) -> Result<usize, isize> {
+        console().read(user_buf, self.nonblock.load(Ordering::Relaxed))
+    }
+    fn write(&self, user_buf: UserBuffer) -> Result<usize, isize> {
+        console().write(user_buf)
+    }
+    fn ioctl(&self, request: usize, arg: usize) -> Result<isize, isize> {
+        console().ioctl(request, arg)
+    }
+    fn status_flags(&self) -> u32 {
+        if self.nonblock.load(Ordering::Relaxed) {
+            O_NONBLOCK
+        } else {
+            0
         }
-        Ok(1)
     }
-    fn write(&self, _user_buf: UserBuffer) -> Result<usize, isize> {
-        panic!("Cannot write to stdin!");
+    fn set_status_flags(&self, flags: u32) -> Result<(), isize> {
+        self.nonblock
+            .store(flags & O_NONBLOCK != 0, Ordering::Relaxed);
+        Ok(())
     }
 }
 
 impl File for Stdout {
-    fn read(&self, _user_buf: UserBuffer) -> Result<usize, isize> {
-        panic!("Cannot read from stdout!");
+    fn read(&self, user_buf: UserBuffer) -> Result<usize, isize> {
+        console().read(user_buf, false)
     }
     fn write(&self, user_buf: UserBuffer) -> Result<usize, isize> {
-        for buffer in user_buf.buffers.iter() {
-            print!("{}", core::str::from_utf8(*buffer).unwrap());
-        }
-        Ok(user_buf.len())
+        console().write(user_buf)
+    }
+    fn ioctl(&self, request: usize, arg: usize) -> Result<isize, isize> {
+        console().ioctl(request, arg)
     }
 }
 
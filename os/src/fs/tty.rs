@@ -0,0 +1,401 @@
+//! The console as a terminal: a line discipline between serial 0 and the
+//! foreground process group of the session it controls.
+
+use crate::errno::{EAGAIN, EINTR, ENOTTY, EPERM};
+use crate::mm::{copy_from_user, copy_to_user, UserBuffer};
+use crate::sync::IrqMutex;
+use crate::task::{
+    block_current_and_run_next, current_signal_pending, current_task, current_user_token,
+    find_task, process_group_exists, signal_group, WaitQueue, SIGINT, SIGQUIT, SIGTSTP, SIGTTIN,
+};
+use crate::uart::{serial_getchar, serial_putchar, serial_write};
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use lazy_static::*;
+
+pub const NCCS: usize = 19;
+
+// c_iflag
+pub const ICRNL: u32 = 0o400;
+// c_oflag
+pub const OPOST: u32 = 0o1;
+pub const ONLCR: u32 = 0o4;
+// c_cflag
+pub const CS8: u32 = 0o60;
+pub const CREAD: u32 = 0o200;
+// c_lflag
+pub const ISIG: u32 = 0o1;
+pub const ICANON: u32 = 0o2;
+pub const ECHO: u32 = 0o10;
+pub const ECHOE: u32 = 0o20;
+pub const ECHOK: u32 = 0o40;
+
+// c_cc
+pub const VINTR: usize = 0;
+pub const VQUIT: usize = 1;
+pub const VERASE: usize = 2;
+pub const VKILL: usize = 3;
+pub const VEOF: usize = 4;
+pub const VMIN: usize = 6;
+pub const VSUSP: usize = 10;
+
+pub const TCGETS: usize = 0x5401;
+pub const TCSETS: usize = 0x5402;
+pub const TCSETSW: usize = 0x5403;
+pub const TCSETSF: usize = 0x5404;
+pub const TIOCSCTTY: usize = 0x540e;
+pub const TIOCGPGRP: usize = 0x540f;
+pub const TIOCSPGRP: usize = 0x5410;
+
+/// Longest line canonical mode keeps, the rest of it is dropped.
+const MAX_CANON: usize = 255;
+const MAX_INPUT: usize = 4096;
+const BS: u8 = 0x08;
+
+/// `struct termios` as Linux lays it out.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Termios {
+    pub iflag: u32,
+    pub oflag: u32,
+    pub cflag: u32,
+    pub lflag: u32,
+    pub line: u8,
+    pub cc: [u8; NCCS],
+}
+
+impl Default for Termios {
+    fn default() -> Self {
+        let mut cc = [0; NCCS];
+        cc[VINTR] = 0x03;
+        cc[VQUIT] = 0x1c;
+        cc[VERASE] = 0x7f;
+        cc[VKILL] = 0x15;
+        cc[VEOF] = 0x04;
+        cc[VMIN] = 1;
+        cc[VSUSP] = 0x1a;
+        Termios {
+            iflag: ICRNL,
+            oflag: OPOST | ONLCR,
+            cflag: CS8 | CREAD,
+            lflag: ISIG | ICANON | ECHO | ECHOE | ECHOK,
+            line: 0,
+            cc,
+        }
+    }
+}
+
+struct TtyInner {
+    termios: Termios,
+    /// The line being edited in canonical mode.
+    line: Vec<u8>,
+    /// What `read` hands out, whole lines only in canonical mode.
+    input: VecDeque<u8>,
+    /// ^D on an empty line, the next read returns 0.
+    eof: bool,
+    /// The session it is the controlling terminal of.
+    session: Option<usize>,
+    foreground: Option<usize>,
+    /// Waiting for `input` or end of file.
+    readers: WaitQueue,
+}
+
+pub struct Tty {
+    serial_id: usize,
+    inner: IrqMutex<TtyInner>,
+}
+
+lazy_static! {
+    static ref CONSOLE: Tty = Tty::new(0);
+}
+
+pub fn console() -> &'static Tty {
+    &CONSOLE
+}
+
+impl Tty {
+    fn new(serial_id: usize) -> Self {
+        Tty {
+            serial_id,
+            inner: IrqMutex::new(TtyInner {
+                termios: Termios::default(),
+                line: Vec::new(),
+                input: VecDeque::new(),
+                eof: false,
+                session: None,
+                foreground: None,
+                readers: WaitQueue::new(),
+            }),
+        }
+    }
+
+    /// Runs bytes from the serial port through the line discipline.
+    pub fn receive(&self, bytes: &[u8]) {
+        let mut echo = Vec::new();
+        let mut signals = Vec::new();
+        let mut inner = self.inner.lock();
+        for &byte in bytes {
+            inner.receive_byte(byte, &mut echo, &mut signals);
+        }
+        if !inner.input.is_empty() || inner.eof {
+            inner.readers.wake_all();
+        }
+        let oflag = inner.termios.oflag;
+        drop(inner);
+        self.output(&echo, oflag);
+        for (pgid, signo) in signals {
+            let _ = signal_group(pgid, signo);
+        }
+    }
+
+    fn output(&self, bytes: &[u8], oflag: u32) {
+        for &byte in bytes {
+            if byte == b'\n' && oflag & (OPOST | ONLCR) == OPOST | ONLCR {
+                serial_putchar(self.serial_id, b'\r');
+            }
+            serial_putchar(self.serial_id, byte);
+        }
+    }
+
+    /// Takes what arrived without an interrupt telling us.
+    fn poll(&self) {
+        let bytes: Vec<u8> = core::iter::from_fn(|| match serial_getchar(self.serial_id) {
+            0 => None,
+            byte => Some(byte),
+        })
+        .collect();
+        if !bytes.is_empty() {
+            self.receive(&bytes);
+        }
+    }
+
+    /// Background groups of the session get SIGTTIN for reading.
+    fn check_foreground(&self) -> Result<(), isize> {
+        let task = current_task().unwrap();
+        let (pgid, sid) = {
+            let inner = task.acquire_inner_lock();
+            (inner.pgid, inner.sid)
+        };
+        let inner = self.inner.lock();
+        if inner.session == Some(sid) && inner.foreground.map_or(false, |fg| fg != pgid) {
+            drop(inner);
+            let _ = signal_group(pgid, SIGTTIN);
+            return Err(EINTR);
+        }
+        Ok(())
+    }
+
+    /// Sleeps until there is input, unless `nonblock`.
+    pub fn read(&self, mut buf: UserBuffer, nonblock: bool) -> Result<usize, isize> {
+        if buf.len() == 0 {
+            return Ok(0);
+        }
+        loop {
+            if let Err(errno) = self.check_foreground() {
+                self.inner.lock().readers.remove_current();
+                return Err(errno);
+            }
+            if current_signal_pending() {
+                self.inner.lock().readers.remove_current();
+                return Err(EINTR);
+            }
+            self.poll();
+            let mut inner = self.inner.lock();
+            let canonical = inner.termios.lflag & ICANON != 0;
+            let mut read_size = 0;
+            'copy: for slice in buf.buffers.iter_mut() {
+                for byte in slice.iter_mut() {
+                    match inner.input.pop_front() {
+                        Some(ch) => {
+                            *byte = ch;
+                            read_size += 1;
+                            if canonical && ch == b'\n' {
+                                break 'copy;
+                            }
+                        }
+                        None => break 'copy,
+                    }
+                }
+            }
+            if read_size > 0 {
+                return Ok(read_size);
+            }
+            if inner.eof {
+                inner.eof = false;
+                return Ok(0);
+            }
+            if nonblock {
+                inner.readers.remove_current();
+                return Err(EAGAIN);
+            }
+            inner.readers.add_current();
+            drop(inner);
+            block_current_and_run_next();
+        }
+    }
+
+    /// Sleeps while the port's Tx buffer is full, unlike the echo.
+    pub fn write(&self, buf: UserBuffer) -> Result<usize, isize> {
+        let onlcr = self.inner.lock().termios.oflag & (OPOST | ONLCR) == OPOST | ONLCR;
+        let mut written = 0;
+        for buffer in buf.buffers.iter() {
+            for byte in buffer.iter() {
+                let bytes: &[u8] = if onlcr && *byte == b'\n' {
+                    b"\r\n"
+                } else {
+                    core::slice::from_ref(byte)
+                };
+                match serial_write(self.serial_id, bytes) {
+                    Ok(sent) if sent == bytes.len() => written += 1,
+                    Err(errno) if written == 0 => return Err(errno),
+                    _ => return Ok(written),
+                }
+            }
+        }
+        Ok(written)
+    }
+
+    pub fn ioctl(&self, request: usize, arg: usize) -> Result<isize, isize> {
+        let token = current_user_token();
+        match request {
+            TCGETS => {
+                let termios = self.inner.lock().termios;
+                copy_to_user(token, arg as *mut Termios, &termios)?;
+            }
+            TCSETS | TCSETSW | TCSETSF => {
+                let termios: Termios = copy_from_user(token, arg as *const Termios)?;
+                let mut inner = self.inner.lock();
+                if request == TCSETSF {
+                    inner.input.clear();
+                    inner.line.clear();
+                } else if termios.lflag & ICANON == 0 {
+                    // leaving canonical mode hands out the line as it is
+                    let line: Vec<u8> = inner.line.drain(..).collect();
+                    inner.input.extend(line);
+                    inner.readers.wake_all();
+                }
+                inner.termios = termios;
+            }
+            TIOCSCTTY => self.set_controlling()?,
+            TIOCGPGRP => {
+                let pgid = self.inner.lock().foreground.ok_or(ENOTTY)? as u32;
+                copy_to_user(token, arg as *mut u32, &pgid)?;
+            }
+            TIOCSPGRP => {
+                let pgid = copy_from_user(token, arg as *const u32)? as usize;
+                self.set_foreground(pgid)?;
+            }
+            _ => return Err(ENOTTY),
+        }
+        Ok(0)
+    }
+
+    /// Only a session leader can take the terminal, and only once the
+    /// session that had it is gone.
+    fn set_controlling(&self) -> Result<(), isize> {
+        let task = current_task().unwrap();
+        let (pgid, sid) = {
+            let inner = task.acquire_inner_lock();
+            (inner.pgid, inner.sid)
+        };
+        if sid != task.getpid() {
+            return Err(EPERM);
+        }
+        let owner = self.inner.lock().session;
+        if let Some(owner) = owner.filter(|&owner| owner != sid) {
+            if find_task(owner).map_or(false, |leader| !leader.acquire_inner_lock().is_zombie()) {
+                return Err(EPERM);
+            }
+        }
+        let mut inner = self.inner.lock();
+        inner.session = Some(sid);
+        inner.foreground = Some(pgid);
+        Ok(())
+    }
+
+    fn set_foreground(&self, pgid: usize) -> Result<(), isize> {
+        let sid = current_task().unwrap().acquire_inner_lock().sid;
+        if self.inner.lock().session != Some(sid) {
+            return Err(ENOTTY);
+        }
+        if !process_group_exists(pgid, sid) {
+            return Err(EPERM);
+        }
+        self.inner.lock().foreground = Some(pgid);
+        Ok(())
+    }
+}
+
+impl TtyInner {
+    fn receive_byte(&mut self, byte: u8, echo: &mut Vec<u8>, signals: &mut Vec<(usize, usize)>) {
+        let termios = self.termios;
+        let byte = if termios.iflag & ICRNL != 0 && byte == b'\r' {
+            b'\n'
+        } else {
+            byte
+        };
+        let is = |cc: usize| termios.cc[cc] != 0 && termios.cc[cc] == byte;
+        let echoing = termios.lflag & ECHO != 0;
+        if termios.lflag & ISIG != 0 {
+            let signo = if is(VINTR) {
+                Some(SIGINT)
+            } else if is(VQUIT) {
+                Some(SIGQUIT)
+            } else if is(VSUSP) {
+                Some(SIGTSTP)
+            } else {
+                None
+            };
+            if let Some(signo) = signo {
+                self.line.clear();
+                if echoing {
+                    echo.extend_from_slice(&[b'^', byte + b'@', b'\n']);
+                }
+                if let Some(pgid) = self.foreground {
+                    signals.push((pgid, signo));
+                }
+                return;
+            }
+        }
+        if termios.lflag & ICANON == 0 {
+            if self.input.len() < MAX_INPUT {
+                self.input.push_back(byte);
+                if echoing {
+                    echo.push(byte);
+                }
+            }
+            return;
+        }
+        if is(VERASE) || byte == BS {
+            if self.line.pop().is_some() && echoing && termios.lflag & ECHOE != 0 {
+                echo.extend_from_slice(&[BS, b' ', BS]);
+            }
+        } else if is(VKILL) {
+            if echoing && termios.lflag & ECHOK != 0 {
+                for _ in 0..self.line.len() {
+                    echo.extend_from_slice(&[BS, b' ', BS]);
+                }
+            }
+            self.line.clear();
+        } else if is(VEOF) {
+            if self.line.is_empty() {
+                self.eof = true;
+            } else {
+                let line: Vec<u8> = self.line.drain(..).collect();
+                self.input.extend(line);
+            }
+        } else if byte == b'\n' {
+            self.line.push(byte);
+            let line: Vec<u8> = self.line.drain(..).collect();
+            self.input.extend(line);
+            if echoing {
+                echo.push(byte);
+            }
+        } else if self.line.len() < MAX_CANON {
+            self.line.push(byte);
+            if echoing {
+                echo.push(byte);
+            }
+        }
+    }
+}
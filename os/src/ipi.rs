@@ -0,0 +1,54 @@
+use crate::config::CPU_NUM;
+use crate::mm::local_flush_tlb;
+use crate::sbi;
+use crate::sync::IrqMutex;
+use crate::task::hart_id;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use lazy_static::*;
+
+#[derive(Clone)]
+pub enum IpiMessage {
+    /// Preempt whatever the hart is running and pick the next task.
+    Reschedule,
+    /// Flush the local TLB entries of an ASID, 0 for everything.
+    TlbFlush(usize),
+    /// Run a closure on the target hart in interrupt context.
+    Call(Arc<dyn Fn() + Send + Sync>),
+}
+
+lazy_static! {
+    static ref IPI_QUEUES: [IrqMutex<VecDeque<IpiMessage>>; CPU_NUM] = Default::default();
+}
+
+/// Queue `message` for every hart in `hart_mask` and interrupt them.
+pub fn send_ipi(hart_mask: usize, message: IpiMessage) {
+    for hart in (0..CPU_NUM).filter(|hart| hart_mask & (1 << hart) != 0) {
+        IPI_QUEUES[hart].lock().push_back(message.clone());
+    }
+    if let Err(e) = sbi::send_ipi(hart_mask) {
+        warn!("send_ipi to {:#b} failed: {:?}", hart_mask, e);
+    }
+}
+
+pub fn call_on(hart_mask: usize, f: impl Fn() + Send + Sync + 'static) {
+    send_ipi(hart_mask, IpiMessage::Call(Arc::new(f)));
+}
+
+/// Handle the messages for this hart, returns whether a reschedule was asked for.
+pub fn handle_ipi() -> bool {
+    unsafe {
+        // clear sip.SSIP before draining, so a message queued meanwhile raises it again
+        asm!("csrc sip, {}", in(reg) 1 << 1);
+    }
+    let messages = core::mem::take(&mut *IPI_QUEUES[hart_id()].lock());
+    let mut reschedule = false;
+    for message in messages {
+        match message {
+            IpiMessage::Reschedule => reschedule = true,
+            IpiMessage::TlbFlush(asid) => local_flush_tlb(asid),
+            IpiMessage::Call(f) => f(),
+        }
+    }
+    reschedule
+}
@@ -1,3 +1,6 @@
+use crate::errno::ENOENT;
+use crate::fs::packfs;
+use alloc::borrow::Cow;
 use alloc::vec::Vec;
 use lazy_static::*;
 
@@ -48,7 +51,6 @@ lazy_static! {
     };
 }
 
-#[allow(unused)]
 pub fn get_app_data_by_name(name: &str) -> Option<&'static [u8]> {
     let num_app = get_num_app();
     (0..num_app)
@@ -56,10 +58,24 @@ pub fn get_app_data_by_name(name: &str) -> Option<&'static [u8]> {
         .map(get_app_data)
 }
 
+/// Look in the mounted image first, so apps can be added without relinking,
+/// then among the apps linked into the kernel. May yield on disk reads.
+pub fn load_app(path: &str) -> Option<Cow<'static, [u8]>> {
+    match packfs::read_all(path) {
+        Ok(data) => return Some(Cow::Owned(data)),
+        Err(ENOENT) => {}
+        Err(errno) => warn!("[loader] reading {} failed: {}", path, errno),
+    }
+    get_app_data_by_name(path.trim_start_matches('/')).map(Cow::Borrowed)
+}
+
 pub fn list_apps() {
     info!("/**** APPS ****");
     for app in APP_NAMES.iter() {
         info!("{}", app);
     }
+    for (name, size) in packfs::list() {
+        info!("/{} ({} bytes)", name, size);
+    }
     info!("**************/")
 }
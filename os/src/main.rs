@@ -17,19 +17,26 @@ extern crate bitflags;
 #[macro_use]
 extern crate log;
 
-use crate::{config::CPU_NUM, mm::init_kernel_space, sbi::send_ipi};
+use crate::{fdt::board, mm::init_kernel_space};
 
 #[macro_use]
 mod console;
+mod block;
 mod config;
+mod device;
+mod errno;
+mod fdt;
 #[macro_use]
 mod fs;
+mod ipi;
 mod lang_items;
 mod loader;
 mod logger;
 mod mm;
+mod net;
 mod plic;
 mod sbi;
+mod sync;
 mod syscall;
 mod task;
 mod timer;
@@ -37,6 +44,7 @@ mod trap;
 #[macro_use]
 mod uart;
 mod async_rt;
+mod virtio;
 
 global_asm!(include_str!("entry.asm"));
 global_asm!(include_str!("link_app.asm"));
@@ -50,17 +58,30 @@ fn clear_bss() {
 }
 
 #[no_mangle]
-pub fn rust_main(hart_id: usize) -> ! {
+pub fn rust_main(hart_id: usize, dtb: usize) -> ! {
     if hart_id == 0 {
         clear_bss();
+        sbi::init();
         logger::init();
+        fdt::init(dtb);
+        debug!(
+            "SBI spec {:?}, impl {:?} version {:?}",
+            sbi::spec_version(),
+            sbi::impl_id(),
+            sbi::impl_version()
+        );
         mm::init();
         debug!("[kernel {}] Hello, world!", hart_id);
+        debug!("[kernel {}] {:?}", hart_id, mm::heap_stats());
+        debug!("[kernel {}] {:?}", hart_id, mm::frame_stats());
         mm::remap_test();
         trap::init();
+        uart::init();
+        virtio::init();
+        fs::packfs::init();
+        net::init();
         plic::init();
         plic::init_hart(hart_id);
-        uart::init();
 
         extern "C" {
             fn boot_stack();
@@ -84,10 +105,14 @@ pub fn rust_main(hart_id: usize) -> ! {
             println_hart!("satp: {:#x}, sp: {:#x}", hart_id, satp, sp);
         }
 
-        for i in 1..CPU_NUM {
+        extern "C" {
+            fn _start();
+        }
+        for i in 1..board().hart_count {
             debug!("[kernel {}] Start {}", hart_id, i);
-            let mask: usize = 1 << i;
-            send_ipi(&mask as *const _ as usize);
+            if let Err(e) = sbi::hart_start(i, _start as usize, 0) {
+                warn!("[kernel {}] Failed to start hart {}: {:?}", hart_id, i, e);
+            }
         }
     } else {
         let hart_id = task::hart_id();
@@ -107,7 +132,7 @@ pub fn rust_main(hart_id: usize) -> ! {
 
     println_hart!("Hello", hart_id);
 
-    timer::set_next_trigger();
+    timer::init_hart();
 
     if hart_id == 0 {
         loader::list_apps();
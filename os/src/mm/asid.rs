@@ -0,0 +1,116 @@
+use crate::fdt::board;
+use crate::ipi::{send_ipi, IpiMessage};
+use crate::sbi::{remote_sfence_vma, remote_sfence_vma_asid};
+use crate::sync::IrqMutex;
+use crate::task::hart_id;
+use alloc::vec::Vec;
+use lazy_static::*;
+use riscv::register::satp;
+
+const SATP_ASID_SHIFT: usize = 44;
+const SATP_ASID_MASK: usize = 0xffff;
+
+/// ASID 0 belongs to the kernel space, and is also handed out when we run out.
+struct AsidAllocator {
+    current: usize,
+    max: usize,
+    recycled: Vec<usize>,
+}
+
+impl AsidAllocator {
+    pub fn new() -> Self {
+        AsidAllocator {
+            current: 1,
+            max: 0,
+            recycled: Vec::new(),
+        }
+    }
+    pub fn alloc(&mut self) -> AsidHandle {
+        let asid = match self.recycled.pop() {
+            Some(asid) => asid,
+            None if self.current <= self.max => {
+                self.current += 1;
+                self.current - 1
+            }
+            None => {
+                if self.max != 0 {
+                    warn!("ASIDs exhausted, falling back to global flushes");
+                }
+                0
+            }
+        };
+        AsidHandle(asid)
+    }
+    pub fn dealloc(&mut self, asid: usize) {
+        assert!(asid < self.current);
+        assert!(
+            self.recycled.iter().find(|a| **a == asid).is_none(),
+            "asid {} has been deallocated!",
+            asid
+        );
+        self.recycled.push(asid);
+    }
+}
+
+lazy_static! {
+    static ref ASID_ALLOCATOR: IrqMutex<AsidAllocator> = IrqMutex::new(AsidAllocator::new());
+}
+
+#[derive(Debug)]
+pub struct AsidHandle(pub usize);
+
+impl Drop for AsidHandle {
+    fn drop(&mut self) {
+        if self.0 != 0 {
+            // stale entries must be gone before the asid is reused
+            flush_tlb(self.0);
+            ASID_ALLOCATOR.lock().dealloc(self.0);
+        }
+    }
+}
+
+pub fn asid_alloc() -> AsidHandle {
+    ASID_ALLOCATOR.lock().alloc()
+}
+
+/// Probe the implemented ASID bits by writing all ones into satp.ASID.
+pub fn init_asid() {
+    let old = satp::read().bits();
+    let probed = unsafe {
+        satp::write(old | SATP_ASID_MASK << SATP_ASID_SHIFT);
+        let probed = satp::read().bits();
+        satp::write(old);
+        llvm_asm!("sfence.vma" :::: "volatile");
+        probed
+    };
+    let max = (probed >> SATP_ASID_SHIFT) & SATP_ASID_MASK;
+    info!("ASID max: {:#x}", max);
+    ASID_ALLOCATOR.lock().max = max;
+}
+
+/// Flush `asid` on this hart only, or everything when `asid` is 0.
+pub fn local_flush_tlb(asid: usize) {
+    unsafe {
+        if asid == 0 {
+            llvm_asm!("sfence.vma" :::: "volatile");
+        } else {
+            asm!("sfence.vma zero, {}", in(reg) asid);
+        }
+    }
+}
+
+/// Flush `asid` on every hart, or everything when `asid` is 0.
+pub fn flush_tlb(asid: usize) {
+    local_flush_tlb(asid);
+    let hart_mask = ((1 << board().hart_count) - 1) & !(1 << hart_id());
+    let result = if asid == 0 {
+        remote_sfence_vma(hart_mask, 0, usize::MAX)
+    } else {
+        remote_sfence_vma_asid(hart_mask, 0, usize::MAX, asid)
+    };
+    if let Err(e) = result {
+        warn!("remote sfence.vma for asid {} failed: {:?}", asid, e);
+        // not synchronous, but better than leaving stale entries around
+        send_ipi(hart_mask, IpiMessage::TlbFlush(asid));
+    }
+}
@@ -0,0 +1,247 @@
+//! Checks an ELF before anything gets mapped, and works out the load bias and
+//! the relocations of position-independent executables.
+
+use super::MapPermission;
+use crate::config::{ELF_DYN_BASE, PAGE_SIZE, USER_SPACE_END};
+use crate::errno::ENOEXEC;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use xmas_elf::program::{ProgramHeader, Type};
+use xmas_elf::ElfFile;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_RISCV: u16 = 243;
+
+const DT_NULL: u64 = 0;
+const DT_PLTRELSZ: u64 = 2;
+const DT_SYMTAB: u64 = 6;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+const DT_JMPREL: u64 = 23;
+
+const R_RISCV_NONE: u32 = 0;
+const R_RISCV_64: u32 = 2;
+const R_RISCV_RELATIVE: u32 = 3;
+const R_RISCV_JUMP_SLOT: u32 = 5;
+
+const DYN_SIZE: usize = 16;
+const RELA_SIZE: usize = 24;
+const SYM_SIZE: usize = 24;
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xfff1;
+
+pub struct Segment<'a> {
+    pub start: usize,
+    pub end: usize,
+    pub perm: MapPermission,
+    /// What goes at `start`, the rest up to `end` is zero.
+    pub data: &'a [u8],
+}
+
+pub struct ElfImage<'a> {
+    pub entry: usize,
+    /// By address, no two of them share a page.
+    pub segments: Vec<Segment<'a>>,
+    /// `(address, value)` to store once the segments are in place.
+    pub relocations: Vec<(usize, u64)>,
+}
+
+fn le16(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(buf[at..at + 2].try_into().unwrap())
+}
+
+fn le64(buf: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(buf[at..at + 8].try_into().unwrap())
+}
+
+fn file_range(elf_data: &[u8], offset: u64, size: u64) -> Result<&[u8], isize> {
+    let start = offset as usize;
+    let end = start.checked_add(size as usize).ok_or(ENOEXEC)?;
+    elf_data.get(start..end).ok_or(ENOEXEC)
+}
+
+fn page_floor(addr: usize) -> usize {
+    addr & !(PAGE_SIZE - 1)
+}
+
+fn page_ceil(addr: usize) -> usize {
+    page_floor(addr + PAGE_SIZE - 1)
+}
+
+/// The header fields xmas_elf would otherwise panic on.
+fn check_header(elf_data: &[u8]) -> Result<u16, isize> {
+    // 64-bit little endian RISC-V
+    if elf_data.len() < EHDR_SIZE
+        || elf_data[..4] != [0x7f, b'E', b'L', b'F']
+        || elf_data[4] != 2
+        || elf_data[5] != 1
+        || le16(elf_data, 18) != EM_RISCV
+    {
+        return Err(ENOEXEC);
+    }
+    let elf_type = le16(elf_data, 16);
+    if elf_type != ET_EXEC && elf_type != ET_DYN {
+        return Err(ENOEXEC);
+    }
+    let ph_offset = le64(elf_data, 32);
+    let ph_entry_size = le16(elf_data, 54) as usize;
+    let ph_count = le16(elf_data, 56) as u64;
+    if ph_entry_size != PHDR_SIZE {
+        return Err(ENOEXEC);
+    }
+    file_range(elf_data, ph_offset, ph_count * PHDR_SIZE as u64)?;
+    Ok(elf_type)
+}
+
+fn load_segment<'a>(
+    elf_data: &'a [u8],
+    ph: &ProgramHeader,
+    bias: usize,
+) -> Result<Segment<'a>, isize> {
+    let vaddr = ph.virtual_addr() as usize;
+    let align = ph.align() as usize;
+    if ph.file_size() > ph.mem_size()
+        || (align > 1
+            && (!align.is_power_of_two() || vaddr.wrapping_sub(ph.offset() as usize) % align != 0))
+    {
+        return Err(ENOEXEC);
+    }
+    let start = vaddr.checked_add(bias).ok_or(ENOEXEC)?;
+    let end = start.checked_add(ph.mem_size() as usize).ok_or(ENOEXEC)?;
+    if end > USER_SPACE_END {
+        return Err(ENOEXEC);
+    }
+    let mut perm = MapPermission::U;
+    let flags = ph.flags();
+    if flags.is_read() {
+        perm |= MapPermission::R;
+    }
+    if flags.is_write() {
+        perm |= MapPermission::W;
+    }
+    if flags.is_execute() {
+        perm |= MapPermission::X;
+    }
+    Ok(Segment {
+        start,
+        end,
+        perm,
+        data: file_range(elf_data, ph.offset(), ph.file_size())?,
+    })
+}
+
+pub fn parse(elf_data: &[u8]) -> Result<ElfImage, isize> {
+    let elf_type = check_header(elf_data)?;
+    let elf = ElfFile::new(elf_data).map_err(|_| ENOEXEC)?;
+    let bias = if elf_type == ET_DYN { ELF_DYN_BASE } else { 0 };
+    let mut segments = Vec::new();
+    let mut dynamic = None;
+    for i in 0..elf.header.pt2.ph_count() {
+        let ph = elf.program_header(i).map_err(|_| ENOEXEC)?;
+        match ph.get_type().map_err(|_| ENOEXEC)? {
+            Type::Load if ph.mem_size() > 0 => segments.push(load_segment(elf_data, &ph, bias)?),
+            Type::Dynamic => dynamic = Some(file_range(elf_data, ph.offset(), ph.file_size())?),
+            // there is no dynamic linker to hand it to
+            Type::Interp => return Err(ENOEXEC),
+            _ => {}
+        }
+    }
+    segments.sort_by_key(|segment| segment.start);
+    // each page gets the permissions of a single segment
+    if segments
+        .windows(2)
+        .any(|pair| page_ceil(pair[0].end) > page_floor(pair[1].start))
+    {
+        return Err(ENOEXEC);
+    }
+    let entry = (elf.header.pt2.entry_point() as usize)
+        .checked_add(bias)
+        .ok_or(ENOEXEC)?;
+    if !segments.iter().any(|segment| {
+        segment.perm.contains(MapPermission::X) && segment.start <= entry && entry < segment.end
+    }) {
+        return Err(ENOEXEC);
+    }
+    let relocations = match dynamic {
+        Some(dynamic) if bias != 0 => relocate(&segments, dynamic, bias)?,
+        _ => Vec::new(),
+    };
+    Ok(ElfImage {
+        entry,
+        segments,
+        relocations,
+    })
+}
+
+/// `len` bytes of file data loaded at `addr`.
+fn loaded<'a>(segments: &[Segment<'a>], addr: usize, len: usize) -> Result<&'a [u8], isize> {
+    let segment = segments
+        .iter()
+        .find(|segment| segment.start <= addr && addr < segment.start + segment.data.len())
+        .ok_or(ENOEXEC)?;
+    let data: &'a [u8] = segment.data;
+    data[addr - segment.start..].get(..len).ok_or(ENOEXEC)
+}
+
+fn relocate(segments: &[Segment], dynamic: &[u8], bias: usize) -> Result<Vec<(usize, u64)>, isize> {
+    let mut rela = (0, 0);
+    let mut jmprel = (0, 0);
+    let mut symtab = None;
+    for entry in dynamic.chunks_exact(DYN_SIZE) {
+        let value = le64(entry, 8);
+        match le64(entry, 0) {
+            DT_NULL => break,
+            DT_RELA => rela.0 = value,
+            DT_RELASZ => rela.1 = value,
+            DT_RELAENT if value != RELA_SIZE as u64 => return Err(ENOEXEC),
+            DT_JMPREL => jmprel.0 = value,
+            DT_PLTRELSZ => jmprel.1 = value,
+            DT_SYMTAB => symtab = Some(value as usize),
+            _ => {}
+        }
+    }
+    let mut relocations = Vec::new();
+    for &(addr, size) in [rela, jmprel].iter() {
+        if size == 0 {
+            continue;
+        }
+        let table = loaded(segments, (addr as usize).wrapping_add(bias), size as usize)?;
+        for rela in table.chunks_exact(RELA_SIZE) {
+            let offset = le64(rela, 0) as usize;
+            let info = le64(rela, 8);
+            let addend = le64(rela, 16);
+            let value = match info as u32 {
+                R_RISCV_NONE => continue,
+                R_RISCV_RELATIVE => (bias as u64).wrapping_add(addend),
+                R_RISCV_64 | R_RISCV_JUMP_SLOT => {
+                    let index = (info >> 32) as usize;
+                    let symtab = symtab.ok_or(ENOEXEC)?;
+                    let sym_addr = symtab
+                        .wrapping_add(bias)
+                        .wrapping_add(index.wrapping_mul(SYM_SIZE));
+                    let sym = loaded(segments, sym_addr, SYM_SIZE)?;
+                    let value = le64(sym, 8).wrapping_add(addend);
+                    match le16(sym, 6) {
+                        // nothing to resolve it against
+                        SHN_UNDEF => return Err(ENOEXEC),
+                        SHN_ABS => value,
+                        _ => value.wrapping_add(bias as u64),
+                    }
+                }
+                _ => return Err(ENOEXEC),
+            };
+            let target = offset.checked_add(bias).ok_or(ENOEXEC)?;
+            if !segments.iter().any(|segment| {
+                segment.start <= target && target < segment.end && segment.end - target >= 8
+            }) {
+                return Err(ENOEXEC);
+            }
+            relocations.push((target, value));
+        }
+    }
+    Ok(relocations)
+}
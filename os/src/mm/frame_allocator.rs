@@ -1,9 +1,10 @@
 use super::{PhysAddr, PhysPageNum};
-use crate::config::MEMORY_END;
+use crate::config::PAGE_SIZE;
+use crate::fdt::board;
+use crate::sync::IrqMutex;
 use alloc::vec::Vec;
 use core::fmt::{self, Debug, Formatter};
 use lazy_static::*;
-use spin::Mutex;
 
 pub struct FrameTracker {
     pub ppn: PhysPageNum,
@@ -32,35 +33,77 @@ impl Drop for FrameTracker {
     }
 }
 
+#[derive(Debug)]
+pub struct FrameStats {
+    pub total: usize,
+    pub free: usize,
+}
+
 trait FrameAllocator {
     fn new() -> Self;
     fn alloc(&mut self) -> Option<PhysPageNum>;
+    /// `count` frames starting at a ppn aligned to `align` frames (a power of two).
+    fn alloc_contiguous(&mut self, count: usize, align: usize) -> Option<PhysPageNum>;
     fn dealloc(&mut self, ppn: PhysPageNum);
+    fn stats(&self) -> FrameStats;
 }
 
+/// Frames handed out in order, freed ones are kept on a stack linked through
+/// the frames themselves, so the heap may grow from it like from the buddy
+/// allocator.
 pub struct StackFrameAllocator {
+    start: usize,
     current: usize,
     end: usize,
-    recycled: Vec<usize>,
+    /// top of the recycled frames, each holds the ppn of the one below
+    recycled: usize,
+    recycled_count: usize,
 }
 
 impl StackFrameAllocator {
     pub fn init(&mut self, l: PhysPageNum, r: PhysPageNum) {
+        self.start = l.0;
         self.current = l.0;
         self.end = r.0;
         debug!("last {} Physical Frames.", self.end - self.current);
     }
+
+    fn next(ppn: usize) -> &'static mut usize {
+        PhysPageNum(ppn).get_mut()
+    }
+
+    fn recycle(&mut self, ppn: usize) {
+        *Self::next(ppn) = self.recycled;
+        self.recycled = ppn;
+        self.recycled_count += 1;
+    }
+
+    fn is_recycled(&self, ppn: usize) -> bool {
+        let mut v = self.recycled;
+        while v != NIL {
+            if v == ppn {
+                return true;
+            }
+            v = *Self::next(v);
+        }
+        false
+    }
 }
 impl FrameAllocator for StackFrameAllocator {
     fn new() -> Self {
         Self {
+            start: 0,
             current: 0,
             end: 0,
-            recycled: Vec::new(),
+            recycled: NIL,
+            recycled_count: 0,
         }
     }
     fn alloc(&mut self) -> Option<PhysPageNum> {
-        if let Some(ppn) = self.recycled.pop() {
+        if self.recycled != NIL {
+            let ppn = self.recycled;
+            self.recycled = *Self::next(ppn);
+            self.recycled_count -= 1;
             Some(ppn.into())
         } else if self.current == self.end {
             None
@@ -69,22 +112,202 @@ impl FrameAllocator for StackFrameAllocator {
             Some((self.current - 1).into())
         }
     }
+    /// Only the never-allocated range is contiguous, recycled frames are not used.
+    fn alloc_contiguous(&mut self, count: usize, align: usize) -> Option<PhysPageNum> {
+        assert!(align.is_power_of_two());
+        let start = (self.current + align - 1) & !(align - 1);
+        if start > self.end || self.end - start < count {
+            None
+        } else {
+            // frames skipped for alignment are recycled
+            for ppn in self.current..start {
+                self.recycle(ppn);
+            }
+            self.current = start + count;
+            Some(start.into())
+        }
+    }
     fn dealloc(&mut self, ppn: PhysPageNum) {
         let ppn = ppn.0;
         // validity check
-        if ppn >= self.current || self.recycled.iter().any(|v| *v == ppn) {
+        if ppn < self.start || ppn >= self.current || self.is_recycled(ppn) {
             panic!("Frame ppn={:#x} has not been allocated!", ppn);
         }
         // recycle
-        self.recycled.push(ppn);
+        self.recycle(ppn);
+    }
+    fn stats(&self) -> FrameStats {
+        FrameStats {
+            total: self.end - self.start,
+            free: self.end - self.current + self.recycled_count,
+        }
+    }
+}
+
+const BUDDY_MAX_ORDER: usize = 20;
+const NIL: usize = usize::MAX;
+
+/// Links of a free block, stored in its first frame.
+#[derive(Copy, Clone)]
+struct FreeBlock {
+    prev: usize,
+    next: usize,
+}
+
+/// Blocks of 2^order frames aligned to their size. The allocator keeps no state
+/// on the kernel heap, so the heap may grow from it.
+pub struct BuddyFrameAllocator {
+    base: usize,
+    end: usize,
+    free_lists: [usize; BUDDY_MAX_ORDER],
+    /// `order + 1` if the frame heads a free block, 0 otherwise
+    orders: &'static mut [u8],
+    total: usize,
+    free: usize,
+}
+
+impl BuddyFrameAllocator {
+    pub fn init(&mut self, l: PhysPageNum, r: PhysPageNum) {
+        let frames = r.0 - l.0;
+        // the order table lives in the first frames of the range
+        let table_frames = (frames + PAGE_SIZE - 1) / PAGE_SIZE;
+        self.orders =
+            unsafe { core::slice::from_raw_parts_mut(PhysAddr::from(l).0 as *mut u8, frames) };
+        self.orders.fill(0);
+        self.base = l.0;
+        self.end = r.0;
+        self.total = frames - table_frames;
+        let mut ppn = l.0 + table_frames;
+        while ppn < self.end {
+            let mut order = BUDDY_MAX_ORDER - 1;
+            while ppn & ((1 << order) - 1) != 0 || ppn + (1 << order) > self.end {
+                order -= 1;
+            }
+            self.push(ppn, order);
+            ppn += 1 << order;
+        }
+        debug!("last {} Physical Frames.", self.free);
+    }
+
+    fn block(ppn: usize) -> &'static mut FreeBlock {
+        PhysPageNum(ppn).get_mut()
+    }
+
+    fn push(&mut self, ppn: usize, order: usize) {
+        let head = self.free_lists[order];
+        *Self::block(ppn) = FreeBlock {
+            prev: NIL,
+            next: head,
+        };
+        if head != NIL {
+            Self::block(head).prev = ppn;
+        }
+        self.free_lists[order] = ppn;
+        self.orders[ppn - self.base] = order as u8 + 1;
+        self.free += 1 << order;
+    }
+
+    fn remove(&mut self, ppn: usize, order: usize) {
+        let FreeBlock { prev, next } = *Self::block(ppn);
+        if prev == NIL {
+            self.free_lists[order] = next;
+        } else {
+            Self::block(prev).next = next;
+        }
+        if next != NIL {
+            Self::block(next).prev = prev;
+        }
+        self.orders[ppn - self.base] = 0;
+        self.free -= 1 << order;
+    }
+
+    fn is_free_block(&self, ppn: usize, order: usize) -> bool {
+        ppn >= self.base && ppn < self.end && self.orders[ppn - self.base] as usize == order + 1
+    }
+
+    fn alloc_order(&mut self, order: usize) -> Option<usize> {
+        let mut current = (order..BUDDY_MAX_ORDER).find(|k| self.free_lists[*k] != NIL)?;
+        let ppn = self.free_lists[current];
+        self.remove(ppn, current);
+        // split, keeping the lower half
+        while current > order {
+            current -= 1;
+            self.push(ppn + (1 << current), current);
+        }
+        Some(ppn)
+    }
+
+    fn free_one(&mut self, mut ppn: usize) {
+        let mut order = 0;
+        while order + 1 < BUDDY_MAX_ORDER {
+            let buddy = ppn ^ (1 << order);
+            if !self.is_free_block(buddy, order) {
+                break;
+            }
+            self.remove(buddy, order);
+            ppn = ppn.min(buddy);
+            order += 1;
+        }
+        self.push(ppn, order);
     }
 }
 
+impl FrameAllocator for BuddyFrameAllocator {
+    fn new() -> Self {
+        Self {
+            base: 0,
+            end: 0,
+            free_lists: [NIL; BUDDY_MAX_ORDER],
+            orders: &mut [],
+            total: 0,
+            free: 0,
+        }
+    }
+    fn alloc(&mut self) -> Option<PhysPageNum> {
+        self.alloc_order(0).map(Into::into)
+    }
+    fn alloc_contiguous(&mut self, count: usize, align: usize) -> Option<PhysPageNum> {
+        assert!(align.is_power_of_two());
+        let order = (count.next_power_of_two().trailing_zeros() as usize)
+            .max(align.trailing_zeros() as usize);
+        if order >= BUDDY_MAX_ORDER {
+            return None;
+        }
+        let ppn = self.alloc_order(order)?;
+        // give back the frames beyond `count`
+        for extra in ppn + count..ppn + (1 << order) {
+            self.free_one(extra);
+        }
+        Some(ppn.into())
+    }
+    fn dealloc(&mut self, ppn: PhysPageNum) {
+        let ppn = ppn.0;
+        // validity check: the frame must not lie in any free block
+        if ppn < self.base
+            || ppn >= self.end
+            || (0..BUDDY_MAX_ORDER)
+                .any(|order| self.is_free_block(ppn & !((1 << order) - 1), order))
+        {
+            panic!("Frame ppn={:#x} has not been allocated!", ppn);
+        }
+        self.free_one(ppn);
+    }
+    fn stats(&self) -> FrameStats {
+        FrameStats {
+            total: self.total,
+            free: self.free,
+        }
+    }
+}
+
+#[cfg(not(feature = "stack_frame_allocator"))]
+type FrameAllocatorImpl = BuddyFrameAllocator;
+#[cfg(feature = "stack_frame_allocator")]
 type FrameAllocatorImpl = StackFrameAllocator;
 
 lazy_static! {
-    pub static ref FRAME_ALLOCATOR: Mutex<FrameAllocatorImpl> =
-        Mutex::new(FrameAllocatorImpl::new());
+    pub static ref FRAME_ALLOCATOR: IrqMutex<FrameAllocatorImpl> =
+        IrqMutex::new(FrameAllocatorImpl::new());
 }
 
 pub fn init_frame_allocator() {
@@ -93,7 +316,7 @@ pub fn init_frame_allocator() {
     }
     FRAME_ALLOCATOR.lock().init(
         PhysAddr::from(ekernel as usize).ceil(),
-        PhysAddr::from(MEMORY_END).floor(),
+        PhysAddr::from(board().memory_end).floor(),
     );
 }
 
@@ -101,6 +324,27 @@ pub fn frame_alloc() -> Option<FrameTracker> {
     FRAME_ALLOCATOR.lock().alloc().map(FrameTracker::new)
 }
 
+/// `count` physically contiguous frames, the first one aligned to `align` frames.
+pub fn frame_alloc_contiguous(count: usize, align: usize) -> Option<Vec<FrameTracker>> {
+    let start = FRAME_ALLOCATOR.lock().alloc_contiguous(count, align)?;
+    Some(
+        (start.0..start.0 + count)
+            .map(|ppn| FrameTracker::new(ppn.into()))
+            .collect(),
+    )
+}
+
+pub fn frame_stats() -> FrameStats {
+    FRAME_ALLOCATOR.lock().stats()
+}
+
+/// Contiguous frames which are never given back, used to grow the kernel heap.
+/// Waits for the allocator like any other caller, interrupts are off meanwhile,
+/// so the frame allocator must not allocate from the heap while it is locked.
+pub fn frame_alloc_for_heap(count: usize) -> Option<PhysPageNum> {
+    FRAME_ALLOCATOR.lock().alloc_contiguous(count, 1)
+}
+
 fn frame_dealloc(ppn: PhysPageNum) {
     FRAME_ALLOCATOR.lock().dealloc(ppn);
 }
@@ -1,12 +1,40 @@
-use crate::config::KERNEL_HEAP_SIZE;
-use buddy_system_allocator::LockedHeap;
+use super::frame_allocator::frame_alloc_for_heap;
+use super::PhysAddr;
+use crate::config::{KERNEL_HEAP_GROW_SIZE, KERNEL_HEAP_SIZE, PAGE_SIZE};
+use crate::sync::IntrGuard;
+use buddy_system_allocator::{Heap, LockedHeap};
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+
+/// Trap handlers allocate too, so the heap lock is only held with interrupts off.
+struct IrqSafeHeap(LockedHeap);
+
+unsafe impl GlobalAlloc for IrqSafeHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _intr = IntrGuard::new();
+        let mut heap = self.0.lock();
+        if let Ok(p) = heap.alloc(layout) {
+            return p.as_ptr();
+        }
+        grow_heap(&mut heap, &layout);
+        heap.alloc(layout).map_or(ptr::null_mut(), |p| p.as_ptr())
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let _intr = IntrGuard::new();
+        self.0.dealloc(ptr, layout)
+    }
+}
 
 #[global_allocator]
-static HEAP_ALLOCATOR: LockedHeap = LockedHeap::empty();
+static HEAP_ALLOCATOR: IrqSafeHeap = IrqSafeHeap(LockedHeap::new());
 
 #[alloc_error_handler]
-pub fn handle_alloc_error(layout: core::alloc::Layout) -> ! {
-    panic!("Heap allocation error, layout = {:?}", layout);
+pub fn handle_alloc_error(layout: Layout) -> ! {
+    panic!(
+        "Heap allocation error, layout = {:?}, {:?}",
+        layout,
+        heap_stats()
+    );
 }
 
 static mut HEAP_SPACE: [u8; KERNEL_HEAP_SIZE] = [0; KERNEL_HEAP_SIZE];
@@ -14,11 +42,51 @@ static mut HEAP_SPACE: [u8; KERNEL_HEAP_SIZE] = [0; KERNEL_HEAP_SIZE];
 pub fn init_heap() {
     unsafe {
         HEAP_ALLOCATOR
+            .0
             .lock()
             .init(HEAP_SPACE.as_ptr() as usize, KERNEL_HEAP_SIZE);
     }
 }
 
+/// Called with the heap locked when `layout` does not fit, the allocation is retried afterwards.
+/// Frames handed to the heap are identity mapped in kernel space and never returned.
+fn grow_heap(heap: &mut Heap, layout: &Layout) {
+    // buddy blocks are size aligned, so room for a large layout needs twice its size
+    let size = layout
+        .size()
+        .max(layout.align())
+        .next_power_of_two()
+        .saturating_mul(2)
+        .max(KERNEL_HEAP_GROW_SIZE);
+    if let Some(ppn) = frame_alloc_for_heap(size / PAGE_SIZE) {
+        let start: usize = PhysAddr::from(ppn).into();
+        unsafe {
+            heap.add_to_heap(start, start + size);
+        }
+        // avoid logging here, formatting may allocate
+    }
+}
+
+#[derive(Debug)]
+pub struct HeapStats {
+    /// bytes requested by allocations
+    pub user: usize,
+    /// bytes actually allocated, including buddy rounding
+    pub allocated: usize,
+    /// bytes managed by the heap, including growth
+    pub total: usize,
+}
+
+pub fn heap_stats() -> HeapStats {
+    let _intr = IntrGuard::new();
+    let heap = HEAP_ALLOCATOR.0.lock();
+    HeapStats {
+        user: heap.stats_alloc_user(),
+        allocated: heap.stats_alloc_actual(),
+        total: heap.stats_total_bytes(),
+    }
+}
+
 #[allow(unused)]
 pub fn heap_test() {
     use alloc::boxed::Box;
@@ -1,14 +1,22 @@
-use super::{frame_alloc, FrameTracker};
+use super::asid::{asid_alloc, flush_tlb, AsidHandle};
+use super::elf;
+use super::{frame_alloc, frame_alloc_contiguous, FrameTracker};
 use super::{PTEFlags, PageTable, PageTableEntry};
 use super::{PhysAddr, PhysPageNum, VirtAddr, VirtPageNum};
 use super::{StepByOne, VPNRange};
-use crate::config::{MEMORY_END, PAGE_SIZE, TRAMPOLINE, TRAP_CONTEXT, USER_STACK_SIZE};
+use crate::config::{
+    HUGE_PAGE_SIZE, PAGE_SIZE, TIME_PAGE, TRAMPOLINE, TRAP_CONTEXT, USER_SPACE_END,
+    USER_STACK_SIZE, USER_TRAP_BUFFER,
+};
+use crate::errno::{ENOEXEC, ENOMEM};
+use crate::fdt::board;
+use crate::sync::IrqMutex;
+use crate::timer::time_page_ppn;
 use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use lazy_static::*;
 use riscv::register::satp;
-use spin::Mutex;
 
 extern "C" {
     fn stext();
@@ -24,24 +32,30 @@ extern "C" {
 }
 
 lazy_static! {
-    pub static ref KERNEL_SPACE: Arc<Mutex<MemorySet>> =
-        Arc::new(Mutex::new(MemorySet::new_kernel()));
+    pub static ref KERNEL_SPACE: Arc<IrqMutex<MemorySet>> =
+        Arc::new(IrqMutex::new(MemorySet::new_kernel()));
 }
 
 pub struct MemorySet {
     page_table: PageTable,
     areas: Vec<MapArea>,
+    asid: AsidHandle,
 }
 
 impl MemorySet {
-    pub fn new_bare() -> Self {
-        Self {
-            page_table: PageTable::new(),
+    pub fn new_bare() -> Result<Self, isize> {
+        Ok(Self {
+            page_table: PageTable::new()?,
             areas: Vec::new(),
-        }
+            asid: asid_alloc(),
+        })
     }
     pub fn token(&self) -> usize {
-        self.page_table.token()
+        self.page_table.token() | self.asid.0 << 44
+    }
+    /// Flush this space's TLB entries on all harts after changing its mappings.
+    pub fn flush_tlb(&self) {
+        flush_tlb(self.asid.0);
     }
     /// Assume that no conflicts.
     pub fn insert_framed_area(
@@ -49,11 +63,13 @@ impl MemorySet {
         start_va: VirtAddr,
         end_va: VirtAddr,
         permission: MapPermission,
-    ) {
+    ) -> Result<(), isize> {
         self.push(
             MapArea::new(start_va, end_va, MapType::Framed, permission),
             None,
-        );
+        )?;
+        self.flush_tlb();
+        Ok(())
     }
     pub fn remove_area_with_start_vpn(&mut self, start_vpn: VirtPageNum) {
         if let Some((idx, area)) = self
@@ -64,28 +80,42 @@ impl MemorySet {
         {
             area.unmap(&mut self.page_table);
             self.areas.remove(idx);
+            self.flush_tlb();
         }
     }
-    fn push(&mut self, mut map_area: MapArea, data: Option<&[u8]>) {
-        map_area.map(&mut self.page_table);
+    fn push(&mut self, mut map_area: MapArea, data: Option<&[u8]>) -> Result<(), isize> {
+        map_area.map(&mut self.page_table)?;
         if let Some(data) = data {
             map_area.copy_data(&mut self.page_table, data);
         }
         self.areas.push(map_area);
+        Ok(())
     }
     /// Mention that trampoline is not collected by areas.
-    fn map_trampoline(&mut self) {
+    fn map_trampoline(&mut self) -> Result<(), isize> {
         self.page_table.map(
             VirtAddr::from(TRAMPOLINE).into(),
             PhysAddr::from(strampoline as usize).into(),
             PTEFlags::R | PTEFlags::X,
-        );
+        )
+    }
+    /// Shared by all user spaces, not collected by areas either.
+    fn map_time_page(&mut self) -> Result<(), isize> {
+        self.page_table.map(
+            VirtAddr::from(TIME_PAGE).into(),
+            time_page_ppn(),
+            PTEFlags::R | PTEFlags::U,
+        )
     }
     /// Without kernel stacks.
     pub fn new_kernel() -> Self {
-        let mut memory_set = Self::new_bare();
+        let mut memory_set = Self {
+            page_table: PageTable::new().unwrap(),
+            areas: Vec::new(),
+            asid: AsidHandle(0),
+        };
         // map trampoline
-        memory_set.map_trampoline();
+        memory_set.map_trampoline().unwrap();
         // map kernel sections
         debug!(".text [{:#x}, {:#x})", stext as usize, etext as usize);
         debug!(".rodata [{:#x}, {:#x})", srodata as usize, erodata as usize);
@@ -95,115 +125,134 @@ impl MemorySet {
             sbss_with_stack as usize, ebss as usize
         );
         debug!("mapping .text section");
-        memory_set.push(
-            MapArea::new(
-                (stext as usize).into(),
-                (etext as usize).into(),
-                MapType::Identical,
-                MapPermission::R | MapPermission::X,
-            ),
-            None,
-        );
+        memory_set
+            .push(
+                MapArea::new(
+                    (stext as usize).into(),
+                    (etext as usize).into(),
+                    MapType::Identical,
+                    MapPermission::R | MapPermission::X,
+                ),
+                None,
+            )
+            .unwrap();
         debug!("mapping .rodata section");
-        memory_set.push(
-            MapArea::new(
-                (srodata as usize).into(),
-                (erodata as usize).into(),
-                MapType::Identical,
-                MapPermission::R,
-            ),
-            None,
-        );
+        memory_set
+            .push(
+                MapArea::new(
+                    (srodata as usize).into(),
+                    (erodata as usize).into(),
+                    MapType::Identical,
+                    MapPermission::R,
+                ),
+                None,
+            )
+            .unwrap();
         debug!("mapping .data section");
-        memory_set.push(
-            MapArea::new(
-                (sdata as usize).into(),
-                (edata as usize).into(),
-                MapType::Identical,
-                MapPermission::R | MapPermission::W,
-            ),
-            None,
-        );
+        memory_set
+            .push(
+                MapArea::new(
+                    (sdata as usize).into(),
+                    (edata as usize).into(),
+                    MapType::Identical,
+                    MapPermission::R | MapPermission::W,
+                ),
+                None,
+            )
+            .unwrap();
         debug!("mapping .bss section");
-        memory_set.push(
-            MapArea::new(
-                (sbss_with_stack as usize).into(),
-                (ebss as usize).into(),
-                MapType::Identical,
-                MapPermission::R | MapPermission::W,
-            ),
-            None,
-        );
+        memory_set
+            .push(
+                MapArea::new(
+                    (sbss_with_stack as usize).into(),
+                    (ebss as usize).into(),
+                    MapType::Identical,
+                    MapPermission::R | MapPermission::W,
+                ),
+                None,
+            )
+            .unwrap();
         debug!("mapping physical memory");
-        memory_set.push(
-            MapArea::new(
-                (ekernel as usize).into(),
-                MEMORY_END.into(),
-                MapType::Identical,
-                MapPermission::R | MapPermission::W,
-            ),
-            None,
-        );
+        memory_set
+            .push(
+                MapArea::new(
+                    (ekernel as usize).into(),
+                    board().memory_end.into(),
+                    MapType::Identical,
+                    MapPermission::R | MapPermission::W,
+                )
+                .with_huge_pages(),
+                None,
+            )
+            .unwrap();
         debug!("mapping plic");
-        memory_set.push(
-            MapArea::new(
-                (0xc00_0000 as usize).into(),
-                (0x1000_0000 as usize).into(),
-                MapType::Mmio,
-                MapPermission::R | MapPermission::W,
-            ),
-            None,
-        );
+        memory_set
+            .push(
+                MapArea::new(
+                    (0xc00_0000 as usize).into(),
+                    (0x1000_0000 as usize).into(),
+                    MapType::Mmio,
+                    MapPermission::R | MapPermission::W,
+                ),
+                None,
+            )
+            .unwrap();
+        debug!("mapping virtio");
+        for slot in board().virtio.iter() {
+            memory_set
+                .push(
+                    MapArea::new(
+                        slot.base.into(),
+                        (slot.base + PAGE_SIZE).into(),
+                        MapType::Mmio,
+                        MapPermission::R | MapPermission::W,
+                    ),
+                    None,
+                )
+                .unwrap();
+        }
         debug!("mapping uart");
         use crate::uart;
         #[cfg(any(feature = "board_qemu", feature = "board_lrv"))]
-        memory_set.push(
-            MapArea::new(
-                (uart::SERIAL_BASE_ADDRESS).into(),
-                (uart::SERIAL_BASE_ADDRESS + uart::SERIAL_NUM * uart::SERIAL_ADDRESS_STRIDE).into(),
-                MapType::Mmio,
-                MapPermission::R | MapPermission::W,
-            ),
-            None,
-        );
+        for serial in board().uarts.iter() {
+            memory_set
+                .push(
+                    MapArea::new(
+                        serial.base.into(),
+                        (serial.base + uart::SERIAL_ADDRESS_STRIDE).into(),
+                        MapType::Mmio,
+                        MapPermission::R | MapPermission::W,
+                    ),
+                    None,
+                )
+                .unwrap();
+        }
         memory_set
     }
     /// Include sections in elf and trampoline and TrapContext and user stack,
     /// also returns user_sp and entry point.
-    pub fn from_elf(elf_data: &[u8]) -> (Self, usize, usize) {
-        let mut memory_set = Self::new_bare();
+    pub fn from_elf(elf_data: &[u8]) -> Result<(Self, usize, usize), isize> {
+        let image = elf::parse(elf_data)?;
+        let mut memory_set = Self::new_bare()?;
         // map trampoline
-        memory_set.map_trampoline();
+        memory_set.map_trampoline()?;
+        memory_set.map_time_page()?;
         // map program headers of elf, with U flag
-        let elf = xmas_elf::ElfFile::new(elf_data).unwrap();
-        let elf_header = elf.header;
-        let magic = elf_header.pt1.magic;
-        assert_eq!(magic, [0x7f, 0x45, 0x4c, 0x46], "invalid elf!");
-        let ph_count = elf_header.pt2.ph_count();
         let mut max_end_vpn = VirtPageNum(0);
-        for i in 0..ph_count {
-            let ph = elf.program_header(i).unwrap();
-            if ph.get_type().unwrap() == xmas_elf::program::Type::Load {
-                let start_va: VirtAddr = (ph.virtual_addr() as usize).into();
-                let end_va: VirtAddr = ((ph.virtual_addr() + ph.mem_size()) as usize).into();
-                let mut map_perm = MapPermission::U;
-                let ph_flags = ph.flags();
-                if ph_flags.is_read() {
-                    map_perm |= MapPermission::R;
-                }
-                if ph_flags.is_write() {
-                    map_perm |= MapPermission::W;
-                }
-                if ph_flags.is_execute() {
-                    map_perm |= MapPermission::X;
-                }
-                let map_area = MapArea::new(start_va, end_va, MapType::Framed, map_perm);
-                max_end_vpn = map_area.vpn_range.get_end();
-                memory_set.push(
-                    map_area,
-                    Some(&elf.input[ph.offset() as usize..(ph.offset() + ph.file_size()) as usize]),
-                );
-            }
+        for segment in image.segments.iter() {
+            let map_area = MapArea::new(
+                segment.start.into(),
+                segment.end.into(),
+                MapType::Framed,
+                segment.perm,
+            );
+            max_end_vpn = max_end_vpn.max(map_area.vpn_range.get_end());
+            memory_set.push(map_area, None)?;
+            // frames come zeroed, which covers .bss, also where it shares a page with data
+            memory_set.write_bytes(segment.start, segment.data);
+        }
+        for (addr, value) in image.relocations.iter() {
+            memory_set.write_bytes(*addr, &value.to_le_bytes());
         }
         // map user stack with U flags
         let max_end_va: VirtAddr = max_end_vpn.into();
@@ -211,6 +260,9 @@ impl MemorySet {
         // guard page
         user_stack_bottom += PAGE_SIZE;
         let user_stack_top = user_stack_bottom + USER_STACK_SIZE;
+        if user_stack_top > USER_SPACE_END {
+            return Err(ENOEXEC);
+        }
         memory_set.push(
             MapArea::new(
                 user_stack_bottom.into(),
@@ -219,7 +271,7 @@ impl MemorySet {
                 MapPermission::R | MapPermission::W | MapPermission::U,
             ),
             None,
-        );
+        )?;
         // map TrapContext
         memory_set.push(
             MapArea::new(
@@ -229,21 +281,31 @@ impl MemorySet {
                 MapPermission::R | MapPermission::W,
             ),
             None,
-        );
-        (
-            memory_set,
-            user_stack_top,
-            elf.header.pt2.entry_point() as usize,
-        )
+        )?;
+        Ok((memory_set, user_stack_top, image.entry))
+    }
+    /// Store `data` at `va` through this space's page table, bypassing the
+    /// permissions. The pages must be mapped.
+    fn write_bytes(&self, va: usize, data: &[u8]) {
+        let mut written = 0;
+        while written < data.len() {
+            let va = VirtAddr::from(va + written);
+            let offset = va.page_offset();
+            let n = (PAGE_SIZE - offset).min(data.len() - written);
+            let ppn = self.page_table.translate(va.floor()).unwrap().ppn();
+            ppn.get_bytes_array()[offset..offset + n].copy_from_slice(&data[written..written + n]);
+            written += n;
+        }
     }
-    pub fn from_existed_user(user_space: &MemorySet) -> MemorySet {
-        let mut memory_set = Self::new_bare();
+    pub fn from_existed_user(user_space: &MemorySet) -> Result<MemorySet, isize> {
+        let mut memory_set = Self::new_bare()?;
         // map trampoline
-        memory_set.map_trampoline();
+        memory_set.map_trampoline()?;
+        memory_set.map_time_page()?;
         // copy data sections/trap_context/user_stack
         for area in user_space.areas.iter() {
             let new_area = MapArea::from_another(area);
-            memory_set.push(new_area, None);
+            memory_set.push(new_area, None)?;
             // copy data from another space
             for vpn in area.vpn_range {
                 let src_ppn = user_space.translate(vpn).unwrap().ppn();
@@ -253,10 +315,10 @@ impl MemorySet {
                     .copy_from_slice(src_ppn.get_bytes_array());
             }
         }
-        memory_set
+        Ok(memory_set)
     }
     pub fn activate(&self) {
-        let satp = self.page_table.token();
+        let satp = self.token();
         unsafe {
             satp::write(satp);
             llvm_asm!("sfence.vma" :::: "volatile");
@@ -267,11 +329,16 @@ impl MemorySet {
     }
 
     fn is_mapped_area(&self, start_va: VirtAddr, end_va: VirtAddr) -> bool {
+        let range = VPNRange::new(start_va.into(), end_va.into());
+        let reserved = VPNRange::new(
+            VirtAddr::from(TIME_PAGE).into(),
+            VirtAddr::from(USER_TRAP_BUFFER).into(),
+        );
+        if range.is_overlapped(&reserved) {
+            return true;
+        }
         for area in &self.areas {
-            if area
-                .vpn_range
-                .is_overlapped(&VPNRange::new(start_va.into(), end_va.into()))
-            {
+            if area.vpn_range.is_overlapped(&range) {
                 return true;
             }
         }
@@ -291,12 +358,17 @@ impl MemorySet {
             if self.is_mapped_area(start_va, end_va) {
                 return Err(-1);
             }
-            self.insert_framed_area(
-                start_va,
-                end_va,
-                MapPermission::from_bits((port << 1 | 0b10000) as u8).unwrap(),
-            );
-
+            self.push(
+                MapArea::new(
+                    start_va,
+                    end_va,
+                    MapType::Framed,
+                    MapPermission::from_bits((port << 1 | 0b10000) as u8).unwrap(),
+                )
+                .with_huge_pages(),
+                None,
+            )?;
+            self.flush_tlb();
             Ok((usize::from(end_va) - usize::from(start_va)) as isize)
         }
     }
@@ -338,7 +410,7 @@ impl MemorySet {
             self.areas[i].unmap(&mut self.page_table);
             self.areas.remove(i);
         }
-
+        self.flush_tlb();
         Ok(len as isize)
     }
 
@@ -363,12 +435,12 @@ impl MemorySet {
                     MapPermission::from_bits((port << 1 | 0b10000) as u8).unwrap(),
                 ),
                 None,
-            );
+            )?;
+            self.flush_tlb();
             Ok((usize::from(end_va) - usize::from(start_va)) as isize)
         }
     }
 
-    #[allow(dead_code)]
     pub fn mmio_unmap(&mut self, start: usize, end: usize) -> Result<isize, isize> {
         let mut start_va: VirtAddr = VirtAddr::from(start);
         if start_va != start_va.floor().into() {
@@ -406,7 +478,7 @@ impl MemorySet {
             self.areas[i].unmap(&mut self.page_table);
             self.areas.remove(i);
         }
-
+        self.flush_tlb();
         Ok((end - start) as isize)
     }
 
@@ -414,13 +486,21 @@ impl MemorySet {
         //*self = Self::new_bare();
         self.areas.clear();
     }
+
+    /// Number of frames owned by framed areas, used to pick the OOM victim.
+    pub fn frame_count(&self) -> usize {
+        self.areas.iter().map(|area| area.data_frames.len()).sum()
+    }
 }
 
+const HUGE_PAGE_FRAMES: usize = HUGE_PAGE_SIZE / PAGE_SIZE;
+
 pub struct MapArea {
     vpn_range: VPNRange,
     data_frames: BTreeMap<VirtPageNum, FrameTracker>,
     map_type: MapType,
     map_perm: MapPermission,
+    huge: bool,
 }
 
 impl MapArea {
@@ -437,34 +517,67 @@ impl MapArea {
             data_frames: BTreeMap::new(),
             map_type,
             map_perm,
+            huge: false,
         }
     }
+    /// Map aligned 2 MiB blocks inside the area with megapages.
+    pub fn with_huge_pages(mut self) -> Self {
+        self.huge = true;
+        self
+    }
     pub fn from_another(another: &MapArea) -> Self {
         Self {
             vpn_range: VPNRange::new(another.vpn_range.get_start(), another.vpn_range.get_end()),
             data_frames: BTreeMap::new(),
             map_type: another.map_type,
             map_perm: another.map_perm,
+            huge: another.huge,
         }
     }
-    pub fn map_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) {
-        let ppn: PhysPageNum;
+    fn is_huge_start(&self, vpn: VirtPageNum) -> bool {
+        self.huge
+            && vpn.0 % HUGE_PAGE_FRAMES == 0
+            && vpn.0 + HUGE_PAGE_FRAMES <= self.vpn_range.get_end().0
+    }
+    /// Returns false if there are no aligned contiguous frames for a framed area,
+    /// the block is then mapped with pages instead.
+    fn map_huge(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) -> Result<bool, isize> {
+        let pte_flags = PTEFlags::from_bits(self.map_perm.bits).unwrap();
         match self.map_type {
             MapType::Identical => {
-                ppn = PhysPageNum(vpn.0);
+                page_table.map_huge(vpn, PhysPageNum(vpn.0), pte_flags)?;
             }
-            MapType::Mmio => {
-                ppn = PhysPageNum(vpn.0);
+            MapType::Framed => {
+                let frames = match frame_alloc_contiguous(HUGE_PAGE_FRAMES, HUGE_PAGE_FRAMES) {
+                    Some(frames) => frames,
+                    None => return Ok(false),
+                };
+                page_table.map_huge(vpn, frames[0].ppn, pte_flags)?;
+                for (i, frame) in frames.into_iter().enumerate() {
+                    self.data_frames.insert(VirtPageNum(vpn.0 + i), frame);
+                }
+                trace!("map_huge: vpn {:?}", vpn);
+            }
+            MapType::Mmio => return Ok(false),
+        }
+        Ok(true)
+    }
+    pub fn map_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) -> Result<(), isize> {
+        let pte_flags = PTEFlags::from_bits(self.map_perm.bits).unwrap();
+        match self.map_type {
+            MapType::Identical | MapType::Mmio => {
+                page_table.map(vpn, PhysPageNum(vpn.0), pte_flags)?;
             }
             MapType::Framed => {
-                let frame = frame_alloc().unwrap();
-                ppn = frame.ppn;
+                let frame = frame_alloc().ok_or(ENOMEM)?;
+                let ppn = frame.ppn;
+                // the frame is released on failure since it is not tracked yet
+                page_table.map(vpn, ppn, pte_flags)?;
                 self.data_frames.insert(vpn, frame);
                 trace!("map_one: vpn {:?} ppn {:?}", vpn, ppn);
             }
         }
-        let pte_flags = PTEFlags::from_bits(self.map_perm.bits).unwrap();
-        page_table.map(vpn, ppn, pte_flags);
+        Ok(())
     }
     pub fn unmap_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) {
         if let MapType::Framed = self.map_type {
@@ -472,14 +585,53 @@ impl MapArea {
         }
         page_table.unmap(vpn);
     }
-    pub fn map(&mut self, page_table: &mut PageTable) {
-        for vpn in self.vpn_range {
-            self.map_one(page_table, vpn);
+    /// Pages mapped before a failure are unmapped again.
+    pub fn map(&mut self, page_table: &mut PageTable) -> Result<(), isize> {
+        let mut vpn = self.vpn_range.get_start();
+        while vpn < self.vpn_range.get_end() {
+            let result = if self.is_huge_start(vpn) {
+                self.map_huge(page_table, vpn)
+            } else {
+                Ok(false)
+            };
+            let result = match result {
+                Ok(true) => {
+                    vpn.0 += HUGE_PAGE_FRAMES;
+                    continue;
+                }
+                Ok(false) => self.map_one(page_table, vpn),
+                Err(errno) => Err(errno),
+            };
+            if let Err(errno) = result {
+                self.unmap_range(page_table, self.vpn_range.get_start(), vpn);
+                return Err(errno);
+            }
+            vpn.step();
         }
+        Ok(())
     }
     pub fn unmap(&mut self, page_table: &mut PageTable) {
-        for vpn in self.vpn_range {
-            self.unmap_one(page_table, vpn);
+        self.unmap_range(
+            page_table,
+            self.vpn_range.get_start(),
+            self.vpn_range.get_end(),
+        );
+    }
+    fn unmap_range(&mut self, page_table: &mut PageTable, start: VirtPageNum, end: VirtPageNum) {
+        let mut vpn = start;
+        while vpn < end {
+            if self.is_huge_start(vpn) && page_table.is_huge_mapped(vpn) {
+                page_table.unmap_huge(vpn);
+                if let MapType::Framed = self.map_type {
+                    for i in 0..HUGE_PAGE_FRAMES {
+                        self.data_frames.remove(&VirtPageNum(vpn.0 + i));
+                    }
+                }
+                vpn.0 += HUGE_PAGE_FRAMES;
+            } else {
+                self.unmap_one(page_table, vpn);
+                vpn.step();
+            }
         }
     }
     /// data: start-aligned but maybe with shorter length
@@ -1,4 +1,6 @@
 mod address;
+mod asid;
+mod elf;
 mod frame_allocator;
 mod heap_allocator;
 mod memory_set;
@@ -6,12 +8,16 @@ mod page_table;
 
 pub use address::{PhysAddr, PhysPageNum, VirtAddr, VirtPageNum};
 use address::{StepByOne, VPNRange};
-pub use frame_allocator::{frame_alloc, FrameTracker};
+pub use asid::local_flush_tlb;
+pub use frame_allocator::{
+    frame_alloc, frame_alloc_contiguous, frame_stats, FrameStats, FrameTracker,
+};
+pub use heap_allocator::{heap_stats, HeapStats};
 pub use memory_set::remap_test;
 pub use memory_set::{MapPermission, MemorySet, KERNEL_SPACE};
 pub use page_table::{
-    translate_writable_va, translated_byte_buffer, translated_refmut, translated_str,
-    PageTableEntry, UserBuffer, UserBufferIterator,
+    copy_from_user, copy_to_user, translate_writable_va, translated_byte_buffer, translated_refmut,
+    translated_str, PageTableEntry, UserBuffer, UserBufferIterator,
 };
 use page_table::{PTEFlags, PageTable};
 
@@ -19,6 +25,7 @@ pub fn init() {
     heap_allocator::init_heap();
     frame_allocator::init_frame_allocator();
     KERNEL_SPACE.lock().activate();
+    asid::init_asid();
 }
 
 pub fn init_kernel_space() {
@@ -1,8 +1,11 @@
 use super::{frame_alloc, FrameTracker, PhysAddr, PhysPageNum, StepByOne, VirtAddr, VirtPageNum};
+use crate::config::{HUGE_PAGE_SIZE, PAGE_SIZE};
+use crate::errno::{EFAULT, ENOMEM};
 use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
 use bitflags::*;
+use core::mem::{size_of, MaybeUninit};
 
 bitflags! {
     pub struct PTEFlags: u8 {
@@ -50,21 +53,27 @@ impl PageTableEntry {
     pub fn executable(&self) -> bool {
         (self.flags() & PTEFlags::X) != PTEFlags::empty()
     }
+    /// A valid entry is a leaf if any of R/W/X is set, otherwise it points to the next level.
+    pub fn is_leaf(&self) -> bool {
+        self.readable() || self.writable() || self.executable()
+    }
 }
 
+const HUGE_PAGE_FRAMES: usize = HUGE_PAGE_SIZE / PAGE_SIZE;
+
 pub struct PageTable {
     root_ppn: PhysPageNum,
     frames: Vec<FrameTracker>,
 }
 
-/// Assume that it won't oom when creating/mapping.
+/// Creating/mapping returns `ENOMEM` when running out of frames.
 impl PageTable {
-    pub fn new() -> Self {
-        let frame = frame_alloc().unwrap();
-        PageTable {
+    pub fn new() -> Result<Self, isize> {
+        let frame = frame_alloc().ok_or(ENOMEM)?;
+        Ok(PageTable {
             root_ppn: frame.ppn,
             frames: vec![frame],
-        }
+        })
     }
     /// Temporarily used to get arguments from user space.
     pub fn from_token(satp: usize) -> Self {
@@ -74,32 +83,42 @@ impl PageTable {
         }
     }
     fn find_pte_create(&mut self, vpn: VirtPageNum) -> Option<&mut PageTableEntry> {
+        self.find_pte_create_at(vpn, 2)
+    }
+    /// Level 1 holds megapage leaves, level 2 holds page leaves.
+    fn find_pte_create_at(
+        &mut self,
+        vpn: VirtPageNum,
+        level: usize,
+    ) -> Option<&mut PageTableEntry> {
         let idxs = vpn.indexes();
         let mut ppn = self.root_ppn;
         let mut result: Option<&mut PageTableEntry> = None;
-        for i in 0..3 {
+        for i in 0..=level {
             let pte = &mut ppn.get_pte_array()[idxs[i]];
-            if i == 2 {
+            if i == level {
                 result = Some(pte);
                 break;
             }
             if !pte.is_valid() {
-                let frame = frame_alloc().unwrap();
+                let frame = frame_alloc()?;
                 *pte = PageTableEntry::new(frame.ppn, PTEFlags::V);
                 self.frames.push(frame);
             }
+            assert!(!pte.is_leaf(), "vpn {:?} is inside a huge page", vpn);
             ppn = pte.ppn();
         }
         result
     }
-    fn find_pte(&self, vpn: VirtPageNum) -> Option<&PageTableEntry> {
+    /// Also returns the level of the leaf.
+    fn find_pte(&self, vpn: VirtPageNum) -> Option<(&PageTableEntry, usize)> {
         let idxs = vpn.indexes();
         let mut ppn = self.root_ppn;
-        let mut result: Option<&PageTableEntry> = None;
+        let mut result: Option<(&PageTableEntry, usize)> = None;
         for i in 0..3 {
             let pte = &ppn.get_pte_array()[idxs[i]];
-            if i == 2 {
-                result = Some(pte);
+            if i == 2 || (pte.is_valid() && pte.is_leaf()) {
+                result = Some((pte, i));
                 break;
             }
             if !pte.is_valid() {
@@ -110,12 +129,18 @@ impl PageTable {
         result
     }
     #[allow(unused)]
-    pub fn map(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags) {
-        let pte = self.find_pte_create(vpn).unwrap();
+    pub fn map(
+        &mut self,
+        vpn: VirtPageNum,
+        ppn: PhysPageNum,
+        flags: PTEFlags,
+    ) -> Result<(), isize> {
+        let pte = self.find_pte_create(vpn).ok_or(ENOMEM)?;
         assert!(!pte.is_valid(), "vpn {:?} is mapped before mapping", vpn);
         #[cfg(feature = "board_lrv")]
         let flags = flags | PTEFlags::A | PTEFlags::D;
         *pte = PageTableEntry::new(ppn, flags | PTEFlags::V);
+        Ok(())
     }
     #[allow(unused)]
     pub fn unmap(&mut self, vpn: VirtPageNum) {
@@ -123,11 +148,51 @@ impl PageTable {
         assert!(pte.is_valid(), "vpn {:?} is invalid before unmapping", vpn);
         *pte = PageTableEntry::empty();
     }
+    /// Sv39 megapage, both `vpn` and `ppn` must be 2 MiB aligned.
+    pub fn map_huge(
+        &mut self,
+        vpn: VirtPageNum,
+        ppn: PhysPageNum,
+        flags: PTEFlags,
+    ) -> Result<(), isize> {
+        assert!(
+            vpn.0 % HUGE_PAGE_FRAMES == 0 && ppn.0 % HUGE_PAGE_FRAMES == 0,
+            "{:?} -> {:?} is not huge page aligned",
+            vpn,
+            ppn
+        );
+        let pte = self.find_pte_create_at(vpn, 1).ok_or(ENOMEM)?;
+        assert!(!pte.is_valid(), "vpn {:?} is mapped before mapping", vpn);
+        #[cfg(feature = "board_lrv")]
+        let flags = flags | PTEFlags::A | PTEFlags::D;
+        *pte = PageTableEntry::new(ppn, flags | PTEFlags::V);
+        Ok(())
+    }
+    pub fn unmap_huge(&mut self, vpn: VirtPageNum) {
+        let pte = self.find_pte_create_at(vpn, 1).unwrap();
+        assert!(
+            pte.is_valid() && pte.is_leaf(),
+            "vpn {:?} is not a huge page before unmapping",
+            vpn
+        );
+        *pte = PageTableEntry::empty();
+    }
+    pub fn is_huge_mapped(&self, vpn: VirtPageNum) -> bool {
+        matches!(self.find_pte(vpn), Some((_, 1)))
+    }
+    /// Pages inside a huge page are reported as if mapped on their own.
     pub fn translate(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
-        self.find_pte(vpn).copied()
+        self.find_pte(vpn).map(|(pte, level)| {
+            if level == 2 {
+                *pte
+            } else {
+                let offset = vpn.0 & ((1 << (9 * (2 - level))) - 1);
+                PageTableEntry::new((pte.ppn().0 + offset).into(), pte.flags())
+            }
+        })
     }
     pub fn translate_va(&self, va: VirtAddr) -> Option<PhysAddr> {
-        self.find_pte(va.clone().floor()).map(|pte| {
+        self.translate(va.clone().floor()).map(|pte| {
             let aligned_pa: PhysAddr = pte.ppn().into();
             let offset = va.page_offset();
             let aligned_pa_usize: usize = aligned_pa.into();
@@ -215,6 +280,35 @@ pub fn translated_refmut<T>(token: usize, ptr: *mut T) -> &'static mut T {
         .get_mut()
 }
 
+/// Copies `value` out to user space, `ptr` may straddle pages.
+pub fn copy_to_user<T: Copy>(token: usize, ptr: *mut T, value: &T) -> Result<(), isize> {
+    let bytes =
+        unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+    let buffers =
+        translated_byte_buffer(token, ptr as *const u8, size_of::<T>()).map_err(|_| EFAULT)?;
+    let mut offset = 0;
+    for buffer in buffers {
+        buffer.copy_from_slice(&bytes[offset..offset + buffer.len()]);
+        offset += buffer.len();
+    }
+    Ok(())
+}
+
+/// Copies a `T` in from user space, `ptr` may straddle pages.
+pub fn copy_from_user<T: Copy>(token: usize, ptr: *const T) -> Result<T, isize> {
+    let mut value = MaybeUninit::<T>::uninit();
+    let bytes =
+        unsafe { core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>()) };
+    let buffers =
+        translated_byte_buffer(token, ptr as *const u8, size_of::<T>()).map_err(|_| EFAULT)?;
+    let mut offset = 0;
+    for buffer in buffers {
+        bytes[offset..offset + buffer.len()].copy_from_slice(buffer);
+        offset += buffer.len();
+    }
+    Ok(unsafe { value.assume_init() })
+}
+
 pub struct UserBuffer {
     pub buffers: Vec<&'static mut [u8]>,
 }
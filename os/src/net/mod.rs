@@ -0,0 +1,181 @@
+//! IPv4 over the virtio NIC, with TCP and UDP sockets from smoltcp. The
+//! addresses fit QEMU's user mode networking.
+
+mod socket;
+
+pub use socket::{NetSocket, SocketKind};
+
+use crate::sync::IrqMutex;
+use crate::task::WaitQueue;
+use crate::timer::get_time_ms;
+use crate::virtio::{net_device, RxBuffer, VirtIONet, MAX_FRAME_LEN};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use lazy_static::*;
+use smoltcp::iface::{Interface, InterfaceBuilder, NeighborCache, Routes};
+use smoltcp::phy::{Device, DeviceCapabilities, RxToken, TxToken};
+use smoltcp::socket::{SocketHandle, SocketSet, TcpSocket, TcpState};
+use smoltcp::time::Instant;
+use smoltcp::wire::{EthernetAddress, IpCidr, Ipv4Address};
+
+const IP_ADDR: [u8; 4] = [10, 0, 2, 15];
+const IP_PREFIX: u8 = 24;
+const GATEWAY: [u8; 4] = [10, 0, 2, 2];
+
+pub struct NetDevice(Arc<VirtIONet>);
+
+pub struct NetRxToken {
+    net: Arc<VirtIONet>,
+    buffer: Option<RxBuffer>,
+}
+
+pub struct NetTxToken(Arc<VirtIONet>);
+
+impl<'a> Device<'a> for NetDevice {
+    type RxToken = NetRxToken;
+    type TxToken = NetTxToken;
+
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        let buffer = self.0.take_rx()?;
+        Some((
+            NetRxToken {
+                net: self.0.clone(),
+                buffer: Some(buffer),
+            },
+            NetTxToken(self.0.clone()),
+        ))
+    }
+
+    fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        if self.0.can_transmit() {
+            Some(NetTxToken(self.0.clone()))
+        } else {
+            None
+        }
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.max_transmission_unit = MAX_FRAME_LEN;
+        caps.max_burst_size = Some(1);
+        caps
+    }
+}
+
+impl RxToken for NetRxToken {
+    fn consume<R, F>(mut self, _timestamp: Instant, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        f(self.buffer.as_mut().unwrap().frame())
+    }
+}
+
+impl Drop for NetRxToken {
+    fn drop(&mut self) {
+        if let Some(buffer) = self.buffer.take() {
+            self.net.recycle_rx(buffer);
+        }
+    }
+}
+
+impl TxToken for NetTxToken {
+    fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        // another token may have taken the last buffer since `transmit`
+        self.0
+            .transmit(len, f)
+            .unwrap_or(Err(smoltcp::Error::Exhausted))
+    }
+}
+
+pub struct NetStack {
+    iface: Interface<'static, NetDevice>,
+    sockets: SocketSet<'static>,
+    /// TCP sockets whose fd is gone, removed once the connection is over.
+    closing: Vec<SocketHandle>,
+    /// Socket calls waiting for the state of their socket to change.
+    waiters: WaitQueue,
+}
+
+lazy_static! {
+    static ref NET: IrqMutex<Option<NetStack>> = IrqMutex::new(None);
+}
+
+fn now() -> Instant {
+    Instant::from_millis(get_time_ms() as i64)
+}
+
+/// Bring up the interface on the NIC found by `virtio::init`, if any.
+pub fn init() {
+    let net = match net_device() {
+        Some(net) => net,
+        None => return,
+    };
+    let [a, b, c, d] = IP_ADDR;
+    let [ga, gb, gc, gd] = GATEWAY;
+    let mut routes = Routes::new(BTreeMap::new());
+    routes
+        .add_default_ipv4_route(Ipv4Address::new(ga, gb, gc, gd))
+        .unwrap();
+    let iface = InterfaceBuilder::new(NetDevice(net.clone()))
+        .ethernet_addr(EthernetAddress(net.mac()))
+        .neighbor_cache(NeighborCache::new(BTreeMap::new()))
+        .ip_addrs(vec![IpCidr::new(
+            Ipv4Address::new(a, b, c, d).into(),
+            IP_PREFIX,
+        )])
+        .routes(routes)
+        .finalize();
+    info!("[net] {:?}/{}", IP_ADDR, IP_PREFIX);
+    *NET.lock() = Some(NetStack {
+        iface,
+        sockets: SocketSet::new(Vec::new()),
+        closing: Vec::new(),
+        waiters: WaitQueue::new(),
+    });
+}
+
+/// Move packets between the NIC and the sockets. Called from the NIC
+/// interrupt and the scheduler tick, and after every socket operation.
+pub fn poll() {
+    // a socket call holding the stack polls on its way out
+    if let Some(mut net) = NET.try_lock() {
+        if let Some(stack) = net.as_mut() {
+            stack.poll();
+        }
+    }
+}
+
+/// Run `f` on the stack and poll afterwards, `None` without a NIC.
+fn with_stack<R>(f: impl FnOnce(&mut NetStack) -> R) -> Option<R> {
+    let mut net = NET.lock();
+    let stack = net.as_mut()?;
+    let result = f(stack);
+    stack.poll();
+    Some(result)
+}
+
+impl NetStack {
+    fn poll(&mut self) {
+        // packets moved, so any socket may be ready now
+        match self.iface.poll(&mut self.sockets, now()) {
+            Ok(true) => self.waiters.wake_all(),
+            Ok(false) => {}
+            Err(e) => trace!("[net] poll: {}", e),
+        }
+        let sockets = &mut self.sockets;
+        self.closing.retain(|&handle| {
+            let state = sockets.get::<TcpSocket>(handle).state();
+            let done = matches!(state, TcpState::Closed | TcpState::TimeWait);
+            if done {
+                sockets.remove(handle);
+            }
+            !done
+        });
+    }
+}
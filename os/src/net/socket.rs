@@ -0,0 +1,370 @@
+use super::{with_stack, NetStack};
+use crate::errno::{
+    EADDRINUSE, ECONNREFUSED, EDESTADDRREQ, EINTR, EINVAL, EISCONN, EMSGSIZE, ENETDOWN, ENOTCONN,
+    EOPNOTSUPP, EPIPE,
+};
+use crate::fs::File;
+use crate::mm::UserBuffer;
+use crate::sync::IrqMutex;
+use crate::task::{block_current_and_run_next, current_signal_pending};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU16, Ordering};
+use smoltcp::socket::{
+    SocketHandle, TcpSocket, TcpSocketBuffer, TcpState, UdpPacketMetadata, UdpSocket,
+    UdpSocketBuffer,
+};
+use smoltcp::wire::{IpAddress, IpEndpoint};
+
+const TCP_BUFFER_SIZE: usize = 8192;
+const UDP_BUFFER_SIZE: usize = 8192;
+const UDP_PACKETS: usize = 16;
+
+const EPHEMERAL_BASE: u16 = 49152;
+const EPHEMERAL_COUNT: u16 = 16384;
+
+static NEXT_EPHEMERAL: AtomicU16 = AtomicU16::new(0);
+
+fn ephemeral_port() -> u16 {
+    EPHEMERAL_BASE + NEXT_EPHEMERAL.fetch_add(1, Ordering::Relaxed) % EPHEMERAL_COUNT
+}
+
+/// Leave the stack's waiters when a wait ends on a signal.
+fn stop_waiting() {
+    with_stack(|stack| stack.waiters.remove_current());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketKind {
+    Tcp,
+    Udp,
+}
+
+struct SocketInner {
+    handle: SocketHandle,
+    /// 0 until bound, or picked by `connect`.
+    port: u16,
+    /// Where UDP writes go.
+    peer: Option<IpEndpoint>,
+}
+
+/// A TCP or UDP socket behind a file descriptor.
+pub struct NetSocket {
+    kind: SocketKind,
+    inner: IrqMutex<SocketInner>,
+}
+
+fn new_tcp(stack: &mut NetStack) -> SocketHandle {
+    stack.sockets.add(TcpSocket::new(
+        TcpSocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+        TcpSocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+    ))
+}
+
+fn new_udp(stack: &mut NetStack) -> SocketHandle {
+    stack.sockets.add(UdpSocket::new(
+        UdpSocketBuffer::new(
+            vec![UdpPacketMetadata::EMPTY; UDP_PACKETS],
+            vec![0; UDP_BUFFER_SIZE],
+        ),
+        UdpSocketBuffer::new(
+            vec![UdpPacketMetadata::EMPTY; UDP_PACKETS],
+            vec![0; UDP_BUFFER_SIZE],
+        ),
+    ))
+}
+
+impl NetSocket {
+    pub fn new(kind: SocketKind) -> Result<Self, isize> {
+        let handle = with_stack(|stack| match kind {
+            SocketKind::Tcp => new_tcp(stack),
+            SocketKind::Udp => new_udp(stack),
+        })
+        .ok_or(ENETDOWN)?;
+        Ok(Self::with_handle(kind, handle, 0))
+    }
+
+    fn with_handle(kind: SocketKind, handle: SocketHandle, port: u16) -> Self {
+        NetSocket {
+            kind,
+            inner: IrqMutex::new(SocketInner {
+                handle,
+                port,
+                peer: None,
+            }),
+        }
+    }
+
+    pub fn bind(&self, port: u16) -> Result<(), isize> {
+        let mut inner = self.inner.lock();
+        if port == 0 || inner.port != 0 {
+            return Err(EINVAL);
+        }
+        if self.kind == SocketKind::Udp {
+            let handle = inner.handle;
+            with_stack(|stack| stack.sockets.get::<UdpSocket>(handle).bind(port))
+                .ok_or(ENETDOWN)?
+                .map_err(|_| EADDRINUSE)?;
+        }
+        inner.port = port;
+        Ok(())
+    }
+
+    pub fn listen(&self) -> Result<(), isize> {
+        if self.kind != SocketKind::Tcp {
+            return Err(EOPNOTSUPP);
+        }
+        let inner = self.inner.lock();
+        if inner.port == 0 {
+            return Err(EINVAL);
+        }
+        with_stack(|stack| {
+            stack
+                .sockets
+                .get::<TcpSocket>(inner.handle)
+                .listen(inner.port)
+        })
+        .ok_or(ENETDOWN)?
+        .map_err(|_| EINVAL)
+    }
+
+    /// Wait for a connection. It keeps the socket it arrived on and a fresh
+    /// one takes over listening.
+    pub fn accept(&self) -> Result<NetSocket, isize> {
+        if self.kind != SocketKind::Tcp {
+            return Err(EOPNOTSUPP);
+        }
+        loop {
+            if current_signal_pending() {
+                stop_waiting();
+                return Err(EINTR);
+            }
+            {
+                let mut inner = self.inner.lock();
+                let port = inner.port;
+                let accepted = with_stack(|stack| {
+                    let state = stack.sockets.get::<TcpSocket>(inner.handle).state();
+                    match state {
+                        TcpState::Listen | TcpState::SynReceived => {
+                            stack.waiters.add_current();
+                            Ok(None)
+                        }
+                        TcpState::Closed => Err(EINVAL),
+                        _ => {
+                            let listener = new_tcp(stack);
+                            stack
+                                .sockets
+                                .get::<TcpSocket>(listener)
+                                .listen(port)
+                                .map_err(|_| EINVAL)?;
+                            Ok(Some(core::mem::replace(&mut inner.handle, listener)))
+                        }
+                    }
+                })
+                .ok_or(ENETDOWN)??;
+                if let Some(handle) = accepted {
+                    return Ok(Self::with_handle(SocketKind::Tcp, handle, port));
+                }
+            }
+            block_current_and_run_next();
+        }
+    }
+
+    /// TCP waits for the handshake, UDP only records where writes go.
+    pub fn connect(&self, addr: [u8; 4], port: u16) -> Result<(), isize> {
+        let endpoint = IpEndpoint::new(IpAddress::v4(addr[0], addr[1], addr[2], addr[3]), port);
+        {
+            let mut inner = self.inner.lock();
+            let handle = inner.handle;
+            match self.kind {
+                SocketKind::Udp => {
+                    if inner.port == 0 {
+                        let local = ephemeral_port();
+                        with_stack(|stack| stack.sockets.get::<UdpSocket>(handle).bind(local))
+                            .ok_or(ENETDOWN)?
+                            .map_err(|_| EADDRINUSE)?;
+                        inner.port = local;
+                    }
+                    inner.peer = Some(endpoint);
+                    return Ok(());
+                }
+                SocketKind::Tcp => {
+                    if inner.port == 0 {
+                        inner.port = ephemeral_port();
+                    }
+                    let local = inner.port;
+                    with_stack(|stack| {
+                        stack
+                            .sockets
+                            .get::<TcpSocket>(handle)
+                            .connect(endpoint, local)
+                    })
+                    .ok_or(ENETDOWN)?
+                    .map_err(|_| EISCONN)?;
+                }
+            }
+        }
+        let handle = self.inner.lock().handle;
+        loop {
+            if current_signal_pending() {
+                stop_waiting();
+                return Err(EINTR);
+            }
+            let state = with_stack(|stack| {
+                let state = stack.sockets.get::<TcpSocket>(handle).state();
+                if let TcpState::SynSent | TcpState::SynReceived = state {
+                    stack.waiters.add_current();
+                }
+                state
+            })
+            .ok_or(ENETDOWN)?;
+            match state {
+                TcpState::Established => return Ok(()),
+                TcpState::SynSent | TcpState::SynReceived => block_current_and_run_next(),
+                _ => return Err(ECONNREFUSED),
+            }
+        }
+    }
+
+    /// One attempt at a read, `None` if it has to wait, with the task queued
+    /// on the stack's waiters.
+    fn try_read(&self, buf: &mut UserBuffer) -> Result<Option<usize>, isize> {
+        let handle = self.inner.lock().handle;
+        with_stack(|stack| match self.kind {
+            SocketKind::Tcp => {
+                let mut socket = stack.sockets.get::<TcpSocket>(handle);
+                if !socket.can_recv() {
+                    // end of stream once the peer has closed
+                    if !socket.may_recv() {
+                        return Ok(Some(0));
+                    }
+                    stack.waiters.add_current();
+                    return Ok(None);
+                }
+                let mut read_size = 0;
+                for slice in buf.buffers.iter_mut() {
+                    let n = socket.recv_slice(slice).map_err(|_| ENOTCONN)?;
+                    read_size += n;
+                    if n < slice.len() {
+                        break;
+                    }
+                }
+                Ok(Some(read_size))
+            }
+            SocketKind::Udp => {
+                let mut socket = stack.sockets.get::<UdpSocket>(handle);
+                if !socket.can_recv() {
+                    stack.waiters.add_current();
+                    return Ok(None);
+                }
+                // the rest of a datagram larger than the buffer is dropped
+                let (mut payload, _) = socket.recv().map_err(|_| ENOTCONN)?;
+                let mut read_size = 0;
+                for slice in buf.buffers.iter_mut() {
+                    let n = slice.len().min(payload.len());
+                    slice[..n].copy_from_slice(&payload[..n]);
+                    payload = &payload[n..];
+                    read_size += n;
+                }
+                Ok(Some(read_size))
+            }
+        })
+        .ok_or(ENETDOWN)?
+    }
+
+    /// Send from `data[sent..]`, returns the new `sent` or `None` to wait, like `try_read`.
+    fn try_write(&self, data: &[u8], sent: usize) -> Result<Option<usize>, isize> {
+        let inner = self.inner.lock();
+        let handle = inner.handle;
+        let peer = inner.peer;
+        drop(inner);
+        with_stack(|stack| match self.kind {
+            SocketKind::Tcp => {
+                let mut socket = stack.sockets.get::<TcpSocket>(handle);
+                if !socket.may_send() {
+                    return if sent == 0 {
+                        Err(EPIPE)
+                    } else {
+                        Ok(Some(sent))
+                    };
+                }
+                if !socket.can_send() {
+                    stack.waiters.add_current();
+                    return Ok(None);
+                }
+                let n = socket.send_slice(&data[sent..]).map_err(|_| EPIPE)?;
+                Ok(Some(sent + n))
+            }
+            SocketKind::Udp => {
+                let peer = peer.ok_or(EDESTADDRREQ)?;
+                let mut socket = stack.sockets.get::<UdpSocket>(handle);
+                match socket.send_slice(data, peer) {
+                    Ok(()) => Ok(Some(data.len())),
+                    Err(smoltcp::Error::Exhausted) => {
+                        stack.waiters.add_current();
+                        Ok(None)
+                    }
+                    Err(smoltcp::Error::Truncated) => Err(EMSGSIZE),
+                    Err(_) => Err(EINVAL),
+                }
+            }
+        })
+        .ok_or(ENETDOWN)?
+    }
+}
+
+impl File for NetSocket {
+    fn read(&self, mut buf: UserBuffer) -> Result<usize, isize> {
+        loop {
+            if current_signal_pending() {
+                stop_waiting();
+                return Err(EINTR);
+            }
+            match self.try_read(&mut buf)? {
+                Some(read_size) => return Ok(read_size),
+                None => block_current_and_run_next(),
+            }
+        }
+    }
+
+    fn write(&self, buf: UserBuffer) -> Result<usize, isize> {
+        let data: Vec<u8> = buf
+            .buffers
+            .iter()
+            .flat_map(|slice| slice.iter().copied())
+            .collect();
+        let mut sent = 0;
+        loop {
+            if current_signal_pending() {
+                stop_waiting();
+                return if sent == 0 { Err(EINTR) } else { Ok(sent) };
+            }
+            match self.try_write(&data, sent)? {
+                // a short count when the peer stopped taking data
+                Some(n) if n == data.len() || (n == sent && n > 0) => return Ok(n),
+                Some(n) => sent = n,
+                None => block_current_and_run_next(),
+            }
+        }
+    }
+
+    fn as_socket(&self) -> Option<&NetSocket> {
+        Some(self)
+    }
+}
+
+impl Drop for NetSocket {
+    fn drop(&mut self) {
+        let handle = self.inner.lock().handle;
+        let kind = self.kind;
+        with_stack(|stack| match kind {
+            SocketKind::Tcp => {
+                stack.sockets.get::<TcpSocket>(handle).close();
+                stack.closing.push(handle);
+            }
+            SocketKind::Udp => {
+                stack.sockets.remove(handle);
+            }
+        });
+    }
+}
@@ -1,5 +1,5 @@
+use crate::device;
 use crate::trap::{push_trap_record, UserTrapRecord, USER_EXT_INT_MAP};
-use crate::uart;
 use rv_plic::{Priority, PLIC};
 
 #[cfg(any(feature = "board_qemu", feature = "board_lrv"))]
@@ -20,29 +20,19 @@ pub fn get_context(hart_id: usize, mode: char) -> usize {
         }
 }
 
-#[cfg(feature = "board_qemu")]
-pub fn init() {
-    Plic::set_priority(12, Priority::lowest());
-    Plic::set_priority(13, Priority::lowest());
-    Plic::set_priority(14, Priority::lowest());
-    Plic::set_priority(15, Priority::lowest());
-}
-
-#[cfg(feature = "board_lrv")]
+/// Devices must be registered by now, see `device::register_device`.
 pub fn init() {
-    Plic::set_priority(4, Priority::lowest());
-    Plic::set_priority(5, Priority::lowest());
-    Plic::set_priority(6, Priority::lowest());
-    Plic::set_priority(7, Priority::lowest());
+    for irq in device::irqs() {
+        Plic::set_priority(irq, Priority::lowest());
+    }
 }
 
 #[cfg(feature = "board_qemu")]
 pub fn init_hart(hart_id: usize) {
     let context = get_context(hart_id, 'S');
-    Plic::enable(context, 12);
-    Plic::enable(context, 13);
-    Plic::enable(context, 14);
-    Plic::enable(context, 15);
+    for irq in device::irqs() {
+        Plic::enable(context, irq);
+    }
     Plic::set_threshold(context, Priority::any());
 }
 
@@ -51,10 +41,9 @@ pub fn init_hart(hart_id: usize) {
     let context = get_context(hart_id, 'S');
     Plic::clear_enable(context, 0);
     Plic::clear_enable(get_context(hart_id, 'U'), 0);
-    Plic::enable(context, 4);
-    Plic::enable(context, 5);
-    Plic::enable(context, 6);
-    Plic::enable(context, 7);
+    for irq in device::irqs() {
+        Plic::enable(context, irq);
+    }
     Plic::set_threshold(context, Priority::any());
     Plic::set_threshold(get_context(hart_id, 'M'), Priority::never());
 }
@@ -80,20 +69,8 @@ pub fn handle_external_interrupt(hart_id: usize) {
             // prioritize_task(*pid);
         }
         if !can_user_handle {
-            match irq {
-                #[cfg(feature = "board_qemu")]
-                12 | 13 | 14 | 15 => {
-                    uart::handle_interrupt(irq);
-                    trace!("[PLIC] irq {:?} handled by kenel", irq);
-                }
-                #[cfg(feature = "board_lrv")]
-                4 | 5 | 6 | 7 => {
-                    uart::handle_interrupt(irq);
-                    // trace!("[PLIC] irq {:?} handled by kenel", irq);
-                }
-                _ => {
-                    warn!("[PLIC]: irq {:?} not supported!", irq);
-                }
+            if !device::handle_irq(irq) {
+                warn!("[PLIC]: irq {:?} not supported!", irq);
             }
             Plic::complete(context, irq);
         }
@@ -1,5 +1,7 @@
 #![allow(unused)]
 
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 const SBI_SET_TIMER: usize = 0;
 const SBI_CONSOLE_PUTCHAR: usize = 1;
 const SBI_CONSOLE_GETCHAR: usize = 2;
@@ -10,13 +12,87 @@ const SBI_REMOTE_SFENCE_VMA: usize = 6;
 const SBI_REMOTE_SFENCE_VMA_ASID: usize = 7;
 const SBI_SHUTDOWN: usize = 8;
 
+const EID_BASE: usize = 0x10;
+const EID_TIME: usize = 0x5449_4D45;
+const EID_IPI: usize = 0x73_5049;
+const EID_RFENCE: usize = 0x5246_4E43;
+const EID_HSM: usize = 0x48_534D;
+const EID_SRST: usize = 0x5352_5354;
+
+pub const HSM_SUSPEND_RETENTIVE: u32 = 0;
+pub const HSM_SUSPEND_NON_RETENTIVE: u32 = 0x8000_0000;
+
+pub const RESET_TYPE_SHUTDOWN: u32 = 0;
+pub const RESET_TYPE_COLD_REBOOT: u32 = 1;
+pub const RESET_TYPE_WARM_REBOOT: u32 = 2;
+pub const RESET_REASON_NO_REASON: u32 = 0;
+pub const RESET_REASON_SYSTEM_FAILURE: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SbiError {
+    Failed,
+    NotSupported,
+    InvalidParam,
+    Denied,
+    InvalidAddress,
+    AlreadyAvailable,
+    AlreadyStarted,
+    AlreadyStopped,
+    Unknown(isize),
+}
+
+impl From<isize> for SbiError {
+    fn from(error: isize) -> Self {
+        match error {
+            -1 => SbiError::Failed,
+            -2 => SbiError::NotSupported,
+            -3 => SbiError::InvalidParam,
+            -4 => SbiError::Denied,
+            -5 => SbiError::InvalidAddress,
+            -6 => SbiError::AlreadyAvailable,
+            -7 => SbiError::AlreadyStarted,
+            -8 => SbiError::AlreadyStopped,
+            e => SbiError::Unknown(e),
+        }
+    }
+}
+
+pub type SbiResult<T> = Result<T, SbiError>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HartStatus {
+    Started,
+    Stopped,
+    StartPending,
+    StopPending,
+    Suspended,
+    SuspendPending,
+    ResumePending,
+}
+
+/// Extensions found by `init`, one bit each.
+#[derive(Clone, Copy)]
+enum Extension {
+    Time = 0,
+    Ipi = 1,
+    Rfence = 2,
+    Hsm = 3,
+    Srst = 4,
+}
+
+static EXTENSIONS: AtomicUsize = AtomicUsize::new(0);
+
+fn has(ext: Extension) -> bool {
+    EXTENSIONS.load(Ordering::Relaxed) & (1 << ext as usize) != 0
+}
+
 #[inline(always)]
-fn sbi_call(which: usize, arg0: usize, arg1: usize, arg2: usize) -> usize {
+fn sbi_call(which: usize, arg0: usize, arg1: usize, arg2: usize, arg3: usize) -> usize {
     let mut ret;
     unsafe {
         llvm_asm!("ecall"
             : "={x10}" (ret)
-            : "{x10}" (arg0), "{x11}" (arg1), "{x12}" (arg2), "{x17}" (which)
+            : "{x10}" (arg0), "{x11}" (arg1), "{x12}" (arg2), "{x13}" (arg3), "{x17}" (which)
             : "memory"
             : "volatile"
         );
@@ -24,23 +100,218 @@ fn sbi_call(which: usize, arg0: usize, arg1: usize, arg2: usize) -> usize {
     ret
 }
 
+/// SBI v0.2+ calling convention: error in a0, value in a1.
+#[inline(always)]
+fn sbi_call_ext(
+    eid: usize,
+    fid: usize,
+    arg0: usize,
+    arg1: usize,
+    arg2: usize,
+    arg3: usize,
+    arg4: usize,
+) -> SbiResult<usize> {
+    let error: isize;
+    let value: usize;
+    unsafe {
+        asm!("ecall",
+            inlateout("x10") arg0 => error,
+            inlateout("x11") arg1 => value,
+            in("x12") arg2,
+            in("x13") arg3,
+            in("x14") arg4,
+            in("x16") fid,
+            in("x17") eid,
+        );
+    }
+    if error == 0 {
+        Ok(value)
+    } else {
+        Err(error.into())
+    }
+}
+
+fn legacy_result(ret: usize) -> SbiResult<()> {
+    match ret as isize {
+        0 => Ok(()),
+        e => Err(e.into()),
+    }
+}
+
+/// Probe the extensions we use. Must run on the boot hart before any other call.
+pub fn init() {
+    let mut extensions = 0;
+    for (eid, ext) in [
+        (EID_TIME, Extension::Time),
+        (EID_IPI, Extension::Ipi),
+        (EID_RFENCE, Extension::Rfence),
+        (EID_HSM, Extension::Hsm),
+        (EID_SRST, Extension::Srst),
+    ]
+    .iter()
+    {
+        if probe_extension(*eid) {
+            extensions |= 1 << *ext as usize;
+        }
+    }
+    EXTENSIONS.store(extensions, Ordering::Relaxed);
+}
+
+pub fn spec_version() -> SbiResult<(usize, usize)> {
+    sbi_call_ext(EID_BASE, 0, 0, 0, 0, 0, 0).map(|v| ((v >> 24) & 0x7f, v & 0xff_ffff))
+}
+
+pub fn impl_id() -> SbiResult<usize> {
+    sbi_call_ext(EID_BASE, 1, 0, 0, 0, 0, 0)
+}
+
+pub fn impl_version() -> SbiResult<usize> {
+    sbi_call_ext(EID_BASE, 2, 0, 0, 0, 0, 0)
+}
+
+/// Legacy-only firmware rejects the Base extension, so this reads as `false`.
+pub fn probe_extension(eid: usize) -> bool {
+    matches!(sbi_call_ext(EID_BASE, 3, eid, 0, 0, 0, 0), Ok(v) if v != 0)
+}
+
 pub fn set_timer(timer: usize) {
-    sbi_call(SBI_SET_TIMER, timer, 0, 0);
+    if has(Extension::Time) {
+        sbi_call_ext(EID_TIME, 0, timer, 0, 0, 0, 0).unwrap();
+    } else {
+        sbi_call(SBI_SET_TIMER, timer, 0, 0, 0);
+    }
 }
 
 pub fn console_putchar(c: usize) {
-    sbi_call(SBI_CONSOLE_PUTCHAR, c, 0, 0);
+    sbi_call(SBI_CONSOLE_PUTCHAR, c, 0, 0, 0);
 }
 
 pub fn console_getchar() -> usize {
-    sbi_call(SBI_CONSOLE_GETCHAR, 0, 0, 0)
+    sbi_call(SBI_CONSOLE_GETCHAR, 0, 0, 0, 0)
 }
 
-pub fn shutdown() -> ! {
-    sbi_call(SBI_SHUTDOWN, 0, 0, 0);
-    panic!("It should shutdown!");
+pub fn send_ipi(hart_mask: usize) -> SbiResult<()> {
+    if has(Extension::Ipi) {
+        sbi_call_ext(EID_IPI, 0, hart_mask, 0, 0, 0, 0).map(|_| ())
+    } else {
+        legacy_result(sbi_call(
+            SBI_SEND_IPI,
+            &hart_mask as *const _ as usize,
+            0,
+            0,
+            0,
+        ))
+    }
+}
+
+pub fn remote_fence_i(hart_mask: usize) -> SbiResult<()> {
+    if has(Extension::Rfence) {
+        sbi_call_ext(EID_RFENCE, 0, hart_mask, 0, 0, 0, 0).map(|_| ())
+    } else {
+        legacy_result(sbi_call(
+            SBI_REMOTE_FENCE_I,
+            &hart_mask as *const _ as usize,
+            0,
+            0,
+            0,
+        ))
+    }
+}
+
+/// Fence the whole range `[start, start + size)` on the harts in `hart_mask`.
+pub fn remote_sfence_vma(hart_mask: usize, start: usize, size: usize) -> SbiResult<()> {
+    if has(Extension::Rfence) {
+        sbi_call_ext(EID_RFENCE, 1, hart_mask, 0, start, size, 0).map(|_| ())
+    } else {
+        legacy_result(sbi_call(
+            SBI_REMOTE_SFENCE_VMA,
+            &hart_mask as *const _ as usize,
+            start,
+            size,
+            0,
+        ))
+    }
+}
+
+/// Like `remote_sfence_vma`, but only for entries tagged with `asid`.
+pub fn remote_sfence_vma_asid(
+    hart_mask: usize,
+    start: usize,
+    size: usize,
+    asid: usize,
+) -> SbiResult<()> {
+    if has(Extension::Rfence) {
+        sbi_call_ext(EID_RFENCE, 2, hart_mask, 0, start, size, asid).map(|_| ())
+    } else {
+        legacy_result(sbi_call(
+            SBI_REMOTE_SFENCE_VMA_ASID,
+            &hart_mask as *const _ as usize,
+            start,
+            size,
+            asid,
+        ))
+    }
 }
 
-pub fn send_ipi(ptr: usize) {
-    sbi_call(SBI_SEND_IPI, ptr, 0, 0);
+/// Start `hartid` at the physical address `start_addr` with `a0 = hartid, a1 = opaque`.
+/// Without HSM the hart is assumed to be parked in the firmware and kicked by an IPI.
+pub fn hart_start(hartid: usize, start_addr: usize, opaque: usize) -> SbiResult<()> {
+    if has(Extension::Hsm) {
+        sbi_call_ext(EID_HSM, 0, hartid, start_addr, opaque, 0, 0).map(|_| ())
+    } else {
+        send_ipi(1 << hartid)
+    }
+}
+
+/// Only returns on failure.
+pub fn hart_stop() -> SbiError {
+    match sbi_call_ext(EID_HSM, 1, 0, 0, 0, 0, 0) {
+        Ok(_) => unreachable!(),
+        Err(e) => e,
+    }
+}
+
+pub fn hart_get_status(hartid: usize) -> SbiResult<HartStatus> {
+    sbi_call_ext(EID_HSM, 2, hartid, 0, 0, 0, 0).and_then(|status| match status {
+        0 => Ok(HartStatus::Started),
+        1 => Ok(HartStatus::Stopped),
+        2 => Ok(HartStatus::StartPending),
+        3 => Ok(HartStatus::StopPending),
+        4 => Ok(HartStatus::Suspended),
+        5 => Ok(HartStatus::SuspendPending),
+        6 => Ok(HartStatus::ResumePending),
+        s => Err(SbiError::Unknown(s as isize)),
+    })
+}
+
+/// A retentive suspend returns like `wfi` once an enabled interrupt is pending.
+pub fn hart_suspend(suspend_type: u32, resume_addr: usize, opaque: usize) -> SbiResult<()> {
+    if !has(Extension::Hsm) {
+        return Err(SbiError::NotSupported);
+    }
+    sbi_call_ext(EID_HSM, 3, suspend_type as usize, resume_addr, opaque, 0, 0).map(|_| ())
+}
+
+/// Only returns on failure.
+pub fn system_reset(reset_type: u32, reset_reason: u32) -> SbiError {
+    match sbi_call_ext(
+        EID_SRST,
+        0,
+        reset_type as usize,
+        reset_reason as usize,
+        0,
+        0,
+        0,
+    ) {
+        Ok(_) => unreachable!(),
+        Err(e) => e,
+    }
+}
+
+pub fn shutdown() -> ! {
+    if has(Extension::Srst) {
+        system_reset(RESET_TYPE_SHUTDOWN, RESET_REASON_NO_REASON);
+    }
+    sbi_call(SBI_SHUTDOWN, 0, 0, 0, 0);
+    panic!("It should shutdown!");
 }
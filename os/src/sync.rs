@@ -0,0 +1,143 @@
+//! Locks shared with trap handlers, which run with `sstatus.SIE` set in the kernel.
+//!
+//! A spin lock taken by an interrupt handler must be held with interrupts disabled,
+//! otherwise the handler spins forever on a lock its own hart already holds.
+
+use crate::config::CPU_NUM;
+use crate::task::hart_id;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use lazy_static::*;
+use riscv::register::sstatus;
+use spin::{Mutex, MutexGuard};
+
+lazy_static! {
+    /// Nesting depth of `IntrGuard`s on each hart.
+    static ref INTR_DEPTH: [AtomicUsize; CPU_NUM] = Default::default();
+    /// Whether interrupts were enabled before the outermost `IntrGuard`.
+    static ref INTR_ENABLED: [AtomicBool; CPU_NUM] = Default::default();
+}
+
+/// Disables interrupts on this hart until dropped, nests like xv6 `push_off`/`pop_off`.
+pub struct IntrGuard(());
+
+impl IntrGuard {
+    pub fn new() -> Self {
+        let enabled = sstatus::read().sie();
+        unsafe {
+            sstatus::clear_sie();
+        }
+        let hart = hart_id();
+        if INTR_DEPTH[hart].fetch_add(1, Ordering::Relaxed) == 0 {
+            INTR_ENABLED[hart].store(enabled, Ordering::Relaxed);
+        }
+        IntrGuard(())
+    }
+}
+
+impl Drop for IntrGuard {
+    fn drop(&mut self) {
+        assert!(
+            !sstatus::read().sie(),
+            "interrupts enabled inside IntrGuard"
+        );
+        let hart = hart_id();
+        let depth = INTR_DEPTH[hart].fetch_sub(1, Ordering::Relaxed);
+        assert!(depth > 0, "unbalanced IntrGuard");
+        if depth == 1 && INTR_ENABLED[hart].load(Ordering::Relaxed) {
+            unsafe {
+                sstatus::set_sie();
+            }
+        }
+    }
+}
+
+/// Interrupt state of the hart, carried across `__switch` by the switching context.
+pub struct IntrState {
+    depth: usize,
+    enabled: bool,
+    sie: bool,
+}
+
+/// Detach the interrupt state from this hart before switching away, interrupts stay off.
+pub fn intr_save() -> IntrState {
+    let sie = sstatus::read().sie();
+    unsafe {
+        sstatus::clear_sie();
+    }
+    let hart = hart_id();
+    IntrState {
+        depth: INTR_DEPTH[hart].swap(0, Ordering::Relaxed),
+        enabled: INTR_ENABLED[hart].load(Ordering::Relaxed),
+        sie,
+    }
+}
+
+/// Restore a state from `intr_save` on whichever hart we resumed on.
+pub fn intr_restore(state: IntrState) {
+    let hart = hart_id();
+    INTR_DEPTH[hart].store(state.depth, Ordering::Relaxed);
+    INTR_ENABLED[hart].store(state.enabled, Ordering::Relaxed);
+    if state.sie {
+        unsafe {
+            sstatus::set_sie();
+        }
+    }
+}
+
+/// A spin lock that keeps interrupts disabled while held.
+pub struct IrqMutex<T: ?Sized>(Mutex<T>);
+
+impl<T: Default> Default for IrqMutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for IrqMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> Self {
+        IrqMutex(Mutex::new(value))
+    }
+}
+
+impl<T: ?Sized> IrqMutex<T> {
+    pub fn lock(&self) -> IrqMutexGuard<T> {
+        let intr = IntrGuard::new();
+        IrqMutexGuard {
+            guard: self.0.lock(),
+            _intr: intr,
+        }
+    }
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<T>> {
+        let intr = IntrGuard::new();
+        self.0
+            .try_lock()
+            .map(|guard| IrqMutexGuard { guard, _intr: intr })
+    }
+}
+
+/// Fields drop in order, so the lock is released before interrupts come back.
+pub struct IrqMutexGuard<'a, T: ?Sized> {
+    guard: MutexGuard<'a, T>,
+    _intr: IntrGuard,
+}
+
+impl<T: ?Sized> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
@@ -1,12 +1,14 @@
 use alloc::boxed::Box;
+use alloc::sync::Arc;
 use core::cmp::min;
-use spin::Mutex;
 
 use crate::{
-    mm::{translated_byte_buffer, translated_refmut, UserBuffer},
+    errno::{EBADF, EINVAL, EROFS},
+    mm::{translated_byte_buffer, translated_refmut, translated_str, UserBuffer},
     task::find_task,
 };
-use crate::fs::{File, make_pipe};
+use crate::fs::{File, make_pipe, packfs, serial, O_NONBLOCK};
+use crate::sync::IrqMutex;
 use crate::task::{current_task, current_user_token};
 
 pub fn sys_write(fd: usize, buf: *const u8, len: usize, user_task_id: usize) -> isize {
@@ -24,7 +26,7 @@ pub fn sys_write(fd: usize, buf: *const u8, len: usize, user_task_id: usize) ->
             if let Ok(buffers) = translated_byte_buffer(token, buf, len) {
                 match file.write(UserBuffer::new(buffers)) {
                     Ok(write_len) => write_len as isize,
-                    Err(_) => -1,
+                    Err(errno) => errno,
                 }
             } else {
                 -1
@@ -35,7 +37,7 @@ pub fn sys_write(fd: usize, buf: *const u8, len: usize, user_task_id: usize) ->
             let future = AsyncWrite::new(file, buf, len, token);
             let future = Box::pin(future);
             let mut queue = KERNEL_TASK_QUEUE.lock();
-            queue.add_task(KernelTask::new(REACTOR.clone(), task.getpid(), user_task_id, Mutex::new(future)));
+            queue.add_task(KernelTask::new(REACTOR.clone(), task.getpid(), user_task_id, IrqMutex::new(future)));
 
             0
         }
@@ -59,7 +61,7 @@ pub fn sys_read(fd: usize, buf: *const u8, len: usize, user_task_id: usize) -> i
             if let Ok(buffers) = translated_byte_buffer(token, buf, len) {
                 match file.read(UserBuffer::new(buffers)) {
                     Ok(read_len) => read_len as isize,
-                    Err(_) => -1,
+                    Err(errno) => errno,
                 }
             } else {
                 -1
@@ -70,7 +72,7 @@ pub fn sys_read(fd: usize, buf: *const u8, len: usize, user_task_id: usize) -> i
             let future = AsyncRead::new(file, buf, len, token);
             let future = Box::pin(future);
             let mut queue = KERNEL_TASK_QUEUE.lock();
-            queue.add_task(KernelTask::new(REACTOR.clone(), task.getpid(), user_task_id, Mutex::new(future)));
+            queue.add_task(KernelTask::new(REACTOR.clone(), task.getpid(), user_task_id, IrqMutex::new(future)));
             0
         }
     } else {
@@ -78,6 +80,32 @@ pub fn sys_read(fd: usize, buf: *const u8, len: usize, user_task_id: usize) -> i
     }
 }
 
+/// Serial ports are under `/dev`. Files in the image can only be read, and
+/// never block, so `O_NONBLOCK` changes nothing for them.
+pub fn sys_open(path: *const u8, flags: u32) -> isize {
+    let token = current_user_token();
+    let path = translated_str(token, path);
+    let file: Arc<dyn File + Send + Sync> = if let Some(name) = path.strip_prefix("/dev/") {
+        match serial::open(name, flags) {
+            Ok(file) => Arc::new(file),
+            Err(errno) => return errno,
+        }
+    } else {
+        if flags & !O_NONBLOCK != 0 {
+            return EROFS;
+        }
+        match packfs::open(path.as_str()) {
+            Ok(file) => Arc::new(file),
+            Err(errno) => return errno,
+        }
+    };
+    let task = current_task().unwrap();
+    let mut inner = task.acquire_inner_lock();
+    let fd = inner.alloc_fd();
+    inner.fd_table[fd] = Some(file);
+    fd as isize
+}
+
 pub fn sys_close(fd: usize, user_task_id: usize) -> isize {
     let task = current_task().unwrap();
     if user_task_id == 0 {
@@ -103,11 +131,55 @@ pub fn sys_close(fd: usize, user_task_id: usize) -> isize {
         use crate::async_rt::{KERNEL_TASK_QUEUE, REACTOR, KernelTask};
 
         let mut queue = KERNEL_TASK_QUEUE.lock();
-        queue.add_task(KernelTask::new(REACTOR.clone(), pid, user_task_id, Mutex::new(future)));
+        queue.add_task(KernelTask::new(REACTOR.clone(), pid, user_task_id, IrqMutex::new(future)));
         0
     }
 }
 
+pub fn sys_dup(fd: usize) -> isize {
+    let task = current_task().unwrap();
+    let mut inner = task.acquire_inner_lock();
+    let file = match inner.fd_table.get(fd) {
+        Some(Some(file)) => file.clone(),
+        _ => return EBADF,
+    };
+    let new_fd = inner.alloc_fd();
+    inner.fd_table[new_fd] = Some(file);
+    new_fd as isize
+}
+
+pub fn sys_ioctl(fd: usize, request: usize, arg: usize) -> isize {
+    let task = current_task().unwrap();
+    let inner = task.acquire_inner_lock();
+    let file = match inner.fd_table.get(fd) {
+        Some(Some(file)) => file.clone(),
+        _ => return EBADF,
+    };
+    drop(inner);
+    file.ioctl(request, arg).unwrap_or_else(|errno| errno)
+}
+
+pub const F_GETFL: usize = 3;
+pub const F_SETFL: usize = 4;
+
+pub fn sys_fcntl(fd: usize, cmd: usize, arg: usize) -> isize {
+    let task = current_task().unwrap();
+    let inner = task.acquire_inner_lock();
+    let file = match inner.fd_table.get(fd) {
+        Some(Some(file)) => file.clone(),
+        _ => return EBADF,
+    };
+    drop(inner);
+    match cmd {
+        F_GETFL => file.status_flags() as isize,
+        F_SETFL => match file.set_status_flags(arg as u32) {
+            Ok(()) => 0,
+            Err(errno) => errno,
+        },
+        _ => EINVAL,
+    }
+}
+
 pub fn sys_pipe(pipe: *mut usize, user_task_id: usize) -> isize {
     debug!("sys pipe, user_id: {}", user_task_id);
     let task = current_task().unwrap();
@@ -129,7 +201,7 @@ pub fn sys_pipe(pipe: *mut usize, user_task_id: usize) -> isize {
 
         let future = AsyncPipeOpen::new(task.clone(), token, pipe);
         let future = Box::pin(future);
-        KERNEL_TASK_QUEUE.lock().add_task(KernelTask::new(REACTOR.clone(), task.getpid(), user_task_id, Mutex::new(future)));
+        KERNEL_TASK_QUEUE.lock().add_task(KernelTask::new(REACTOR.clone(), task.getpid(), user_task_id, IrqMutex::new(future)));
 
         0
     }
@@ -1,12 +1,31 @@
+const SYSCALL_DUP: usize = 24;
+const SYSCALL_FCNTL: usize = 25;
+const SYSCALL_IOCTL: usize = 29;
+const SYSCALL_OPEN: usize = 56;
 const SYSCALL_CLOSE: usize = 57;
 const SYSCALL_PIPE: usize = 59;
 const SYSCALL_READ: usize = 63;
 const SYSCALL_WRITE: usize = 64;
 const SYSCALL_EXIT: usize = 93;
+const SYSCALL_TIMER_CREATE: usize = 107;
+const SYSCALL_TIMER_SETTIME: usize = 110;
+const SYSCALL_TIMER_DELETE: usize = 111;
+const SYSCALL_CLOCK_SETTIME: usize = 112;
+const SYSCALL_CLOCK_GETTIME: usize = 113;
 const SYSCALL_YIELD: usize = 124;
+const SYSCALL_KILL: usize = 129;
 const SYSCALL_SET_PRIORITY: usize = 140;
+const SYSCALL_SETPGID: usize = 154;
+const SYSCALL_GETPGID: usize = 155;
+const SYSCALL_GETSID: usize = 156;
+const SYSCALL_SETSID: usize = 157;
 const SYSCALL_GET_TIME: usize = 169;
 const SYSCALL_GETPID: usize = 172;
+const SYSCALL_SOCKET: usize = 198;
+const SYSCALL_BIND: usize = 200;
+const SYSCALL_LISTEN: usize = 201;
+const SYSCALL_ACCEPT: usize = 202;
+const SYSCALL_CONNECT: usize = 203;
 const SYSCALL_MUNMAP: usize = 215;
 const SYSCALL_FORK: usize = 220;
 const SYSCALL_EXEC: usize = 221;
@@ -20,38 +39,61 @@ const SYSCALL_SEND_MSG: usize = 601;
 const SYSCALL_SET_TIMER: usize = 602;
 const SYSCALL_CLAIM_EXT_INT: usize = 603;
 const SYSCALL_SET_EXT_INT_ENABLE: usize = 604;
+const SYSCALL_GET_TIMER: usize = 605;
 
 mod fs;
+mod net;
 mod process;
 
 use fs::*;
+use net::*;
 pub use process::*;
 
 pub fn syscall(syscall_id: usize, args: [usize; 4]) -> isize {
     trace!("syscall {}, args {:x?}", syscall_id, args);
     match syscall_id {
+        SYSCALL_DUP => sys_dup(args[0]),
+        SYSCALL_FCNTL => sys_fcntl(args[0], args[1], args[2]),
+        SYSCALL_IOCTL => sys_ioctl(args[0], args[1], args[2]),
+        SYSCALL_OPEN => sys_open(args[0] as *const u8, args[1] as u32),
         SYSCALL_CLOSE => sys_close(args[0], args[1]),
         SYSCALL_PIPE => sys_pipe(args[0] as *mut usize, args[1]),
         SYSCALL_READ => sys_read(args[0], args[1] as *const u8, args[2], args[3]),
         SYSCALL_WRITE => sys_write(args[0], args[1] as *const u8, args[2], args[3]),
         SYSCALL_EXIT => sys_exit(args[0] as i32),
+        SYSCALL_TIMER_CREATE => sys_timer_create(),
+        SYSCALL_TIMER_SETTIME => sys_timer_settime(args[0], args[1], args[2], args[3]),
+        SYSCALL_TIMER_DELETE => sys_timer_delete(args[0]),
+        SYSCALL_CLOCK_SETTIME => sys_clock_settime(args[0], args[1]),
+        SYSCALL_CLOCK_GETTIME => sys_clock_gettime(args[0], args[1]),
         SYSCALL_YIELD => sys_yield(),
+        SYSCALL_KILL => sys_kill(args[0] as isize, args[1]),
+        SYSCALL_SETPGID => sys_setpgid(args[0], args[1]),
+        SYSCALL_GETPGID => sys_getpgid(args[0]),
+        SYSCALL_GETSID => sys_getsid(args[0]),
+        SYSCALL_SETSID => sys_setsid(),
         SYSCALL_GET_TIME => sys_get_time(args[0], args[1]),
         SYSCALL_SET_PRIORITY => sys_set_priority(args[0] as isize),
         SYSCALL_MMAP => sys_mmap(args[0], args[1], args[2]),
         SYSCALL_MUNMAP => sys_munmap(args[0], args[1]),
         SYSCALL_GETPID => sys_getpid(),
+        SYSCALL_SOCKET => sys_socket(args[0]),
+        SYSCALL_BIND => sys_bind(args[0], args[1]),
+        SYSCALL_LISTEN => sys_listen(args[0]),
+        SYSCALL_ACCEPT => sys_accept(args[0]),
+        SYSCALL_CONNECT => sys_connect(args[0], args[1], args[2]),
         SYSCALL_FORK => sys_fork(),
-        SYSCALL_EXEC => sys_exec(args[0] as *const u8),
-        SYSCALL_WAITPID => sys_waitpid(args[0] as isize, args[1] as *mut i32),
+        SYSCALL_EXEC => sys_exec(args[0] as *const u8, args[1] as *const usize),
+        SYSCALL_WAITPID => sys_waitpid(args[0] as isize, args[1] as *mut i32, args[2]),
         SYSCALL_SPAWN => sys_spawn(args[0] as *const u8),
         SYSCALL_MAILREAD => sys_mailread(args[0] as *mut u8, args[1]),
         SYSCALL_MAILWRITE => sys_mailwrite(args[0], args[1] as *mut u8, args[2]),
         SYSCALL_INIT_USER_TRAP => sys_init_user_trap(),
         SYSCALL_SEND_MSG => sys_send_msg(args[0], args[1]),
-        SYSCALL_SET_TIMER => sys_set_timer(args[0]),
+        SYSCALL_SET_TIMER => sys_set_timer(args[0], args[1]),
         SYSCALL_CLAIM_EXT_INT => sys_claim_ext_int(args[0]),
         SYSCALL_SET_EXT_INT_ENABLE => sys_set_ext_int_enable(args[0], args[1]),
+        SYSCALL_GET_TIMER => sys_get_timer(args[0]),
         _ => panic!("Unsupported syscall_id: {}", syscall_id),
     }
 }
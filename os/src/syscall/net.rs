@@ -0,0 +1,74 @@
+use crate::errno::{EBADF, EINVAL, ENOTSOCK};
+use crate::fs::File;
+use crate::net::{NetSocket, SocketKind};
+use crate::task::current_task;
+use alloc::sync::Arc;
+
+const SOCK_STREAM: usize = 1;
+const SOCK_DGRAM: usize = 2;
+
+fn get_file(fd: usize) -> Result<Arc<dyn File + Send + Sync>, isize> {
+    let task = current_task().unwrap();
+    let inner = task.acquire_inner_lock();
+    inner
+        .fd_table
+        .get(fd)
+        .and_then(|file| file.clone())
+        .ok_or(EBADF)
+}
+
+/// Run `f` on the socket at `fd`, without holding the task lock.
+fn with_socket(fd: usize, f: impl FnOnce(&NetSocket) -> Result<isize, isize>) -> isize {
+    let file = match get_file(fd) {
+        Ok(file) => file,
+        Err(errno) => return errno,
+    };
+    match file.as_socket() {
+        Some(socket) => f(socket).unwrap_or_else(|errno| errno),
+        None => ENOTSOCK,
+    }
+}
+
+fn install(socket: NetSocket) -> isize {
+    let task = current_task().unwrap();
+    let mut inner = task.acquire_inner_lock();
+    let fd = inner.alloc_fd();
+    inner.fd_table[fd] = Some(Arc::new(socket));
+    fd as isize
+}
+
+pub fn sys_socket(kind: usize) -> isize {
+    let kind = match kind {
+        SOCK_STREAM => SocketKind::Tcp,
+        SOCK_DGRAM => SocketKind::Udp,
+        _ => return EINVAL,
+    };
+    match NetSocket::new(kind) {
+        Ok(socket) => install(socket),
+        Err(errno) => errno,
+    }
+}
+
+pub fn sys_bind(fd: usize, port: usize) -> isize {
+    if port > u16::MAX as usize {
+        return EINVAL;
+    }
+    with_socket(fd, |socket| socket.bind(port as u16).map(|_| 0))
+}
+
+pub fn sys_listen(fd: usize) -> isize {
+    with_socket(fd, |socket| socket.listen().map(|_| 0))
+}
+
+pub fn sys_accept(fd: usize) -> isize {
+    with_socket(fd, |socket| socket.accept().map(install))
+}
+
+/// `addr` is the IPv4 address as a number, 10.0.2.2 is `0x0a000202`.
+pub fn sys_connect(fd: usize, addr: usize, port: usize) -> isize {
+    if addr > u32::MAX as usize || port > u16::MAX as usize {
+        return EINVAL;
+    }
+    let addr = (addr as u32).to_be_bytes();
+    with_socket(fd, |socket| socket.connect(addr, port as u16).map(|_| 0))
+}
@@ -1,19 +1,31 @@
 use core::mem::size_of;
 
-use crate::config::CPU_NUM;
-use crate::loader::get_app_data_by_name;
+use crate::device;
+use crate::errno::{EFAULT, EINVAL, ENOMEM, EPERM, ESRCH};
+use crate::fdt::board;
+use crate::loader::load_app;
 use crate::mm;
 use crate::plic::{get_context, Plic};
 use crate::task::{
-    add_task, current_task, current_user_token, exit_current_and_run_next, hart_id, mmap, munmap,
-    set_current_priority, suspend_current_and_run_next, WAIT_LOCK,
+    add_task, current_task, current_user_token, exit_current_and_run_next, find_task, hart_id,
+    mmap, munmap, oom_kill, process_group_exists, send_signal, set_current_priority, signal_group,
+    suspend_current_and_run_next, TaskControlBlock, NSIG, WAIT_LOCK,
 };
 use crate::trap::{push_trap_record, UserTrapRecord};
 
-use crate::timer::get_time;
+use crate::timer::{self, get_time, get_time_ticks, TimeSpec};
+use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 
+/// Apply the OOM policy when a syscall ran out of memory.
+fn check_oom(errno: isize) -> isize {
+    if errno == ENOMEM {
+        oom_kill();
+    }
+    errno
+}
+
 pub fn sys_exit(exit_code: i32) -> ! {
     exit_current_and_run_next(exit_code);
     panic!("Unreachable in sys_exit!");
@@ -47,7 +59,7 @@ pub fn sys_get_time(time: usize, tz: usize) -> isize {
 }
 
 pub fn sys_mmap(start: usize, len: usize, port: usize) -> isize {
-    mmap(start, len, port).unwrap_or(-1)
+    mmap(start, len, port).unwrap_or_else(check_oom)
 }
 
 pub fn sys_munmap(start: usize, len: usize) -> isize {
@@ -58,10 +70,111 @@ pub fn sys_getpid() -> isize {
     current_task().unwrap().pid.0 as isize
 }
 
+/// `pid` 0 is the caller, `pgid` 0 makes `pid` the leader of a new group.
+/// Only the caller and its children can move, into their own group or one
+/// that already exists in their session.
+pub fn sys_setpgid(pid: usize, pgid: usize) -> isize {
+    let task = current_task().unwrap();
+    let sid = task.acquire_inner_lock().sid;
+    let target = if pid == 0 || pid == task.getpid() {
+        task
+    } else {
+        let child = task
+            .acquire_inner_lock()
+            .children
+            .iter()
+            .find(|child| child.getpid() == pid)
+            .cloned();
+        match child {
+            Some(child) => child,
+            None => return ESRCH,
+        }
+    };
+    let target_sid = target.acquire_inner_lock().sid;
+    // session leaders stay where they are
+    if target_sid != sid || target_sid == target.getpid() {
+        return EPERM;
+    }
+    let pgid = if pgid == 0 { target.getpid() } else { pgid };
+    if pgid != target.getpid() && !process_group_exists(pgid, sid) {
+        return EPERM;
+    }
+    target.acquire_inner_lock().pgid = pgid;
+    0
+}
+
+pub fn sys_getpgid(pid: usize) -> isize {
+    let task = if pid == 0 {
+        current_task()
+    } else {
+        find_task(pid)
+    };
+    match task {
+        Some(task) => task.acquire_inner_lock().pgid as isize,
+        None => ESRCH,
+    }
+}
+
+/// Starts a new session and group led by the caller, which must not lead a
+/// group already. It has no controlling terminal until it takes one.
+pub fn sys_setsid() -> isize {
+    let task = current_task().unwrap();
+    let pid = task.getpid();
+    let mut inner = task.acquire_inner_lock();
+    if inner.pgid == pid {
+        return EPERM;
+    }
+    inner.pgid = pid;
+    inner.sid = pid;
+    pid as isize
+}
+
+pub fn sys_getsid(pid: usize) -> isize {
+    let task = if pid == 0 {
+        current_task()
+    } else {
+        find_task(pid)
+    };
+    match task {
+        Some(task) => task.acquire_inner_lock().sid as isize,
+        None => ESRCH,
+    }
+}
+
+/// `pid` above 0 is one task, 0 the caller's process group and below -1
+/// the group `-pid`. Signal 0 only checks that the target exists.
+pub fn sys_kill(pid: isize, signo: usize) -> isize {
+    if signo >= NSIG {
+        return EINVAL;
+    }
+    let result = match pid {
+        pid if pid > 0 => find_task(pid as usize)
+            .map(|task| send_signal(&task, signo))
+            .ok_or(ESRCH),
+        0 => {
+            let pgid = current_task().unwrap().acquire_inner_lock().pgid;
+            signal_group(pgid, signo)
+        }
+        // every process at once is not supported
+        -1 => Err(EINVAL),
+        pid => signal_group((-pid) as usize, signo),
+    };
+    match result {
+        Ok(()) => 0,
+        Err(errno) => errno,
+    }
+}
+
 pub fn sys_fork() -> isize {
     debug!("Fork start");
     let current_task = current_task().unwrap();
-    let new_task = current_task.fork();
+    let new_task = match current_task.fork() {
+        Ok(new_task) => new_task,
+        Err(errno) => {
+            warn!("fork failed!");
+            return check_oom(errno);
+        }
+    };
     let new_pid = new_task.pid.0;
     // modify trap context of new_task, because it returns immediately after switching
     let trap_cx = new_task.acquire_inner_lock().get_trap_cx();
@@ -74,23 +187,48 @@ pub fn sys_fork() -> isize {
     new_pid as isize
 }
 
-pub fn sys_exec(path: *const u8) -> isize {
+/// `args` points to the argument strings, terminated by a null pointer.
+pub fn sys_exec(path: *const u8, mut args: *const usize) -> isize {
     let token = current_user_token();
     let path = mm::translated_str(token, path);
-    debug!("EXEC {}", &path);
-    if let Some(data) = get_app_data_by_name(path.as_str()) {
+    let mut args_vec: Vec<String> = Vec::new();
+    if !args.is_null() {
+        loop {
+            let arg_str_ptr = *mm::translated_refmut(token, args as *mut usize);
+            if arg_str_ptr == 0 {
+                break;
+            }
+            args_vec.push(mm::translated_str(token, arg_str_ptr as *const u8));
+            unsafe {
+                args = args.add(1);
+            }
+        }
+    }
+    debug!("EXEC {} {:?}", &path, args_vec);
+    if let Some(data) = load_app(path.as_str()) {
         let task = current_task().unwrap();
-        task.exec(data);
-        0
+        match task.exec(path.as_str(), &data, args_vec) {
+            Ok(_) => 0,
+            Err(errno) => {
+                warn!("exec failed!");
+                check_oom(errno)
+            }
+        }
     } else {
         warn!("exec failed!");
         -1
     }
 }
 
-/// If there is not a child process whose pid is same as given, return -1.
+pub const WUNTRACED: usize = 2;
+
+/// `pid` -1 waits for any child, 0 for one in the caller's process group and
+/// below -1 for one in group `-pid`.
+/// With `WUNTRACED` in `options` a child that stopped is reported once too,
+/// its status is `0x7f | signo << 8` as on Linux.
+/// If there is not a child process matching `pid`, return -1.
 /// Else if there is a child process but it is still running, return -2.
-pub fn sys_waitpid(pid: isize, exit_code_ptr: *mut i32) -> isize {
+pub fn sys_waitpid(pid: isize, exit_code_ptr: *mut i32, options: usize) -> isize {
     trace!("sys_waitpid {}", pid);
     let task = current_task().unwrap();
     // find a child process
@@ -98,18 +236,20 @@ pub fn sys_waitpid(pid: isize, exit_code_ptr: *mut i32) -> isize {
     let _ = WAIT_LOCK.lock();
     // ---- hold current PCB lock
     let mut inner = task.acquire_inner_lock();
-    if inner
-        .children
-        .iter()
-        .find(|p| pid == -1 || pid as usize == p.getpid())
-        .is_none()
-    {
+    let own_pgid = inner.pgid;
+    let wanted = |child: &Arc<TaskControlBlock>| match pid {
+        -1 => true,
+        0 => child.acquire_inner_lock().pgid == own_pgid,
+        pid if pid < -1 => child.acquire_inner_lock().pgid == (-pid) as usize,
+        pid => child.getpid() == pid as usize,
+    };
+    if !inner.children.iter().any(wanted) {
         return -1;
         // ---- release current PCB lock
     }
     let pair = inner.children.iter().enumerate().find(|(_, p)| {
         // ++++ temporarily hold child PCB lock
-        p.acquire_inner_lock().is_zombie() && (pid == -1 || pid as usize == p.getpid())
+        wanted(p) && p.acquire_inner_lock().is_zombie()
         // ++++ release child PCB lock
     });
     if let Some((idx, _)) = pair {
@@ -121,10 +261,22 @@ pub fn sys_waitpid(pid: isize, exit_code_ptr: *mut i32) -> isize {
         let exit_code = child.acquire_inner_lock().exit_code;
         // ++++ release child PCB lock
         *mm::translated_refmut(inner.memory_set.token(), exit_code_ptr) = exit_code;
-        found_pid as isize
-    } else {
-        -2
+        return found_pid as isize;
     }
+    if options & WUNTRACED != 0 {
+        for child in inner.children.iter().filter(|child| wanted(child)) {
+            // ++++ temporarily hold child PCB lock
+            let mut child_inner = child.acquire_inner_lock();
+            if let (Some(signo), false) = (child_inner.stopped_by, child_inner.stop_reported) {
+                child_inner.stop_reported = true;
+                let status = 0x7f | ((signo as i32) << 8);
+                *mm::translated_refmut(inner.memory_set.token(), exit_code_ptr) = status;
+                return child.getpid() as isize;
+            }
+            // ++++ release child PCB lock
+        }
+    }
+    -2
     // ---- release current PCB lock automatically
 }
 
@@ -140,7 +292,7 @@ pub fn sys_spawn(file: *const u8) -> isize {
             debug!("new_task via spawn {:?}", new_pid);
             new_pid as isize
         }
-        Err(_) => -1,
+        Err(errno) => check_oom(errno),
     }
 }
 
@@ -175,15 +327,158 @@ pub fn sys_send_msg(pid: usize, msg: usize) -> isize {
     }
 }
 
-pub fn sys_set_timer(time_us: usize) -> isize {
-    let pid = current_task().unwrap().pid.0;
-    use crate::config::CLOCK_FREQ;
-    use crate::timer::{set_virtual_timer, USEC_PER_SEC};
-    let time = time_us * CLOCK_FREQ / USEC_PER_SEC;
-    set_virtual_timer(time, pid);
+/// One-shot at the absolute `time_us` when `interval_us` is 0. Otherwise the task's
+/// periodic timer first fires at `time_us`, then every `interval_us` without drift,
+/// until `sys_set_timer(0, 0)` stops it.
+pub fn sys_set_timer(time_us: usize, interval_us: usize) -> isize {
+    let task = current_task().unwrap();
+    let pid = task.pid.0;
+    let (time, interval) = match (timer::us_to_ticks(time_us), timer::us_to_ticks(interval_us)) {
+        (Some(time), Some(interval)) => (time, interval),
+        _ => return EINVAL,
+    };
+    if interval_us == 0 && time_us != 0 {
+        return match timer::set_virtual_timer(time, pid) {
+            Ok(()) => 0,
+            Err(errno) => errno,
+        };
+    }
+    let mut inner = task.acquire_inner_lock();
+    let id = match inner.user_timer {
+        Some(id) => id,
+        None => match timer::timer_create(pid) {
+            Ok(id) => {
+                inner.user_timer = Some(id);
+                id
+            }
+            Err(errno) => return errno,
+        },
+    };
+    drop(inner);
+    match timer::timer_settime(pid, id, time, interval) {
+        Ok(()) => 0,
+        Err(errno) => errno,
+    }
+}
+
+/// Writes the next expiry, interval (both in us, 0 if stopped) and overrun count
+/// of the periodic timer.
+pub fn sys_get_timer(spec: usize) -> isize {
+    let task = current_task().unwrap();
+    let id = match task.acquire_inner_lock().user_timer {
+        Some(id) => id,
+        None => return EINVAL,
+    };
+    let (deadline, interval, overrun) = match timer::timer_gettime(task.pid.0, id) {
+        Ok(spec) => spec,
+        Err(errno) => return errno,
+    };
+    let to_us = timer::ticks_to_us;
+    let token = current_user_token();
+    for (i, value) in [to_us(deadline), to_us(interval), overrun]
+        .iter()
+        .enumerate()
+    {
+        match mm::translate_writable_va(token, spec + i * size_of::<usize>()) {
+            Ok(pa) => unsafe { *(pa as *mut usize) = *value },
+            Err(_) => return -1,
+        }
+    }
+    0
+}
+
+const CLOCK_REALTIME: usize = 0;
+const CLOCK_MONOTONIC: usize = 1;
+const CLOCK_PROCESS_CPUTIME_ID: usize = 2;
+const CLOCK_THREAD_CPUTIME_ID: usize = 3;
+
+pub fn sys_clock_gettime(clock_id: usize, ts: usize) -> isize {
+    let time = match clock_id {
+        CLOCK_REALTIME => timer::realtime(),
+        CLOCK_MONOTONIC => timer::monotonic_time(),
+        // every task is single threaded
+        CLOCK_PROCESS_CPUTIME_ID | CLOCK_THREAD_CPUTIME_ID => {
+            let task = current_task().unwrap();
+            let inner = task.acquire_inner_lock();
+            TimeSpec::from_ticks(inner.cpu_time + get_time_ticks() - inner.scheduled_at)
+        }
+        _ => return EINVAL,
+    };
+    let token = current_user_token();
+    for (i, value) in [time.sec, time.nsec].iter().enumerate() {
+        match mm::translate_writable_va(token, ts + i * size_of::<usize>()) {
+            Ok(pa) => unsafe { *(pa as *mut usize) = *value },
+            Err(_) => return EFAULT,
+        }
+    }
     0
 }
 
+/// Only CLOCK_REALTIME can be set.
+pub fn sys_clock_settime(clock_id: usize, ts: usize) -> isize {
+    if clock_id != CLOCK_REALTIME {
+        return EINVAL;
+    }
+    let mut time = TimeSpec::default();
+    let token = current_user_token();
+    match mm::translated_byte_buffer(token, ts as *const u8, size_of::<TimeSpec>()) {
+        Ok(buffers) => {
+            let dst = unsafe {
+                core::slice::from_raw_parts_mut(
+                    &mut time as *mut TimeSpec as *mut u8,
+                    size_of::<TimeSpec>(),
+                )
+            };
+            let mut offset = 0;
+            for buffer in buffers {
+                dst[offset..offset + buffer.len()].copy_from_slice(buffer);
+                offset += buffer.len();
+            }
+        }
+        Err(_) => return EFAULT,
+    }
+    match timer::set_realtime(time) {
+        Ok(()) => 0,
+        Err(errno) => errno,
+    }
+}
+
+const TIMER_ABSTIME: usize = 1;
+
+pub fn sys_timer_create() -> isize {
+    match timer::timer_create(current_task().unwrap().pid.0) {
+        Ok(id) => id as isize,
+        Err(errno) => errno,
+    }
+}
+
+/// `value_us` is relative unless `TIMER_ABSTIME` is set, 0 disarms the timer.
+pub fn sys_timer_settime(id: usize, value_us: usize, interval_us: usize, flags: usize) -> isize {
+    let pid = current_task().unwrap().pid.0;
+    let deadline = match value_us {
+        0 => Some(0),
+        _ if flags & TIMER_ABSTIME != 0 => timer::us_to_ticks(value_us),
+        _ => timer::us_to_ticks(value_us).and_then(|ticks| ticks.checked_add(get_time_ticks())),
+    };
+    let (deadline, interval) = match (deadline, timer::us_to_ticks(interval_us)) {
+        (Some(deadline), Some(interval)) => (deadline, interval),
+        _ => return EINVAL,
+    };
+    match timer::timer_settime(pid, id, deadline, interval) {
+        Ok(()) => 0,
+        Err(errno) => errno,
+    }
+}
+
+pub fn sys_timer_delete(id: usize) -> isize {
+    match timer::timer_delete(current_task().unwrap().pid.0, id) {
+        Ok(()) => 0,
+        Err(errno) => errno,
+    }
+}
+
+/// Hand the device at irq `device_id` to the current process, returns the
+/// base of its first MMIO range.
 pub fn sys_claim_ext_int(device_id: usize) -> isize {
     let device_id = device_id as u16;
     let current_task = current_task().unwrap();
@@ -191,66 +486,60 @@ pub fn sys_claim_ext_int(device_id: usize) -> isize {
     if !inner.is_user_trap_enabled() {
         return -1;
     }
-    use crate::plic;
-    use crate::trap::USER_EXT_INT_MAP;
-    let user_trap_info = &mut inner.user_trap_info;
-    match user_trap_info {
-        Some(info) => {
-            let mut map = USER_EXT_INT_MAP.lock();
-            if !map.contains_key(&device_id) {
-                let pid = current_task.getpid();
-                debug!(
-                    "[syscall claim] mapping device {} to pid {}",
-                    device_id, pid
-                );
-                map.insert(device_id, pid);
-                info.devices.push((device_id, false));
-                for hart_id in 0..CPU_NUM {
-                    let claim_addr = Plic::context_address(plic::get_context(hart_id, 'U'));
-                    if inner
-                        .memory_set
-                        .mmio_map(claim_addr, claim_addr + crate::config::PAGE_SIZE, 0b11)
-                        .is_err()
-                    {
-                        warn!("[syscall claim] map plic claim reg failed!");
-                        return -6;
-                    }
-                }
-            }
-            use crate::uart;
-            match device_id {
-                #[cfg(feature = "board_qemu")]
-                13 | 14 | 15 => {
-                    let base_address = uart::get_base_addr_from_irq(device_id);
-                    match inner.memory_set.mmio_map(
-                        base_address,
-                        base_address + uart::SERIAL_ADDRESS_STRIDE,
-                        0x3,
-                    ) {
-                        Ok(_) => base_address as isize,
-                        Err(_) => -2,
-                    }
-                }
-                #[cfg(feature = "board_lrv")]
-                5 | 6 | 7 => {
-                    let base_address = uart::get_base_addr_from_irq(device_id);
-                    match inner.memory_set.mmio_map(
-                        base_address,
-                        base_address + uart::SERIAL_ADDRESS_STRIDE,
-                        0x3,
-                    ) {
-                        Ok(_) => base_address as isize,
-                        Err(_) => -2,
-                    }
-                }
-                _ => -4,
-            }
-        }
+    let pid = current_task.getpid();
+    let inner = &mut *inner;
+    let info = match &mut inner.user_trap_info {
+        Some(info) => info,
         None => {
             warn!("[syscall claim] user trap info is None!");
-            -5
+            return -5;
+        }
+    };
+    let device = match device::claim_device(device_id, pid) {
+        Ok(device) => device,
+        Err(errno) => {
+            warn!(
+                "[syscall claim] device {} not claimable: {}",
+                device_id, errno
+            );
+            return errno;
+        }
+    };
+    let mmio_start = device.mmio.first().map_or(0, |(start, _)| *start as isize);
+    if info.devices.iter().any(|(id, _)| *id == device_id) {
+        // already mapped by the earlier claim
+        return mmio_start;
+    }
+    // the claim registers are shared by all devices of the process
+    let mut ranges = Vec::new();
+    if info.devices.is_empty() {
+        for hart_id in 0..board().hart_count {
+            let claim_addr = Plic::context_address(get_context(hart_id, 'U'));
+            ranges.push((claim_addr, claim_addr + crate::config::PAGE_SIZE, 0b11, -6));
+        }
+    }
+    ranges.extend(
+        device
+            .mmio
+            .iter()
+            .map(|(start, end)| (*start, *end, 0x3, -2)),
+    );
+    for (i, (start, end, port, errno)) in ranges.iter().enumerate() {
+        if inner.memory_set.mmio_map(*start, *end, *port).is_err() {
+            warn!("[syscall claim] map {:#x}..{:#x} failed!", start, end);
+            for (start, end, _, _) in &ranges[..i] {
+                inner.memory_set.mmio_unmap(*start, *end).unwrap();
+            }
+            device::release_device(device_id, pid);
+            return *errno;
         }
     }
+    debug!(
+        "[syscall claim] mapped device {} ({}) to pid {}",
+        device_id, device.name, pid
+    );
+    info.devices.push((device_id, false));
+    mmio_start
 }
 
 pub fn sys_set_ext_int_enable(device_id: usize, enable: usize) -> isize {
@@ -273,7 +562,7 @@ pub fn sys_set_ext_int_enable(device_id: usize, enable: usize) -> isize {
                             *en = is_enable;
                             if is_enable {
                                 Plic::enable(get_context(hart_id(), 'U'), device_id);
-                                for hart in 0..CPU_NUM {
+                                for hart in 0..board().hart_count {
                                     Plic::disable(get_context(hart, 'S'), device_id);
                                 }
                             } else {
@@ -24,6 +24,9 @@ impl TaskManager {
             }
         }
     }
+    pub fn is_empty(&self) -> bool {
+        self.ready_queue.is_empty()
+    }
     pub fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
         // May need to concern affinity
         self.ready_queue.pop_front()
@@ -3,27 +3,31 @@ mod manager;
 mod pid;
 mod pool;
 mod processor;
+mod signal;
 mod switch;
 mod task;
+mod wait_queue;
 
-use crate::loader::get_app_data_by_name;
+use crate::loader::load_app;
 use alloc::sync::Arc;
 use lazy_static::*;
 
-use spin::Mutex;
+use crate::sync::IrqMutex;
 use switch::__switch;
 
 pub use task::{TaskControlBlock, TaskStatus};
 pub use context::TaskContext;
 pub use pid::{find_task, pid_alloc, KernelStack, PidHandle};
-pub use pool::{add_task, fetch_task, prioritize_task};
+pub use pool::{add_task, fetch_task, has_ready_task, park_task, prioritize_task, unpark_task};
 pub use processor::{
     current_task, current_trap_cx, current_user_token, hart_id, mmap, munmap, run_tasks, schedule,
-    set_current_priority, take_current_task,
+    set_current_priority, switch_to_idle, take_current_task,
 };
+pub use signal::*;
+pub use wait_queue::WaitQueue;
 
 lazy_static! {
-    pub static ref WAIT_LOCK: Mutex<()> = Mutex::new(());
+    pub static ref WAIT_LOCK: IrqMutex<()> = IrqMutex::new(());
 }
 
 pub fn suspend_current_and_run_next() {
@@ -37,12 +41,56 @@ pub fn suspend_current_and_run_next() {
     schedule(task_cx_ptr2);
 }
 
+/// Yields a task interrupted in the kernel. Kernel tasks are not run from
+/// here, they may be what was interrupted.
+pub fn preempt_current_and_run_next() {
+    let task = current_task().unwrap();
+    let task_cx_ptr2 = task.acquire_inner_lock().get_task_cx_ptr2();
+    drop(task);
+    switch_to_idle(task_cx_ptr2);
+}
+
+/// Switches away from a task that a `WaitQueue` marked blocked, and returns
+/// once it is woken. Returns right away if it was woken in between, or has
+/// a signal to take.
+pub fn block_current_and_run_next() {
+    let task = current_task().unwrap();
+    let mut task_inner = task.acquire_inner_lock();
+    if task_inner.task_status != TaskStatus::Blocked {
+        return;
+    }
+    if task_inner.pending_signals != 0 || task_inner.killed {
+        task_inner.task_status = TaskStatus::Running;
+        return;
+    }
+    let task_cx_ptr2 = task_inner.get_task_cx_ptr2();
+    drop(task_inner);
+    drop(task);
+    schedule(task_cx_ptr2);
+}
+
+/// Makes a blocked or sleeping task runnable again, does nothing to others.
+pub fn wake_task(task: &Arc<TaskControlBlock>) {
+    let mut inner = task.acquire_inner_lock();
+    match inner.task_status {
+        // not switched out yet, `block_current_and_run_next` will return
+        TaskStatus::Blocked => inner.task_status = TaskStatus::Running,
+        // under the task lock, like `Processor::suspend_current` parks it
+        TaskStatus::Sleeping => {
+            inner.task_status = TaskStatus::Ready;
+            unpark_task(task.clone());
+        }
+        _ => {}
+    }
+}
+
 pub fn exit_current_and_run_next(exit_code: i32) {
     // take from Processor
     let task = take_current_task().unwrap();
     // **** hold current PCB lock
     let wl = WAIT_LOCK.lock();
     debug!("pid: {} exited with code {}", task.pid.0, exit_code);
+    crate::timer::remove_task_timers(task.pid.0);
     let mut inner = task.acquire_inner_lock();
     if let Some(trap_info) = &inner.user_trap_info {
         trap_info.remove_user_ext_int_map();
@@ -89,9 +137,54 @@ pub fn exit_current_and_run_next(exit_code: i32) {
     // }
 }
 
+/// OOM policy: mark the task holding the most frames (except initproc) as killed.
+/// Its memory is reclaimed when it exits on its way back to user mode.
+pub fn oom_kill() {
+    let mut victim: Option<(usize, Arc<TaskControlBlock>)> = None;
+    for task in pid::all_tasks() {
+        if task.getpid() == INITPROC.getpid() {
+            continue;
+        }
+        let inner = task.acquire_inner_lock();
+        if inner.is_zombie() {
+            continue;
+        }
+        if inner.killed {
+            // a victim is already on its way out
+            return;
+        }
+        let frames = inner.memory_set.frame_count();
+        drop(inner);
+        if victim.as_ref().map_or(true, |(max, _)| frames > *max) {
+            victim = Some((frames, task));
+        }
+    }
+    if let Some((frames, task)) = victim {
+        warn!(
+            "[oom] out of memory, killing pid {} holding {} frames",
+            task.getpid(),
+            frames
+        );
+        task.acquire_inner_lock().killed = true;
+        // it may be sleeping in a read
+        wake_task(&task);
+    } else {
+        error!("[oom] out of memory, but no task can be killed!");
+    }
+}
+
+/// Whether a task of session `sid` is in process group `pgid`, zombies not
+/// reaped yet included.
+pub fn process_group_exists(pgid: usize, sid: usize) -> bool {
+    pid::all_tasks().iter().any(|task| {
+        let inner = task.acquire_inner_lock();
+        inner.pgid == pgid && inner.sid == sid
+    })
+}
+
 lazy_static! {
     pub static ref INITPROC: Arc<TaskControlBlock> =
-        TaskControlBlock::new(get_app_data_by_name("initproc").unwrap());
+        TaskControlBlock::new(&load_app("initproc").unwrap());
 }
 
 pub fn add_initproc() {
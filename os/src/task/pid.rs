@@ -1,10 +1,10 @@
 use crate::config::{KERNEL_STACK_SIZE, PAGE_SIZE, TRAMPOLINE};
 use crate::mm::{MapPermission, VirtAddr, KERNEL_SPACE};
+use crate::sync::IrqMutex;
 use alloc::collections::BTreeMap;
 use alloc::sync::{Arc, Weak};
 use alloc::vec::Vec;
 use lazy_static::*;
-use spin::Mutex;
 
 use super::task::TaskControlBlock;
 
@@ -40,22 +40,19 @@ impl PidAllocator {
     }
     pub fn dealloc(&mut self, pid: usize) {
         assert!(pid < self.current);
-        // assert!(
-        //     self.recycled.iter().find(|ppid| **ppid == pid).is_none(),
-        //     "pid {} has been deallocated!",
-        //     pid
-        // );
         assert!(
-            self.task_table.remove(&pid).is_some(),
+            self.recycled.iter().find(|ppid| **ppid == pid).is_none(),
             "pid {} has been deallocated!",
             pid
         );
+        // a pid whose task creation failed half way was never added to the table
+        self.task_table.remove(&pid);
         self.recycled.push(pid);
     }
 }
 
 lazy_static! {
-    static ref PID_ALLOCATOR: Mutex<PidAllocator> = Mutex::new(PidAllocator::new());
+    static ref PID_ALLOCATOR: IrqMutex<PidAllocator> = IrqMutex::new(PidAllocator::new());
 }
 
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug)]
@@ -82,6 +79,15 @@ pub fn add_task_2_map(pid: usize, task: Arc<TaskControlBlock>) {
     PID_ALLOCATOR.lock().add_task(pid, task).unwrap();
 }
 
+pub fn all_tasks() -> Vec<Arc<TaskControlBlock>> {
+    PID_ALLOCATOR
+        .lock()
+        .task_table
+        .values()
+        .filter_map(|weak| weak.upgrade())
+        .collect()
+}
+
 pub fn find_task(pid: usize) -> Option<Arc<TaskControlBlock>> {
     PID_ALLOCATOR
         .lock()
@@ -103,15 +109,15 @@ pub struct KernelStack {
 }
 
 impl KernelStack {
-    pub fn new(pid_handle: &PidHandle) -> Self {
+    pub fn new(pid_handle: &PidHandle) -> Result<Self, isize> {
         let pid = pid_handle.0;
         let (kernel_stack_bottom, kernel_stack_top) = kernel_stack_position(pid);
         KERNEL_SPACE.lock().insert_framed_area(
             kernel_stack_bottom.into(),
             kernel_stack_top.into(),
             MapPermission::R | MapPermission::W,
-        );
-        KernelStack { pid: pid_handle.0 }
+        )?;
+        Ok(KernelStack { pid: pid_handle.0 })
     }
     pub fn push_on_top<T>(&self, value: T) -> *mut T
     where
@@ -1,8 +1,8 @@
+use crate::sync::IrqMutex;
 use alloc::{collections::BTreeSet, sync::Arc};
 use lazy_static::*;
-use spin::Mutex;
 
-use super::{manager::TaskManager, task::TaskControlBlock};
+use super::{manager::TaskManager, processor::wake_idle_hart, task::TaskControlBlock};
 
 pub struct TaskPool {
     pub scheduler: TaskManager,
@@ -10,7 +10,7 @@ pub struct TaskPool {
 }
 
 lazy_static! {
-    pub static ref TASK_POOL: Mutex<TaskPool> = Mutex::new(TaskPool::new());
+    pub static ref TASK_POOL: IrqMutex<TaskPool> = IrqMutex::new(TaskPool::new());
 }
 
 impl TaskPool {
@@ -52,12 +52,27 @@ pub fn add_task(task: Arc<TaskControlBlock>) {
     let token = task.acquire_inner_lock().memory_set.token();
     // trace!("task pid: {}, satp: {:#x} added to pool", task.pid.0, token);
     TASK_POOL.lock().add(task);
+    wake_idle_hart();
 }
 
 pub fn fetch_task() -> Option<Arc<TaskControlBlock>> {
     TASK_POOL.lock().fetch()
 }
 
+pub fn has_ready_task() -> bool {
+    !TASK_POOL.lock().scheduler.is_empty()
+}
+
+/// Keeps a sleeping task aside, off the ready queue.
+pub fn park_task(task: Arc<TaskControlBlock>) {
+    TASK_POOL.lock().sleep(task);
+}
+
+pub fn unpark_task(task: Arc<TaskControlBlock>) {
+    TASK_POOL.lock().wake(task);
+    wake_idle_hart();
+}
+
 pub fn prioritize_task(pid: usize) {
     TASK_POOL.lock().prioritize(pid);
 }
@@ -1,12 +1,19 @@
 use super::TaskControlBlock;
 use super::__switch;
-use super::add_task;
-use super::{fetch_task, TaskStatus};
+use super::{add_task, park_task};
+use super::{fetch_task, has_ready_task, TaskStatus};
 use crate::config::CPU_NUM;
-use crate::trap::TrapContext;
+use crate::ipi::{handle_ipi, send_ipi, IpiMessage};
+use crate::plic;
+use crate::sbi;
+use crate::sync::{intr_restore, intr_save, IntrGuard};
+use crate::timer::get_time_ticks;
+use crate::trap::{handle_timer, TrapContext};
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::cell::RefCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use riscv::register::sip;
 use lazy_static::*;
 use crate::async_rt::run_until_idle;
 
@@ -14,6 +21,9 @@ lazy_static! {
     pub static ref PROCESSORS: [Processor; CPU_NUM] = Default::default();
 }
 
+/// Harts sleeping in `Processor::idle`, one bit each.
+static IDLE_HARTS: AtomicUsize = AtomicUsize::new(0);
+
 pub struct Processor {
     inner: RefCell<ProcessorInner>,
 }
@@ -99,23 +109,38 @@ impl Processor {
         if let Some(trap_info) = &task_inner.user_trap_info {
             trap_info.enable_user_ext_int();
         }
+        task_inner.scheduled_at = get_time_ticks();
         drop(task_inner);
-        self.inner.borrow_mut().current = Some(task);
+        crate::timer::migrate_task_timers(task.pid.0);
+        self.inner.borrow_mut().current = Some(task.clone());
 
+        let intr = intr_save();
         unsafe {
             __switch(idle_task_cx_ptr2, next_task_cx_ptr2);
         }
+        intr_restore(intr);
+        // back from the task, whether it yielded or exited
+        let mut task_inner = task.acquire_inner_lock();
+        task_inner.cpu_time += get_time_ticks() - task_inner.scheduled_at;
     }
 
     fn suspend_current(&self) {
         if let Some(task) = take_current_task() {
             // ---- hold current PCB lock
             let mut task_inner = task.acquire_inner_lock();
-            // Change status to Ready
-            task_inner.task_status = TaskStatus::Ready;
             if let Some(trap_info) = &task_inner.user_trap_info {
                 trap_info.disable_user_ext_int();
             }
+            if task_inner.task_status == TaskStatus::Blocked {
+                // its context is saved now, so a waker may run it anywhere.
+                // Parked under the task lock, or a `wake_task` in between
+                // would unpark it before it is parked.
+                task_inner.task_status = TaskStatus::Sleeping;
+                park_task(task.clone());
+                return;
+            }
+            // Change status to Ready
+            task_inner.task_status = TaskStatus::Ready;
             drop(task_inner);
             // ---- release current PCB lock
 
@@ -130,8 +155,35 @@ impl Processor {
                 self.run_next(task);
                 // __switch inside run_next
                 self.suspend_current();
+            } else {
+                self.idle();
+            }
+        }
+    }
+    /// Sleep until an interrupt arrives, `add_task` sends an IPI to one idle hart.
+    fn idle(&self) {
+        let mask = 1 << hart_id();
+        IDLE_HARTS.fetch_or(mask, Ordering::SeqCst);
+        // a task added before we published the idle bit did not wake anyone
+        if !has_ready_task() {
+            // sstatus.SIE stays clear, so pending interrupts wake us without trapping
+            if sbi::hart_suspend(sbi::HSM_SUSPEND_RETENTIVE, 0, 0).is_err() {
+                unsafe {
+                    asm!("wfi");
+                }
             }
         }
+        IDLE_HARTS.fetch_and(!mask, Ordering::SeqCst);
+        // nothing to preempt here, a reschedule request is served by the loop anyway
+        handle_ipi();
+        if sip::read().stimer() {
+            handle_timer();
+        }
+        // a pending SEIP makes the suspend return at once, and device
+        // interrupts are what wakes blocked tasks
+        if sip::read().sext() {
+            plic::handle_external_interrupt(hart_id());
+        }
     }
     pub fn take_current(&self) -> Option<Arc<TaskControlBlock>> {
         self.inner.borrow_mut().current.take()
@@ -157,16 +209,36 @@ pub fn hart_id() -> usize {
     hart_id
 }
 
+/// Kick one idle hart, if any, to pick up newly added work.
+pub fn wake_idle_hart() {
+    loop {
+        let idle = IDLE_HARTS.load(Ordering::SeqCst);
+        if idle == 0 {
+            return;
+        }
+        let mask = 1 << idle.trailing_zeros();
+        // claim the hart so concurrent wakers pick different ones
+        if IDLE_HARTS.fetch_and(!mask, Ordering::SeqCst) & mask != 0 {
+            send_ipi(mask, IpiMessage::Reschedule);
+            return;
+        }
+    }
+}
+
 pub fn run_tasks() {
     debug!("run_tasks");
     PROCESSORS[hart_id()].run();
 }
 
+// the guards keep us from migrating between reading hart_id and using it
+
 pub fn take_current_task() -> Option<Arc<TaskControlBlock>> {
+    let _intr = IntrGuard::new();
     PROCESSORS[hart_id()].take_current()
 }
 
 pub fn current_task() -> Option<Arc<TaskControlBlock>> {
+    let _intr = IntrGuard::new();
     PROCESSORS[hart_id()].current()
 }
 
@@ -190,10 +262,18 @@ pub fn current_trap_cx() -> &'static mut TrapContext {
 pub fn schedule(switched_task_cx_ptr2: *const usize) {
     // 调度所有的系统调用任务
     run_until_idle();
+    switch_to_idle(switched_task_cx_ptr2);
+}
+
+/// `schedule` without running the kernel tasks, for a task preempted in the
+/// middle of the kernel.
+pub fn switch_to_idle(switched_task_cx_ptr2: *const usize) {
+    let intr = intr_save();
     let idle_task_cx_ptr2 = PROCESSORS[hart_id()].get_idle_task_cx_ptr2();
     unsafe {
         __switch(switched_task_cx_ptr2, idle_task_cx_ptr2);
     }
+    intr_restore(intr);
 }
 
 pub fn set_current_priority(priority: isize) -> Result<isize, isize> {
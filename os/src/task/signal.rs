@@ -0,0 +1,102 @@
+//! Signals with their default actions only: terminate, stop or continue.
+//! They are acted on when the task goes back to user mode.
+
+use super::{
+    block_current_and_run_next, current_task, exit_current_and_run_next, pid::all_tasks, wake_task,
+    TaskControlBlock, TaskStatus,
+};
+use crate::errno::ESRCH;
+use alloc::sync::Arc;
+
+pub const SIGHUP: usize = 1;
+pub const SIGINT: usize = 2;
+pub const SIGQUIT: usize = 3;
+pub const SIGKILL: usize = 9;
+pub const SIGTERM: usize = 15;
+pub const SIGCHLD: usize = 17;
+pub const SIGCONT: usize = 18;
+pub const SIGSTOP: usize = 19;
+pub const SIGTSTP: usize = 20;
+pub const SIGTTIN: usize = 21;
+pub const SIGTTOU: usize = 22;
+pub const SIGURG: usize = 23;
+pub const SIGWINCH: usize = 28;
+pub const NSIG: usize = 32;
+
+const STOP_MASK: usize = (1 << SIGSTOP) | (1 << SIGTSTP) | (1 << SIGTTIN) | (1 << SIGTTOU);
+const IGNORE_MASK: usize = (1 << SIGCHLD) | (1 << SIGURG) | (1 << SIGWINCH);
+
+/// Marks `signo` pending on `task` and wakes it, to give up what it waits
+/// for. SIGCONT takes effect right away instead, waking the task from its
+/// stop and dropping its pending stops.
+pub fn send_signal(task: &Arc<TaskControlBlock>, signo: usize) {
+    let mut inner = task.acquire_inner_lock();
+    if inner.is_zombie() || signo == 0 || IGNORE_MASK & (1 << signo) != 0 {
+        return;
+    }
+    if signo == SIGCONT {
+        inner.stopped_by = None;
+        inner.pending_signals &= !STOP_MASK;
+    } else {
+        inner.pending_signals |= 1 << signo;
+    }
+    drop(inner);
+    wake_task(task);
+}
+
+/// Sends `signo` to every task in process group `pgid`.
+pub fn signal_group(pgid: usize, signo: usize) -> Result<(), isize> {
+    let mut found = false;
+    for task in all_tasks() {
+        if task.acquire_inner_lock().pgid == pgid {
+            send_signal(&task, signo);
+            found = true;
+        }
+    }
+    if found {
+        Ok(())
+    } else {
+        Err(ESRCH)
+    }
+}
+
+/// For blocking waits in the kernel, which give up with EINTR on a signal,
+/// or once the OOM killer picked the task.
+pub fn current_signal_pending() -> bool {
+    current_task()
+        .map(|task| {
+            let inner = task.acquire_inner_lock();
+            inner.pending_signals != 0 || inner.killed
+        })
+        .unwrap_or(false)
+}
+
+/// Runs the default action of the pending signals. A stopped task sleeps
+/// here until SIGCONT, or a signal that terminates it.
+pub fn handle_signals() {
+    loop {
+        let task = current_task().unwrap();
+        let mut inner = task.acquire_inner_lock();
+        let pending = inner.pending_signals;
+        let terminate = pending & !STOP_MASK;
+        if terminate != 0 {
+            let signo = terminate.trailing_zeros() as i32;
+            drop(inner);
+            drop(task);
+            exit_current_and_run_next(-signo);
+            unreachable!();
+        }
+        if pending & STOP_MASK != 0 {
+            inner.pending_signals &= !STOP_MASK;
+            inner.stopped_by = Some((pending & STOP_MASK).trailing_zeros() as usize);
+            inner.stop_reported = false;
+        }
+        if inner.stopped_by.is_none() {
+            return;
+        }
+        inner.task_status = TaskStatus::Blocked;
+        drop(inner);
+        drop(task);
+        block_current_and_run_next();
+    }
+}
@@ -2,18 +2,21 @@ use super::TaskContext;
 use super::{pid_alloc, KernelStack, PidHandle};
 use crate::fs::{File, MailBox, Serial, Socket, Stdin, Stdout};
 use crate::mm::{translate_writable_va, MemorySet, PhysAddr, PhysPageNum, VirtAddr, KERNEL_SPACE};
+use crate::sync::{IrqMutex, IrqMutexGuard};
 use crate::task::pid::add_task_2_map;
 use crate::trap::{trap_handler, TrapContext, UserTrapInfo};
 use crate::{
-    config::{PAGE_SIZE, TRAP_CONTEXT, USER_TRAP_BUFFER},
-    loader::get_app_data_by_name,
-    mm::translated_str,
+    config::{PAGE_SIZE, TRAP_CONTEXT, USER_STACK_SIZE, USER_TRAP_BUFFER},
+    errno::{E2BIG, ELOOP, ENOENT, ENOEXEC},
+    loader::load_app,
+    mm::{translated_refmut, translated_str},
 };
+use alloc::string::String;
 use alloc::sync::{Arc, Weak};
 use alloc::vec;
 use alloc::vec::Vec;
 use core::fmt::{self, Debug, Formatter};
-use spin::{Mutex, MutexGuard};
+use core::mem::size_of;
 
 #[derive(Debug)]
 pub struct TaskControlBlock {
@@ -21,7 +24,7 @@ pub struct TaskControlBlock {
     pub pid: PidHandle,
     pub kernel_stack: KernelStack,
     // mutable
-    inner: Mutex<TaskControlBlockInner>,
+    inner: IrqMutex<TaskControlBlockInner>,
 }
 
 pub struct TaskControlBlockInner {
@@ -35,6 +38,23 @@ pub struct TaskControlBlockInner {
     pub parent: Option<Weak<TaskControlBlock>>,
     pub children: Vec<Arc<TaskControlBlock>>,
     pub exit_code: i32,
+    pub killed: bool,
+    /// Process group, named after the pid of its leader.
+    pub pgid: usize,
+    /// Session, named after the pid of its leader.
+    pub sid: usize,
+    /// Signals not acted on yet, bit n for signal n.
+    pub pending_signals: usize,
+    /// The signal that stopped the task, until SIGCONT.
+    pub stopped_by: Option<usize>,
+    /// Whether `waitpid` told the parent about the stop already.
+    pub stop_reported: bool,
+    /// Timer id behind the periodic mode of `sys_set_timer`.
+    pub user_timer: Option<usize>,
+    /// Ticks spent running, kernel time included.
+    pub cpu_time: usize,
+    /// When the task was last switched to.
+    pub scheduled_at: usize,
     pub fd_table: Vec<Option<Arc<dyn File + Send + Sync>>>,
     pub mail_box: Arc<MailBox>,
 }
@@ -145,19 +165,20 @@ impl TaskControlBlockInner {
 }
 
 impl TaskControlBlock {
-    pub fn acquire_inner_lock(&self) -> MutexGuard<TaskControlBlockInner> {
+    pub fn acquire_inner_lock(&self) -> IrqMutexGuard<TaskControlBlockInner> {
         self.inner.lock()
     }
     pub fn new(elf_data: &[u8]) -> Arc<TaskControlBlock> {
         // memory_set with elf program headers/trampoline/trap context/user stack
-        let (memory_set, user_sp, entry_point) = MemorySet::from_elf(elf_data);
+        let (memory_set, user_sp, entry_point) = MemorySet::from_elf(elf_data).unwrap();
         let trap_cx_ppn = memory_set
             .translate(VirtAddr::from(TRAP_CONTEXT).into())
             .unwrap()
             .ppn();
         // alloc a pid and a kernel stack in kernel space
         let pid_handle = pid_alloc();
-        let kernel_stack = KernelStack::new(&pid_handle);
+        let pgid = pid_handle.0;
+        let kernel_stack = KernelStack::new(&pid_handle).unwrap();
         let kernel_stack_top = kernel_stack.get_top();
         // push a task context which goes to trap_return to the top of kernel stack
         let task_cx_ptr = kernel_stack.push_on_top(TaskContext::goto_trap_return());
@@ -165,7 +186,7 @@ impl TaskControlBlock {
         let task_control_block = Arc::new(TaskControlBlock {
             pid: pid_handle,
             kernel_stack,
-            inner: Mutex::new(TaskControlBlockInner {
+            inner: IrqMutex::new(TaskControlBlockInner {
                 trap_cx_ppn,
                 base_size: user_sp,
                 task_cx_ptr: task_cx_ptr as usize,
@@ -175,18 +196,27 @@ impl TaskControlBlock {
                 parent: None,
                 children: Vec::new(),
                 exit_code: 0,
+                killed: false,
+                pgid,
+                sid: pgid,
+                pending_signals: 0,
+                stopped_by: None,
+                stop_reported: false,
+                user_timer: None,
+                cpu_time: 0,
+                scheduled_at: 0,
                 priority: 16,
                 fd_table: vec![
                     // 0 -> stdin
-                    Some(Arc::new(Stdin)),
+                    Some(Arc::new(Stdin::default())),
                     // 1 -> stdout
                     Some(Arc::new(Stdout)),
                     // 2 -> stderr
                     Some(Arc::new(Stdout)),
                     // 3 -> serial 3
-                    Some(Arc::new(Serial::<2>)),
+                    Some(Arc::new(Serial::new(2))),
                     // 4 -> serial 4
-                    Some(Arc::new(Serial::<3>)),
+                    Some(Arc::new(Serial::new(3))),
                 ],
                 mail_box: Arc::new(MailBox::new()),
             }),
@@ -204,17 +234,65 @@ impl TaskControlBlock {
         task_control_block
     }
 
-    pub fn exec(&self, elf_data: &[u8]) {
+    /// Scripts starting with `#!` run their interpreter instead, with the
+    /// script path as argv[1]. May yield while loading an interpreter.
+    pub fn exec(&self, path: &str, data: &[u8], mut args: Vec<String>) -> Result<(), isize> {
+        let mut path = String::from(path);
+        let mut interpreter_data;
+        let mut data = data;
+        for _ in 0..=MAX_INTERPRETER_DEPTH {
+            let (interpreter, arg) = match parse_shebang(data)? {
+                Some(shebang) => shebang,
+                None => return self.exec_elf(data, args),
+            };
+            debug!("exec {} via {}", path, interpreter);
+            let mut new_args = vec![interpreter.clone()];
+            new_args.extend(arg);
+            new_args.push(path);
+            new_args.extend(args.into_iter().skip(1));
+            args = new_args;
+            interpreter_data = load_app(interpreter.as_str()).ok_or(ENOENT)?;
+            data = &interpreter_data[..];
+            path = interpreter;
+        }
+        Err(ELOOP)
+    }
+
+    fn exec_elf(&self, elf_data: &[u8], args: Vec<String>) -> Result<(), isize> {
         // memory_set with elf program headers/trampoline/trap context/user stack
-        let (memory_set, user_sp, entry_point) = MemorySet::from_elf(elf_data);
+        let (memory_set, mut user_sp, entry_point) = MemorySet::from_elf(elf_data)?;
         let trap_cx_ppn = memory_set
             .translate(VirtAddr::from(TRAP_CONTEXT).into())
             .unwrap()
             .ppn();
+        // argv pointers with a null at the end, then the strings below them
+        let token = memory_set.token();
+        let args_size = (args.len() + 1) * size_of::<usize>()
+            + args.iter().map(|arg| arg.len() + 1).sum::<usize>();
+        if args_size > USER_STACK_SIZE / 2 {
+            return Err(E2BIG);
+        }
+        user_sp -= (args.len() + 1) * size_of::<usize>();
+        let argv_base = user_sp;
+        *translated_refmut(
+            token,
+            (argv_base + args.len() * size_of::<usize>()) as *mut usize,
+        ) = 0;
+        for (i, arg) in args.iter().enumerate() {
+            user_sp -= arg.len() + 1;
+            *translated_refmut(token, (argv_base + i * size_of::<usize>()) as *mut usize) = user_sp;
+            for (offset, byte) in arg.bytes().chain(Some(0)).enumerate() {
+                *translated_refmut(token, (user_sp + offset) as *mut u8) = byte;
+            }
+        }
+        // the RISC-V ABI keeps sp 16 byte aligned
+        user_sp &= !0xf;
 
         // **** hold current PCB lock
+        crate::timer::remove_task_timers(self.pid.0);
         let mut inner = self.acquire_inner_lock();
         inner.user_trap_info = None;
+        inner.user_timer = None;
         // substitute memory_set
         inner.memory_set = memory_set;
         // update trap_cx ppn
@@ -228,21 +306,24 @@ impl TaskControlBlock {
             self.kernel_stack.get_top(),
             trap_handler as usize,
         );
+        trap_cx.x[10] = args.len();
+        trap_cx.x[11] = argv_base;
+        Ok(())
         // **** release current PCB lock
     }
 
-    pub fn fork(self: &Arc<TaskControlBlock>) -> Arc<TaskControlBlock> {
+    pub fn fork(self: &Arc<TaskControlBlock>) -> Result<Arc<TaskControlBlock>, isize> {
         // ---- hold parent PCB lock
         let mut parent_inner = self.acquire_inner_lock();
         // copy user space(include trap context)
-        let memory_set = MemorySet::from_existed_user(&parent_inner.memory_set);
+        let memory_set = MemorySet::from_existed_user(&parent_inner.memory_set)?;
         let trap_cx_ppn = memory_set
             .translate(VirtAddr::from(TRAP_CONTEXT).into())
             .unwrap()
             .ppn();
         // alloc a pid and a kernel stack in kernel space
         let pid_handle = pid_alloc();
-        let kernel_stack = KernelStack::new(&pid_handle);
+        let kernel_stack = KernelStack::new(&pid_handle)?;
         let kernel_stack_top = kernel_stack.get_top();
         // push a goto_trap_return task_cx on the top of kernel stack
         let task_cx_ptr = kernel_stack.push_on_top(TaskContext::goto_trap_return());
@@ -268,7 +349,7 @@ impl TaskControlBlock {
         let task_control_block = Arc::new(TaskControlBlock {
             pid: pid_handle,
             kernel_stack,
-            inner: Mutex::new(TaskControlBlockInner {
+            inner: IrqMutex::new(TaskControlBlockInner {
                 trap_cx_ppn,
                 base_size: parent_inner.base_size,
                 task_cx_ptr: task_cx_ptr as usize,
@@ -278,6 +359,15 @@ impl TaskControlBlock {
                 parent: Some(Arc::downgrade(self)),
                 children: Vec::new(),
                 exit_code: 0,
+                killed: false,
+                pgid: parent_inner.pgid,
+                sid: parent_inner.sid,
+                pending_signals: 0,
+                stopped_by: None,
+                stop_reported: false,
+                user_timer: None,
+                cpu_time: 0,
+                scheduled_at: 0,
                 priority: 16,
                 fd_table: new_fd_table,
                 mail_box: Arc::new(MailBox::new()),
@@ -292,7 +382,7 @@ impl TaskControlBlock {
         // **** release child PCB lock
         trap_cx.kernel_sp = kernel_stack_top;
         // return
-        task_control_block
+        Ok(task_control_block)
         // ---- release parent PCB lock
     }
     pub fn getpid(&self) -> usize {
@@ -303,65 +393,80 @@ impl TaskControlBlock {
         self: &Arc<TaskControlBlock>,
         file: *const u8,
     ) -> Result<Arc<TaskControlBlock>, isize> {
-        let mut parent_inner = self.acquire_inner_lock();
-        let parent_token = parent_inner.get_user_token();
+        let parent_token = self.acquire_inner_lock().get_user_token();
         let f = translated_str(parent_token, file);
         debug!("SPAWN exec {:?}", &f);
+        // without the lock held, loading from disk may yield
+        let elf_data = match load_app(f.as_str()) {
+            Some(elf_data) => elf_data,
+            None => return Err(-1),
+        };
+        let (memory_set, user_sp, entry_point) = MemorySet::from_elf(&elf_data)?;
+        let (pgid, sid) = {
+            let parent_inner = self.acquire_inner_lock();
+            (parent_inner.pgid, parent_inner.sid)
+        };
+        let trap_cx_ppn = memory_set
+            .translate(VirtAddr::from(TRAP_CONTEXT).into())
+            .unwrap()
+            .ppn();
+        let pid_handle = pid_alloc();
+        let kernel_stack = KernelStack::new(&pid_handle)?;
+        let kernel_stack_top = kernel_stack.get_top();
+        let task_cx_ptr = kernel_stack.push_on_top(TaskContext::goto_trap_return());
+        trace!("spawned task cx ptr: {:#x?}", task_cx_ptr as usize);
 
-        if let Some(elf_data) = get_app_data_by_name(f.as_str()) {
-            let (memory_set, user_sp, entry_point) = MemorySet::from_elf(elf_data);
-            let trap_cx_ppn = memory_set
-                .translate(VirtAddr::from(TRAP_CONTEXT).into())
-                .unwrap()
-                .ppn();
-            let pid_handle = pid_alloc();
-            let kernel_stack = KernelStack::new(&pid_handle);
-            let kernel_stack_top = kernel_stack.get_top();
-            let task_cx_ptr = kernel_stack.push_on_top(TaskContext::goto_trap_return());
-            trace!("spawned task cx ptr: {:#x?}", task_cx_ptr as usize);
-
-            let task_control_block = Arc::new(TaskControlBlock {
-                pid: pid_handle,
-                kernel_stack,
-                inner: Mutex::new(TaskControlBlockInner {
-                    trap_cx_ppn,
-                    base_size: user_sp,
-                    task_cx_ptr: task_cx_ptr as usize,
-                    user_trap_info: None,
-                    task_status: TaskStatus::Ready,
-                    memory_set,
-                    parent: Some(Arc::downgrade(self)),
-                    children: Vec::new(),
-                    exit_code: 0,
-                    priority: 16,
-                    fd_table: vec![
-                        // 0 -> stdin
-                        Some(Arc::new(Stdin)),
-                        // 1 -> stdout
-                        Some(Arc::new(Stdout)),
-                        // 2 -> stderr
-                        Some(Arc::new(Stdout)),
-                        // 3 -> serial 2
-                        Some(Arc::new(Serial::<2>)),
-                        // 4 -> serial 3
-                        Some(Arc::new(Serial::<3>)),
-                    ],
-                    mail_box: Arc::new(MailBox::new()),
-                }),
-            });
-            add_task_2_map(task_control_block.getpid(), task_control_block.clone());
-            parent_inner.children.push(task_control_block.clone());
-            let trap_cx = task_control_block.acquire_inner_lock().get_trap_cx();
-            *trap_cx = TrapContext::app_init_context(
-                entry_point,
-                user_sp,
-                KERNEL_SPACE.lock().token(),
-                kernel_stack_top,
-                trap_handler as usize,
-            );
-            return Ok(task_control_block);
-        }
-        Err(-1)
+        let task_control_block = Arc::new(TaskControlBlock {
+            pid: pid_handle,
+            kernel_stack,
+            inner: IrqMutex::new(TaskControlBlockInner {
+                trap_cx_ppn,
+                base_size: user_sp,
+                task_cx_ptr: task_cx_ptr as usize,
+                user_trap_info: None,
+                task_status: TaskStatus::Ready,
+                memory_set,
+                parent: Some(Arc::downgrade(self)),
+                children: Vec::new(),
+                exit_code: 0,
+                killed: false,
+                pgid,
+                sid,
+                pending_signals: 0,
+                stopped_by: None,
+                stop_reported: false,
+                user_timer: None,
+                cpu_time: 0,
+                scheduled_at: 0,
+                priority: 16,
+                fd_table: vec![
+                    // 0 -> stdin
+                    Some(Arc::new(Stdin::default())),
+                    // 1 -> stdout
+                    Some(Arc::new(Stdout)),
+                    // 2 -> stderr
+                    Some(Arc::new(Stdout)),
+                    // 3 -> serial 2
+                    Some(Arc::new(Serial::new(2))),
+                    // 4 -> serial 3
+                    Some(Arc::new(Serial::new(3))),
+                ],
+                mail_box: Arc::new(MailBox::new()),
+            }),
+        });
+        add_task_2_map(task_control_block.getpid(), task_control_block.clone());
+        self.acquire_inner_lock()
+            .children
+            .push(task_control_block.clone());
+        let trap_cx = task_control_block.acquire_inner_lock().get_trap_cx();
+        *trap_cx = TrapContext::app_init_context(
+            entry_point,
+            user_sp,
+            KERNEL_SPACE.lock().token(),
+            kernel_stack_top,
+            trap_handler as usize,
+        );
+        Ok(task_control_block)
     }
 
     pub fn create_socket(&self) -> Arc<Socket> {
@@ -369,6 +474,30 @@ impl TaskControlBlock {
     }
 }
 
+/// Scripts may name a script as their interpreter, up to this deep.
+const MAX_INTERPRETER_DEPTH: usize = 4;
+/// Longest `#!` line looked at, as on Linux.
+const MAX_SHEBANG_LEN: usize = 256;
+
+/// The interpreter of a `#!` script and the optional argument after it.
+fn parse_shebang(data: &[u8]) -> Result<Option<(String, Option<String>)>, isize> {
+    if !data.starts_with(b"#!") {
+        return Ok(None);
+    }
+    let head = &data[2..data.len().min(MAX_SHEBANG_LEN)];
+    let line = match head.iter().position(|&b| b == b'\n') {
+        Some(end) => &head[..end],
+        None if data.len() <= MAX_SHEBANG_LEN => head,
+        None => return Err(ENOEXEC),
+    };
+    let line = core::str::from_utf8(line).map_err(|_| ENOEXEC)?.trim();
+    // like Linux, everything after the interpreter is a single argument
+    let mut parts = line.splitn(2, |c: char| c == ' ' || c == '\t');
+    let interpreter = parts.next().filter(|s| !s.is_empty()).ok_or(ENOEXEC)?;
+    let arg = parts.next().map(str::trim).filter(|s| !s.is_empty());
+    Ok(Some((interpreter.into(), arg.map(String::from))))
+}
+
 impl PartialEq for TaskControlBlock {
     fn eq(&self, other: &Self) -> bool {
         self.pid == other.pid
@@ -393,5 +522,9 @@ impl Ord for TaskControlBlock {
 pub enum TaskStatus {
     Ready,
     Running,
+    /// Going to sleep on a `WaitQueue`, still running until it switches out.
+    Blocked,
+    /// Off the ready queue until woken.
+    Sleeping,
     Zombie,
 }
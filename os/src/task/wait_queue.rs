@@ -0,0 +1,44 @@
+use super::{current_task, wake_task, TaskControlBlock, TaskStatus};
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+
+/// Tasks sleeping until some event, kept under the lock of whatever they
+/// wait on. A waiter checks the condition and queues itself while holding
+/// that lock, then releases it and calls `block_current_and_run_next`, so a
+/// wakeup in between is not lost. Woken tasks check the condition again.
+#[derive(Default)]
+pub struct WaitQueue {
+    tasks: VecDeque<Arc<TaskControlBlock>>,
+}
+
+impl WaitQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues the current task and marks it blocked.
+    pub fn add_current(&mut self) {
+        let task = current_task().unwrap();
+        task.acquire_inner_lock().task_status = TaskStatus::Blocked;
+        self.remove(&task);
+        self.tasks.push_back(task);
+    }
+
+    /// Drops `task` from the queue, for a wait that ends without a wakeup.
+    pub fn remove(&mut self, task: &Arc<TaskControlBlock>) {
+        self.tasks.retain(|other| !Arc::ptr_eq(other, task));
+    }
+
+    /// `remove` for the current task, on its way out with EINTR or EAGAIN.
+    pub fn remove_current(&mut self) {
+        if let Some(task) = current_task() {
+            self.remove(&task);
+        }
+    }
+
+    pub fn wake_all(&mut self) {
+        for task in self.tasks.drain(..) {
+            wake_task(&task);
+        }
+    }
+}
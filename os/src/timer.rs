@@ -1,14 +1,22 @@
-use crate::config::{CLOCK_FREQ, CPU_NUM};
+use crate::config::CPU_NUM;
+use crate::errno::{EAGAIN, EINVAL};
+use crate::fdt::board;
+use crate::ipi::call_on;
+use crate::mm::{frame_alloc, FrameTracker, PhysPageNum};
 use crate::sbi::set_timer;
+use crate::sync::IrqMutex;
 use crate::task::hart_id;
-use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use lazy_static::*;
 use riscv::register::time;
-use spin::Mutex;
 
 const TICKS_PER_SEC: usize = 100;
 const MSEC_PER_SEC: usize = 1000;
 pub const USEC_PER_SEC: usize = 1_000_000;
+/// Per task, including the transient ones of `sys_set_timer`.
+const MAX_TASK_TIMERS: usize = 32;
 
 #[repr(C)]
 #[derive(Debug)]
@@ -24,50 +32,379 @@ impl TimeVal {
     }
 }
 
+pub const NSEC_PER_SEC: usize = 1_000_000_000;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TimeSpec {
+    pub sec: usize,
+    pub nsec: usize,
+}
+
+impl TimeSpec {
+    /// Split before scaling, `ticks * NSEC_PER_SEC` overflows within an hour.
+    pub fn from_ticks(ticks: usize) -> Self {
+        let freq = clock_freq();
+        TimeSpec {
+            sec: ticks / freq,
+            nsec: ticks % freq * NSEC_PER_SEC / freq,
+        }
+    }
+    pub fn from_nanos(nanos: usize) -> Self {
+        TimeSpec {
+            sec: nanos / NSEC_PER_SEC,
+            nsec: nanos % NSEC_PER_SEC,
+        }
+    }
+    /// `None` if it does not fit in usize.
+    pub fn as_nanos(&self) -> Option<usize> {
+        self.sec.checked_mul(NSEC_PER_SEC)?.checked_add(self.nsec)
+    }
+}
+
+/// Mapped read-only at `TIME_PAGE` in every user space, so user_lib can turn
+/// `rdtime` into time without a syscall. The layout is shared with user_lib.
+#[repr(C)]
+pub struct TimePage {
+    pub clock_freq: usize,
+    /// Nanoseconds from boot to the realtime epoch, set by `clock_settime`.
+    pub realtime_offset: AtomicUsize,
+}
+
+lazy_static! {
+    static ref TIME_PAGE_FRAME: FrameTracker = {
+        let frame = frame_alloc().unwrap();
+        frame.ppn.get_mut::<TimePage>().clock_freq = clock_freq();
+        frame
+    };
+}
+
+pub fn time_page_ppn() -> PhysPageNum {
+    TIME_PAGE_FRAME.ppn
+}
+
+fn time_page() -> &'static TimePage {
+    TIME_PAGE_FRAME.ppn.get_mut()
+}
+
+/// Frequency of the `time` CSR, from the device tree.
+pub fn clock_freq() -> usize {
+    board().clock_freq
+}
+
+pub fn monotonic_time() -> TimeSpec {
+    TimeSpec::from_ticks(time::read())
+}
+
+pub fn realtime() -> TimeSpec {
+    let offset = time_page().realtime_offset.load(Ordering::Relaxed);
+    let now = monotonic_time().as_nanos().unwrap();
+    TimeSpec::from_nanos(now.saturating_add(offset))
+}
+
+/// Times before boot cannot be represented.
+pub fn set_realtime(ts: TimeSpec) -> Result<(), isize> {
+    let now = monotonic_time().as_nanos().unwrap();
+    let nanos = ts.as_nanos().ok_or(EINVAL)?;
+    if ts.nsec >= NSEC_PER_SEC || nanos < now {
+        return Err(EINVAL);
+    }
+    time_page()
+        .realtime_offset
+        .store(nanos - now, Ordering::Relaxed);
+    Ok(())
+}
+
 #[allow(unused_variables)]
 pub fn get_time(mut ts: Vec<*mut usize>, tz: usize) -> isize {
     let t = time::read();
     unsafe {
-        *ts[0] = t / CLOCK_FREQ;
-        *ts[1] = (t % CLOCK_FREQ) * 1000000 / CLOCK_FREQ;
+        let freq = clock_freq();
+        *ts[0] = t / freq;
+        *ts[1] = (t % freq) * 1000000 / freq;
         trace!("t {} sec {} usec {}", t, *ts[0], *ts[1]);
     }
 
     0
 }
 
+pub fn get_time_ticks() -> usize {
+    time::read()
+}
+
 #[allow(dead_code)]
 pub fn get_time_ms() -> usize {
-    time::read() / (CLOCK_FREQ / MSEC_PER_SEC)
+    time::read() / (clock_freq() / MSEC_PER_SEC)
+}
+
+/// `None` if the ticks do not fit in usize.
+pub fn us_to_ticks(us: usize) -> Option<usize> {
+    let freq = clock_freq();
+    (us / USEC_PER_SEC)
+        .checked_mul(freq)?
+        .checked_add(us % USEC_PER_SEC * freq / USEC_PER_SEC)
+}
+
+pub fn ticks_to_us(ticks: usize) -> usize {
+    let freq = clock_freq();
+    ticks / freq * USEC_PER_SEC + ticks % freq * USEC_PER_SEC / freq
 }
 
 #[allow(dead_code)]
 pub fn get_time_us() -> usize {
-    time::read() * USEC_PER_SEC / CLOCK_FREQ
+    ticks_to_us(time::read())
+}
+
+/// What a timer does when it fires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerTarget {
+    /// The scheduler tick of the hart the timer lives on.
+    Tick,
+    /// A user timer of a task, delivered as `utimer` or a trap record.
+    Task(usize),
+}
+
+struct Timer {
+    target: TimerTarget,
+    /// 0 while disarmed.
+    deadline: usize,
+    /// 0 for one-shot timers.
+    interval: usize,
+    /// Periods skipped because they were handled late.
+    overrun: usize,
+    /// Deleted after firing once, for `sys_set_timer`.
+    transient: bool,
+    hart: usize,
+}
+
+/// Armed timers are queued on one hart by `(deadline, id)`, so equal deadlines don't collide.
+/// Task timers move to the hart their task runs on, see `migrate_task_timers`.
+struct TimerTable {
+    next_id: usize,
+    timers: BTreeMap<usize, Timer>,
+    queues: [BTreeSet<(usize, usize)>; CPU_NUM],
+    /// Timer ids of each task.
+    task_timers: BTreeMap<usize, BTreeSet<usize>>,
 }
 
-pub fn set_next_trigger() {
-    // set_timer(time::read() + CLOCK_FREQ / TICKS_PER_SEC);
-    set_virtual_timer(time::read() + CLOCK_FREQ / TICKS_PER_SEC, 0);
+impl TimerTable {
+    fn new() -> Self {
+        Self {
+            next_id: 1,
+            timers: BTreeMap::new(),
+            queues: Default::default(),
+            task_timers: BTreeMap::new(),
+        }
+    }
+    fn create(&mut self, target: TimerTarget, hart: usize) -> Result<usize, isize> {
+        if let TimerTarget::Task(pid) = target {
+            let ids = self.task_timers.entry(pid).or_default();
+            if ids.len() >= MAX_TASK_TIMERS {
+                return Err(EAGAIN);
+            }
+            ids.insert(self.next_id);
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.timers.insert(
+            id,
+            Timer {
+                target,
+                deadline: 0,
+                interval: 0,
+                overrun: 0,
+                transient: false,
+                hart,
+            },
+        );
+        Ok(id)
+    }
+    fn remove(&mut self, id: usize) {
+        self.disarm(id);
+        if let Some(Timer {
+            target: TimerTarget::Task(pid),
+            ..
+        }) = self.timers.remove(&id)
+        {
+            let ids = self.task_timers.get_mut(&pid).unwrap();
+            ids.remove(&id);
+            if ids.is_empty() {
+                self.task_timers.remove(&pid);
+            }
+        }
+    }
+    fn disarm(&mut self, id: usize) {
+        let timer = self.timers.get_mut(&id).unwrap();
+        if timer.deadline != 0 {
+            self.queues[timer.hart].remove(&(timer.deadline, id));
+            timer.deadline = 0;
+        }
+    }
+    /// Returns the hart whose queue got a new earliest deadline.
+    fn arm(&mut self, id: usize, deadline: usize, interval: usize) -> Option<usize> {
+        self.disarm(id);
+        let timer = self.timers.get_mut(&id).unwrap();
+        timer.interval = interval;
+        timer.overrun = 0;
+        if deadline == 0 {
+            return None;
+        }
+        timer.deadline = deadline;
+        let hart = timer.hart;
+        self.queues[hart].insert((deadline, id));
+        if self.queues[hart].iter().next() == Some(&(deadline, id)) {
+            Some(hart)
+        } else {
+            None
+        }
+    }
+    fn next_deadline(&self, hart: usize) -> Option<usize> {
+        self.queues[hart]
+            .iter()
+            .next()
+            .map(|(deadline, _)| *deadline)
+    }
 }
 
 lazy_static! {
-    pub static ref TIMER_MAP: [Arc<Mutex<BTreeMap<usize, usize>>>; CPU_NUM] = Default::default();
+    static ref TIMERS: IrqMutex<TimerTable> = IrqMutex::new(TimerTable::new());
+}
+
+/// Program the hardware timer of this hart for its earliest deadline.
+fn program_local(table: &TimerTable) {
+    set_timer(table.next_deadline(hart_id()).unwrap_or(usize::MAX));
+}
+
+fn reprogram(hart: Option<usize>) {
+    match hart {
+        Some(hart) if hart == hart_id() => program_local(&TIMERS.lock()),
+        Some(hart) => call_on(1 << hart, || program_local(&TIMERS.lock())),
+        None => {}
+    }
+}
+
+/// Start the periodic scheduler tick of this hart.
+pub fn init_hart() {
+    let interval = clock_freq() / TICKS_PER_SEC;
+    let mut table = TIMERS.lock();
+    let id = table.create(TimerTarget::Tick, hart_id()).unwrap();
+    table.arm(id, time::read() + interval, interval);
+    program_local(&table);
+}
+
+pub fn timer_create(pid: usize) -> Result<usize, isize> {
+    TIMERS.lock().create(TimerTarget::Task(pid), hart_id())
+}
+
+fn owned_by(table: &TimerTable, id: usize, pid: usize) -> Result<(), isize> {
+    match table.timers.get(&id) {
+        Some(timer) if timer.target == TimerTarget::Task(pid) => Ok(()),
+        _ => Err(EINVAL),
+    }
+}
+
+/// Arm `id` for the absolute `deadline` in ticks, 0 disarms it. A non-zero
+/// `interval` makes it periodic.
+pub fn timer_settime(pid: usize, id: usize, deadline: usize, interval: usize) -> Result<(), isize> {
+    let mut table = TIMERS.lock();
+    owned_by(&table, id, pid)?;
+    let hart = table.arm(id, deadline, interval);
+    drop(table);
+    reprogram(hart);
+    Ok(())
 }
 
-pub fn set_virtual_timer(mut time: usize, pid: usize) {
+/// (deadline, interval, overrun) of `id`, the deadline is 0 if disarmed.
+pub fn timer_gettime(pid: usize, id: usize) -> Result<(usize, usize, usize), isize> {
+    let table = TIMERS.lock();
+    owned_by(&table, id, pid)?;
+    let timer = &table.timers[&id];
+    Ok((timer.deadline, timer.interval, timer.overrun))
+}
+
+pub fn timer_delete(pid: usize, id: usize) -> Result<(), isize> {
+    let mut table = TIMERS.lock();
+    owned_by(&table, id, pid)?;
+    table.remove(id);
+    Ok(())
+}
+
+/// One-shot timer deleted once it fires.
+pub fn set_virtual_timer(time: usize, pid: usize) -> Result<(), isize> {
     if time < time::read() {
         warn!("Time travel!");
-        // return;
     }
-    let mut timer_map = TIMER_MAP[hart_id()].lock();
-    while timer_map.contains_key(&time) {
-        time += 1;
+    let mut table = TIMERS.lock();
+    let id = table.create(TimerTarget::Task(pid), hart_id())?;
+    table.timers.get_mut(&id).unwrap().transient = true;
+    let hart = table.arm(id, time, 0);
+    drop(table);
+    reprogram(hart);
+    Ok(())
+}
+
+/// Delete all timers of a task, on exit and exec.
+pub fn remove_task_timers(pid: usize) {
+    let mut table = TIMERS.lock();
+    let ids = table.task_timers.get(&pid).cloned().unwrap_or_default();
+    for id in ids {
+        table.remove(id);
+    }
+}
+
+/// Move the timers of `pid` to this hart, called when the task is scheduled here.
+pub fn migrate_task_timers(pid: usize) {
+    let hart = hart_id();
+    let mut table = TIMERS.lock();
+    let table = &mut *table;
+    let ids = match table.task_timers.get(&pid) {
+        Some(ids) => ids,
+        None => return,
+    };
+    let mut moved = false;
+    for id in ids {
+        let timer = table.timers.get_mut(id).unwrap();
+        if timer.hart == hart {
+            continue;
+        }
+        if timer.deadline != 0 {
+            table.queues[timer.hart].remove(&(timer.deadline, *id));
+            table.queues[hart].insert((timer.deadline, *id));
+        }
+        timer.hart = hart;
+        moved = true;
+    }
+    if moved {
+        program_local(table);
     }
-    timer_map.insert(time, pid);
-    if let Some((timer_min, _)) = timer_map.first_key_value() {
-        if time == *timer_min {
-            set_timer(time);
+}
+
+/// Pop the due timers of this hart, rearm the periodic ones and program the next deadline.
+pub fn expire() -> Vec<TimerTarget> {
+    let hart = hart_id();
+    let now = time::read();
+    let mut table = TIMERS.lock();
+    let mut fired = Vec::new();
+    while let Some(&(deadline, id)) = table.queues[hart].iter().next() {
+        if deadline > now {
+            break;
+        }
+        table.queues[hart].remove(&(deadline, id));
+        let timer = table.timers.get_mut(&id).unwrap();
+        fired.push(timer.target);
+        if timer.interval != 0 {
+            // step from the old deadline, not from now, so the period does not drift
+            let missed = (now - deadline) / timer.interval;
+            timer.overrun = missed;
+            timer.deadline = deadline + (missed + 1) * timer.interval;
+            let next = timer.deadline;
+            table.queues[hart].insert((next, id));
+        } else if timer.transient {
+            table.remove(id);
+        } else {
+            timer.deadline = 0;
         }
     }
+    program_local(&table);
+    fired
 }
@@ -2,14 +2,14 @@ mod context;
 mod usertrap;
 
 use crate::config::{TRAMPOLINE, TRAP_CONTEXT};
+use crate::ipi;
 use crate::plic;
-use crate::sbi::set_timer;
 use crate::syscall::syscall;
 use crate::task::{
-    current_task, current_trap_cx, current_user_token, exit_current_and_run_next, hart_id,
-    suspend_current_and_run_next,
+    current_task, current_trap_cx, current_user_token, exit_current_and_run_next, handle_signals,
+    hart_id, preempt_current_and_run_next, suspend_current_and_run_next,
 };
-use crate::timer::{get_time_us, set_next_trigger, TIMER_MAP};
+use crate::timer::{self, get_time_us, TimerTarget};
 use riscv::register::{
     mtvec::TrapMode,
     scause::{self, Exception, Interrupt, Trap},
@@ -26,6 +26,8 @@ pub fn init() {
         sideleg::set_usoft();
         sideleg::set_uext();
         sideleg::set_utimer();
+        // let user space read `time` for the time page
+        asm!("csrs scounteren, {}", in(reg) 1 << 1);
     }
     set_kernel_trap_entry();
 }
@@ -57,6 +59,10 @@ pub fn trap_handler() -> ! {
             let mut cx = current_trap_cx();
             cx.sepc += 4;
             let id = cx.x[17];
+            // syscalls may run long, let timer and device interrupts in
+            unsafe {
+                sstatus::set_sie();
+            }
             // get system call return value
             let result = syscall(cx.x[17], [cx.x[10], cx.x[11], cx.x[12], cx.x[13]]);
             // cx is changed during sys_exec, so we have to call it again
@@ -86,50 +92,19 @@ pub fn trap_handler() -> ! {
             exit_current_and_run_next(-3);
         }
         Trap::Interrupt(Interrupt::SupervisorTimer) => {
-            // let current_time = time::read();
-            // debug!("time int");
-            let mut timer_map = TIMER_MAP[hart_id()].lock();
-            while let Some((_, pid)) = timer_map.pop_first() {
-                if let Some((next_time, _)) = timer_map.first_key_value() {
-                    // if *next_time < current_time {
-                    //     continue;
-                    // } else {
-                    //     set_timer(*next_time);
-                    // }
-                    set_timer(*next_time);
-                }
-                drop(timer_map);
-                if pid == 0 {
-                    set_next_trigger();
-                    // static mut CNT: u8 = 0;
-                    // unsafe {
-                    //     CNT += 1;
-                    //     if CNT > 200 {
-                    //         trace!("kernel tick");
-                    //         CNT = 0;
-                    //     }
-                    // }
-                    suspend_current_and_run_next();
-                } else if pid == current_task().unwrap().pid.0 {
-                    unsafe {
-                        sip::set_utimer();
-                    }
-                } else {
-                    let _ = push_trap_record(
-                        pid,
-                        UserTrapRecord {
-                            cause: 4,
-                            message: get_time_us(),
-                        },
-                    );
-                }
-                break;
+            if handle_timer() {
+                suspend_current_and_run_next();
             }
         }
         Trap::Interrupt(Interrupt::SupervisorExternal) => {
             // debug!("Supervisor External");
             plic::handle_external_interrupt(hart_id());
         }
+        Trap::Interrupt(Interrupt::SupervisorSoft) => {
+            if ipi::handle_ipi() {
+                suspend_current_and_run_next();
+            }
+        }
         _ => {
             error!(
                 "Unsupported trap {:?}, stval = {:#x}!",
@@ -141,8 +116,44 @@ pub fn trap_handler() -> ! {
     trap_return();
 }
 
+/// Deliver the due timers of this hart. Returns whether the scheduler tick fired,
+/// the caller decides about preemption.
+pub fn handle_timer() -> bool {
+    let current_pid = current_task().map(|task| task.pid.0);
+    let mut tick = false;
+    for target in timer::expire() {
+        match target {
+            TimerTarget::Tick => tick = true,
+            TimerTarget::Task(pid) if Some(pid) == current_pid => unsafe {
+                sip::set_utimer();
+            },
+            TimerTarget::Task(pid) => {
+                let _ = push_trap_record(
+                    pid,
+                    UserTrapRecord {
+                        cause: 4,
+                        message: get_time_us(),
+                    },
+                );
+            }
+        }
+    }
+    if tick {
+        // timers that smoltcp keeps, like retransmits, need polling too
+        crate::net::poll();
+        #[cfg(any(feature = "board_qemu", feature = "board_lrv"))]
+        crate::uart::poll_tx_drained();
+    }
+    tick
+}
+
 #[no_mangle]
 pub fn trap_return() -> ! {
+    if current_task().unwrap().acquire_inner_lock().killed {
+        // killed by the OOM policy
+        exit_current_and_run_next(-9);
+    }
+    handle_signals();
     unsafe {
         sstatus::clear_sie();
     }
@@ -175,22 +186,18 @@ pub extern "C" fn trap_from_kernel() {
     let sepc = sepc::read();
     let sstatus = sstatus::read();
     match scause.cause() {
-        // Trap::Interrupt(Interrupt::SupervisorTimer) => {
-        //     set_next_trigger();
-        //     // unsafe {
-        //     //     ebreak();
-        //     // }
-        //     suspend_current_and_run_next();
-        // }
-        // Trap::Interrupt(Interrupt::SupervisorExternal) => {
-        //     // debug!("Supervisor External");
-        //     unsafe {
-        //         ebreak();
-        //     }
-        //     plic::handle_external_interrupt();
-        // }
+        Trap::Interrupt(Interrupt::SupervisorTimer) => {
+            if handle_timer() {
+                preempt_kernel(sepc);
+            }
+        }
+        Trap::Interrupt(Interrupt::SupervisorExternal) => {
+            plic::handle_external_interrupt(hart_id());
+        }
         Trap::Interrupt(Interrupt::SupervisorSoft) => {
-            debug!("SupervisorSoft");
+            if ipi::handle_ipi() {
+                preempt_kernel(sepc);
+            }
         }
         _ => {
             error!(
@@ -205,6 +212,25 @@ pub extern "C" fn trap_from_kernel() {
     }
 }
 
+/// Yield the task whose syscall was interrupted. Kernel spin locks are all
+/// `IrqMutex`, so interrupts only come in with none held and switching away is
+/// safe. Traps taken by other tasks clobber sepc and sstatus, restore them
+/// before returning through `kernelret`.
+fn preempt_kernel(sepc: usize) {
+    if current_task().is_none() {
+        return;
+    }
+    let sstatus: usize;
+    unsafe {
+        asm!("csrr {}, sstatus", out(reg) sstatus);
+    }
+    preempt_current_and_run_next();
+    unsafe {
+        asm!("csrw sepc, {}", in(reg) sepc);
+        asm!("csrw sstatus, {}", in(reg) sstatus);
+    }
+}
+
 pub use context::TrapContext;
 pub use usertrap::{
     push_trap_record, UserTrapError, UserTrapInfo, UserTrapRecord, USER_EXT_INT_MAP,
@@ -39,9 +39,14 @@ __alltraps:
     ld t1, 36*8(sp)
     # move to kernel_sp
     ld sp, 35*8(sp)
-    # switch to kernel space
+    # switch to kernel space, no flush needed if the user space has its own asid
+    csrr t2, satp
+    slli t2, t2, 4
+    srli t2, t2, 48
     csrw satp, t0
+    bnez t2, 1f
     sfence.vma
+1:
     # jump to trap_handler
     jr t1
 
@@ -49,7 +54,11 @@ __restore:
     # a0: *TrapContext in user space(Constant); a1: user space token
     # switch to user space
     csrw satp, a1
+    slli t0, a1, 4
+    srli t0, t0, 48
+    bnez t0, 2f
     sfence.vma
+2:
     csrw sscratch, a0
     mv sp, a0
     # now sp points to TrapContext in user space, start restoring based on it
@@ -1,12 +1,12 @@
 const MAX_USER_TRAP_NUM: usize = 128;
 
-use crate::config::CPU_NUM;
+use crate::fdt::board;
 use crate::plic::Plic;
+use crate::sync::IrqMutex;
 use crate::task::hart_id;
 use crate::{mm::PhysPageNum, plic::get_context};
 use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
 use lazy_static::*;
-use spin::Mutex;
 
 #[derive(Clone)]
 pub struct UserTrapInfo {
@@ -52,7 +52,7 @@ impl UserTrapInfo {
     pub fn enable_user_ext_int(&self) {
         let u_context = get_context(hart_id(), 'U');
         for (device_id, is_enabled) in &self.devices {
-            for hart_id in 0..CPU_NUM {
+            for hart_id in 0..board().hart_count {
                 Plic::disable(get_context(hart_id, 'S'), *device_id);
             }
             if *is_enabled {
@@ -107,7 +107,7 @@ impl UserTrapInfo {
 
     pub fn remove_user_ext_int_map(&self) {
         let mut int_map = USER_EXT_INT_MAP.lock();
-        for hart_id in 0..CPU_NUM {
+        for hart_id in 0..board().hart_count {
             for (device_id, _) in &self.devices {
                 Plic::claim(get_context(hart_id, 'U'));
                 Plic::complete(get_context(hart_id, 'U'), *device_id);
@@ -120,8 +120,8 @@ impl UserTrapInfo {
 }
 
 lazy_static! {
-    pub static ref USER_EXT_INT_MAP: Arc<Mutex<BTreeMap<u16, usize>>> =
-        Arc::new(Mutex::new(BTreeMap::new()));
+    pub static ref USER_EXT_INT_MAP: Arc<IrqMutex<BTreeMap<u16, usize>>> =
+        Arc::new(IrqMutex::new(BTreeMap::new()));
 }
 
 pub fn push_trap_record(pid: usize, trap_record: UserTrapRecord) -> Result<usize, UserTrapError> {
@@ -0,0 +1,238 @@
+use super::{VirtQueue, VirtioMmio};
+use crate::block::{register_block_device, BlockDevice, BLOCK_SIZE};
+use crate::config::PAGE_SIZE;
+use crate::device::{register_device, ClaimPolicy, Device};
+use crate::errno::{EINVAL, EIO, ENODEV, ENOMEM, EROFS};
+use crate::mm::{frame_alloc, FrameTracker, PhysAddr};
+use crate::sync::IrqMutex;
+use crate::task::{
+    block_current_and_run_next, current_signal_pending, current_task, suspend_current_and_run_next,
+    WaitQueue,
+};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use core::ptr::read_volatile;
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_static::*;
+
+const QUEUE_SIZE: u16 = 16;
+
+const F_RO: u64 = 1 << 5;
+
+const BLK_T_IN: u32 = 0;
+const BLK_T_OUT: u32 = 1;
+const BLK_S_OK: u8 = 0;
+
+// where the parts of the request sit in the DMA frame
+const STATUS_OFFSET: usize = 16;
+const DATA_OFFSET: usize = BLOCK_SIZE;
+
+#[repr(C)]
+struct BlkReqHeader {
+    req_type: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+struct BlkQueue {
+    queue: VirtQueue,
+    done: bool,
+    /// Waiting for the request in flight, or for `busy` to clear.
+    waiters: WaitQueue,
+}
+
+impl BlkQueue {
+    /// Reap finished requests, returns whether the one in flight is done.
+    fn reap(&mut self) -> bool {
+        let mut reaped = false;
+        while self.queue.pop_used().is_some() {
+            reaped = true;
+        }
+        if reaped {
+            self.done = true;
+            self.waiters.wake_all();
+        }
+        self.done
+    }
+}
+
+pub struct VirtIOBlock {
+    mmio: VirtioMmio,
+    capacity: usize,
+    read_only: bool,
+    queue: IrqMutex<BlkQueue>,
+    /// Set while a request is in flight, which owns `dma`.
+    busy: AtomicBool,
+    dma: FrameTracker,
+}
+
+lazy_static! {
+    /// By irq, for the PLIC handler.
+    static ref VIRTIO_BLOCKS: IrqMutex<BTreeMap<u16, Arc<VirtIOBlock>>> =
+        IrqMutex::new(BTreeMap::new());
+}
+
+pub(super) fn init(mmio: VirtioMmio, irq: u16) {
+    let base = mmio.base();
+    match VirtIOBlock::new(mmio) {
+        Ok(blk) => {
+            let blk = Arc::new(blk);
+            info!(
+                "[virtio-blk] {:#x}: {} blocks{}",
+                base,
+                blk.capacity,
+                if blk.read_only { ", read-only" } else { "" }
+            );
+            VIRTIO_BLOCKS.lock().insert(irq, blk.clone());
+            register_device(Device {
+                name: "virtio-blk",
+                irq,
+                mmio: vec![(base, base + PAGE_SIZE)],
+                policy: ClaimPolicy::KernelOnly,
+                handler: Some(handle_irq),
+            });
+            register_block_device(blk);
+        }
+        Err(errno) => warn!("[virtio-blk] {:#x}: init failed: {}", base, errno),
+    }
+}
+
+fn handle_irq(irq: u16) {
+    let blk = VIRTIO_BLOCKS.lock().get(&irq).cloned();
+    if let Some(blk) = blk {
+        blk.mmio.ack_interrupt();
+        blk.queue.lock().reap();
+    }
+}
+
+/// Releases `busy` when dropped.
+struct InFlight<'a>(&'a VirtIOBlock);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.busy.store(false, Ordering::Release);
+        self.0.queue.lock().waiters.wake_all();
+    }
+}
+
+impl VirtIOBlock {
+    fn new(mmio: VirtioMmio) -> Result<Self, isize> {
+        let features = mmio.begin_init(F_RO)?;
+        let max = mmio.queue_max(0);
+        if max == 0 {
+            return Err(ENODEV);
+        }
+        // the largest power of two the device takes
+        let size = QUEUE_SIZE.min(1 << (15 - max.leading_zeros()));
+        let queue = VirtQueue::new(size)?;
+        mmio.setup_queue(0, &queue);
+        mmio.finish_init();
+        let capacity =
+            mmio.read_config::<u32>(0) as usize | (mmio.read_config::<u32>(4) as usize) << 32;
+        Ok(VirtIOBlock {
+            mmio,
+            capacity,
+            read_only: features & F_RO != 0,
+            queue: IrqMutex::new(BlkQueue {
+                queue,
+                done: false,
+                waiters: WaitQueue::new(),
+            }),
+            busy: AtomicBool::new(false),
+            dma: frame_alloc().ok_or(ENOMEM)?,
+        })
+    }
+
+    fn check(&self, block_id: usize, len: usize) -> Result<InFlight, isize> {
+        if block_id >= self.capacity || len != BLOCK_SIZE {
+            return Err(EINVAL);
+        }
+        self.wait_until(|_| {
+            self.busy
+                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        });
+        Ok(InFlight(self))
+    }
+
+    /// Sleeps until `ready` holds, it is checked under the queue lock. Spins
+    /// before there are tasks, and only yields while a signal is pending since
+    /// a request cannot be abandoned.
+    fn wait_until(&self, mut ready: impl FnMut(&mut BlkQueue) -> bool) {
+        loop {
+            let mut queue = self.queue.lock();
+            if ready(&mut *queue) {
+                return;
+            }
+            if current_task().is_none() {
+                drop(queue);
+                core::hint::spin_loop();
+            } else if current_signal_pending() {
+                queue.waiters.remove_current();
+                drop(queue);
+                suspend_current_and_run_next();
+            } else {
+                queue.waiters.add_current();
+                drop(queue);
+                block_current_and_run_next();
+            }
+        }
+    }
+
+    /// Send the request staged in `dma` and wait for it, with `busy` held.
+    fn submit(&self, req_type: u32, block_id: usize) -> Result<(), isize> {
+        let base = PhysAddr::from(self.dma.ppn).0;
+        *self.dma.ppn.get_mut::<BlkReqHeader>() = BlkReqHeader {
+            req_type,
+            reserved: 0,
+            sector: block_id as u64,
+        };
+        let status = &mut self.dma.ppn.get_bytes_array()[STATUS_OFFSET];
+        *status = 0xff;
+        {
+            let mut queue = self.queue.lock();
+            queue.done = false;
+            queue
+                .queue
+                .add(&[
+                    (base, 16, false),
+                    (base + DATA_OFFSET, BLOCK_SIZE, req_type == BLK_T_IN),
+                    (base + STATUS_OFFSET, 1, true),
+                ])
+                .ok_or(EIO)?;
+        }
+        self.mmio.notify(0);
+        // reaping here too covers interrupts being off
+        self.wait_until(BlkQueue::reap);
+        match unsafe { read_volatile(status) } {
+            BLK_S_OK => Ok(()),
+            s => {
+                warn!("[virtio-blk] block {}: status {}", block_id, s);
+                Err(EIO)
+            }
+        }
+    }
+}
+
+impl BlockDevice for VirtIOBlock {
+    fn num_blocks(&self) -> usize {
+        self.capacity
+    }
+
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), isize> {
+        let _in_flight = self.check(block_id, buf.len())?;
+        self.submit(BLK_T_IN, block_id)?;
+        buf.copy_from_slice(&self.dma.ppn.get_bytes_array()[DATA_OFFSET..][..BLOCK_SIZE]);
+        Ok(())
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), isize> {
+        if self.read_only {
+            return Err(EROFS);
+        }
+        let _in_flight = self.check(block_id, buf.len())?;
+        self.dma.ppn.get_bytes_array()[DATA_OFFSET..][..BLOCK_SIZE].copy_from_slice(buf);
+        self.submit(BLK_T_OUT, block_id)
+    }
+}
@@ -0,0 +1,185 @@
+//! virtio-mmio devices, both the legacy (version 1) and the modern (version 2)
+//! register layout, with interrupts delivered through the PLIC.
+
+mod blk;
+mod net;
+mod queue;
+
+pub use net::{net_device, RxBuffer, VirtIONet, MAX_FRAME_LEN};
+use queue::VirtQueue;
+
+use crate::config::PAGE_SIZE;
+use crate::errno::ENODEV;
+use crate::fdt::board;
+use core::ptr::{read_volatile, write_volatile};
+
+const MAGIC: u32 = 0x7472_6976;
+
+const MAGIC_VALUE: usize = 0x000;
+const VERSION: usize = 0x004;
+const DEVICE_ID: usize = 0x008;
+const DEVICE_FEATURES: usize = 0x010;
+const DEVICE_FEATURES_SEL: usize = 0x014;
+const DRIVER_FEATURES: usize = 0x020;
+const DRIVER_FEATURES_SEL: usize = 0x024;
+const GUEST_PAGE_SIZE: usize = 0x028;
+const QUEUE_SEL: usize = 0x030;
+const QUEUE_NUM_MAX: usize = 0x034;
+const QUEUE_NUM: usize = 0x038;
+const QUEUE_ALIGN: usize = 0x03c;
+const QUEUE_PFN: usize = 0x040;
+const QUEUE_READY: usize = 0x044;
+const QUEUE_NOTIFY: usize = 0x050;
+const INTERRUPT_STATUS: usize = 0x060;
+const INTERRUPT_ACK: usize = 0x064;
+const STATUS: usize = 0x070;
+const QUEUE_DESC_LOW: usize = 0x080;
+const QUEUE_DESC_HIGH: usize = 0x084;
+const QUEUE_DRIVER_LOW: usize = 0x090;
+const QUEUE_DRIVER_HIGH: usize = 0x094;
+const QUEUE_DEVICE_LOW: usize = 0x0a0;
+const QUEUE_DEVICE_HIGH: usize = 0x0a4;
+const CONFIG: usize = 0x100;
+
+const STATUS_ACKNOWLEDGE: u32 = 1;
+const STATUS_DRIVER: u32 = 2;
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_FEATURES_OK: u32 = 8;
+const STATUS_FAILED: u32 = 128;
+
+const F_VERSION_1: u64 = 1 << 32;
+
+const DEVICE_NET: u32 = 1;
+const DEVICE_BLOCK: u32 = 2;
+
+pub struct VirtioMmio {
+    base: usize,
+    version: u32,
+}
+
+impl VirtioMmio {
+    /// QEMU fills unused slots with device id 0.
+    pub fn probe(base: usize) -> Option<Self> {
+        let mmio = VirtioMmio { base, version: 0 };
+        if mmio.read(MAGIC_VALUE) != MAGIC || mmio.read(DEVICE_ID) == 0 {
+            return None;
+        }
+        match mmio.read(VERSION) {
+            version @ 1..=2 => Some(VirtioMmio { base, version }),
+            version => {
+                warn!("[virtio] {:#x}: unknown version {}", base, version);
+                None
+            }
+        }
+    }
+
+    fn read(&self, reg: usize) -> u32 {
+        unsafe { read_volatile((self.base + reg) as *const u32) }
+    }
+
+    fn write(&self, reg: usize, value: u32) {
+        unsafe { write_volatile((self.base + reg) as *mut u32, value) }
+    }
+
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    pub fn device_id(&self) -> u32 {
+        self.read(DEVICE_ID)
+    }
+
+    /// Device specific configuration, read with accesses of `T`'s size.
+    pub fn read_config<T: Copy>(&self, offset: usize) -> T {
+        unsafe { read_volatile((self.base + CONFIG + offset) as *const T) }
+    }
+
+    /// Reset the device and accept the offered features within `supported`.
+    pub fn begin_init(&self, supported: u64) -> Result<u64, isize> {
+        self.write(STATUS, 0);
+        self.write(STATUS, STATUS_ACKNOWLEDGE);
+        self.write(STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        self.write(DEVICE_FEATURES_SEL, 0);
+        let mut offered = self.read(DEVICE_FEATURES) as u64;
+        if self.version == 2 {
+            self.write(DEVICE_FEATURES_SEL, 1);
+            offered |= (self.read(DEVICE_FEATURES) as u64) << 32;
+        }
+        let mut features = offered & supported;
+        if self.version == 2 {
+            features |= F_VERSION_1;
+        }
+        self.write(DRIVER_FEATURES_SEL, 0);
+        self.write(DRIVER_FEATURES, features as u32);
+        if self.version == 2 {
+            self.write(DRIVER_FEATURES_SEL, 1);
+            self.write(DRIVER_FEATURES, (features >> 32) as u32);
+            let status = STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK;
+            self.write(STATUS, status);
+            if self.read(STATUS) & STATUS_FEATURES_OK == 0 {
+                self.write(STATUS, STATUS_FAILED);
+                return Err(ENODEV);
+            }
+        } else {
+            self.write(GUEST_PAGE_SIZE, PAGE_SIZE as u32);
+        }
+        Ok(features)
+    }
+
+    pub fn queue_max(&self, index: u32) -> u16 {
+        self.write(QUEUE_SEL, index);
+        self.read(QUEUE_NUM_MAX) as u16
+    }
+
+    pub fn setup_queue(&self, index: u32, queue: &VirtQueue) {
+        self.write(QUEUE_SEL, index);
+        self.write(QUEUE_NUM, queue.size() as u32);
+        if self.version == 2 {
+            let halves = |addr: usize| (addr as u32, (addr >> 32) as u32);
+            let (low, high) = halves(queue.desc_addr());
+            self.write(QUEUE_DESC_LOW, low);
+            self.write(QUEUE_DESC_HIGH, high);
+            let (low, high) = halves(queue.avail_addr());
+            self.write(QUEUE_DRIVER_LOW, low);
+            self.write(QUEUE_DRIVER_HIGH, high);
+            let (low, high) = halves(queue.used_addr());
+            self.write(QUEUE_DEVICE_LOW, low);
+            self.write(QUEUE_DEVICE_HIGH, high);
+            self.write(QUEUE_READY, 1);
+        } else {
+            self.write(QUEUE_ALIGN, PAGE_SIZE as u32);
+            self.write(QUEUE_PFN, (queue.desc_addr() / PAGE_SIZE) as u32);
+        }
+    }
+
+    pub fn finish_init(&self) {
+        let status = self.read(STATUS);
+        self.write(STATUS, status | STATUS_DRIVER_OK);
+    }
+
+    pub fn notify(&self, index: u32) {
+        self.write(QUEUE_NOTIFY, index);
+    }
+
+    /// Acknowledge whatever raised the interrupt, returns the status bits.
+    pub fn ack_interrupt(&self) -> u32 {
+        let status = self.read(INTERRUPT_STATUS);
+        self.write(INTERRUPT_ACK, status);
+        status
+    }
+}
+
+/// Probe the virtio-mmio slots from the device tree and bring up what we drive.
+pub fn init() {
+    for slot in board().virtio.iter() {
+        let mmio = match VirtioMmio::probe(slot.base) {
+            Some(mmio) => mmio,
+            None => continue,
+        };
+        match mmio.device_id() {
+            DEVICE_NET => net::init(mmio, slot.irq),
+            DEVICE_BLOCK => blk::init(mmio, slot.irq),
+            id => debug!("[virtio] {:#x}: device {} not supported", slot.base, id),
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use super::{VirtQueue, VirtioMmio, F_VERSION_1};
+use crate::config::PAGE_SIZE;
+use crate::device::{register_device, ClaimPolicy, Device};
+use crate::errno::{ENODEV, ENOMEM};
+use crate::mm::{frame_alloc, FrameTracker, PhysAddr};
+use crate::sync::IrqMutex;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::slice;
+use lazy_static::*;
+
+const QUEUE_SIZE: u16 = 16;
+
+const F_MAC: u64 = 1 << 5;
+
+const RX_QUEUE: u32 = 0;
+const TX_QUEUE: u32 = 1;
+
+/// Each descriptor gets one buffer, two of them per frame.
+const BUF_SIZE: usize = PAGE_SIZE / 2;
+/// Ethernet frame without FCS.
+pub const MAX_FRAME_LEN: usize = 1514;
+
+/// A filled receive buffer, handed back with `recycle_rx`.
+pub struct RxBuffer {
+    addr: usize,
+    len: usize,
+}
+
+impl RxBuffer {
+    pub fn frame(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.addr as *mut u8, self.len) }
+    }
+}
+
+struct NetQueues {
+    rx: VirtQueue,
+    tx: VirtQueue,
+    /// The buffer behind each descriptor head given to the device.
+    rx_posted: BTreeMap<u16, usize>,
+    tx_posted: BTreeMap<u16, usize>,
+    /// Received frames, past the virtio header.
+    rx_ready: VecDeque<RxBuffer>,
+    tx_free: Vec<usize>,
+    _frames: Vec<FrameTracker>,
+}
+
+pub struct VirtIONet {
+    mmio: VirtioMmio,
+    mac: [u8; 6],
+    header_len: usize,
+    queues: IrqMutex<NetQueues>,
+}
+
+lazy_static! {
+    static ref VIRTIO_NET: IrqMutex<Option<(u16, Arc<VirtIONet>)>> = IrqMutex::new(None);
+}
+
+/// The first NIC, the only one the network stack uses.
+pub fn net_device() -> Option<Arc<VirtIONet>> {
+    VIRTIO_NET.lock().as_ref().map(|(_, net)| net.clone())
+}
+
+pub(super) fn init(mmio: VirtioMmio, irq: u16) {
+    let base = mmio.base();
+    if VIRTIO_NET.lock().is_some() {
+        info!("[virtio-net] {:#x}: only one NIC is supported", base);
+        return;
+    }
+    match VirtIONet::new(mmio) {
+        Ok(net) => {
+            info!("[virtio-net] {:#x}: mac {:02x?}", base, net.mac);
+            *VIRTIO_NET.lock() = Some((irq, Arc::new(net)));
+            register_device(Device {
+                name: "virtio-net",
+                irq,
+                mmio: vec![(base, base + PAGE_SIZE)],
+                policy: ClaimPolicy::KernelOnly,
+                handler: Some(handle_irq),
+            });
+        }
+        Err(errno) => warn!("[virtio-net] {:#x}: init failed: {}", base, errno),
+    }
+}
+
+fn handle_irq(_irq: u16) {
+    if let Some(net) = net_device() {
+        net.mmio.ack_interrupt();
+        net.reap();
+        crate::net::poll();
+    }
+}
+
+impl VirtIONet {
+    fn new(mmio: VirtioMmio) -> Result<Self, isize> {
+        let features = mmio.begin_init(F_MAC)?;
+        // legacy devices leave out `num_buffers` without MRG_RXBUF
+        let header_len = if features & F_VERSION_1 != 0 { 12 } else { 10 };
+        let mut mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+        if features & F_MAC != 0 {
+            for (i, byte) in mac.iter_mut().enumerate() {
+                *byte = mmio.read_config::<u8>(i);
+            }
+        }
+        let size = QUEUE_SIZE
+            .min(mmio.queue_max(RX_QUEUE))
+            .min(mmio.queue_max(TX_QUEUE));
+        if size == 0 {
+            return Err(ENODEV);
+        }
+        let size = 1 << (15 - size.leading_zeros());
+        let rx = VirtQueue::new(size)?;
+        let tx = VirtQueue::new(size)?;
+        mmio.setup_queue(RX_QUEUE, &rx);
+        mmio.setup_queue(TX_QUEUE, &tx);
+
+        let mut frames = Vec::new();
+        let mut buffers = Vec::new();
+        for _ in 0..size {
+            let frame = frame_alloc().ok_or(ENOMEM)?;
+            let base = PhysAddr::from(frame.ppn).0;
+            buffers.push(base);
+            buffers.push(base + BUF_SIZE);
+            frames.push(frame);
+        }
+        let mut queues = NetQueues {
+            rx,
+            tx,
+            rx_posted: BTreeMap::new(),
+            tx_posted: BTreeMap::new(),
+            rx_ready: VecDeque::new(),
+            tx_free: buffers.split_off(size as usize),
+            _frames: frames,
+        };
+        for addr in buffers {
+            queues.post_rx(addr);
+        }
+        let net = VirtIONet {
+            mmio,
+            mac,
+            header_len,
+            queues: IrqMutex::new(queues),
+        };
+        net.mmio.finish_init();
+        net.mmio.notify(RX_QUEUE);
+        Ok(net)
+    }
+
+    pub fn mac(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    /// Collect what the device is done with, the PLIC handler does this too.
+    fn reap(&self) {
+        let mut queues = self.queues.lock();
+        while let Some((head, len)) = queues.rx.pop_used() {
+            let addr = queues.rx_posted.remove(&head).unwrap();
+            let len = (len as usize).saturating_sub(self.header_len);
+            queues.rx_ready.push_back(RxBuffer {
+                addr: addr + self.header_len,
+                len,
+            });
+        }
+        while let Some((head, _)) = queues.tx.pop_used() {
+            let addr = queues.tx_posted.remove(&head).unwrap();
+            queues.tx_free.push(addr);
+        }
+    }
+
+    pub fn take_rx(&self) -> Option<RxBuffer> {
+        self.reap();
+        self.queues.lock().rx_ready.pop_front()
+    }
+
+    pub fn recycle_rx(&self, buffer: RxBuffer) {
+        self.queues.lock().post_rx(buffer.addr - self.header_len);
+        self.mmio.notify(RX_QUEUE);
+    }
+
+    pub fn can_transmit(&self) -> bool {
+        self.reap();
+        !self.queues.lock().tx_free.is_empty()
+    }
+
+    /// Let `f` fill a frame of `len` bytes and send it, `None` if no buffer is free.
+    pub fn transmit<R>(&self, len: usize, f: impl FnOnce(&mut [u8]) -> R) -> Option<R> {
+        assert!(len <= MAX_FRAME_LEN);
+        self.reap();
+        let addr = self.queues.lock().tx_free.pop()?;
+        let buffer = unsafe { slice::from_raw_parts_mut(addr as *mut u8, self.header_len + len) };
+        buffer[..self.header_len].fill(0);
+        let result = f(&mut buffer[self.header_len..]);
+        {
+            let mut queues = self.queues.lock();
+            let head = queues
+                .tx
+                .add(&[(addr, self.header_len + len, false)])
+                .unwrap();
+            queues.tx_posted.insert(head, addr);
+        }
+        self.mmio.notify(TX_QUEUE);
+        Some(result)
+    }
+}
+
+impl NetQueues {
+    fn post_rx(&mut self, addr: usize) {
+        // as many buffers as descriptors, so this cannot fail
+        let head = self.rx.add(&[(addr, BUF_SIZE, true)]).unwrap();
+        self.rx_posted.insert(head, addr);
+    }
+}
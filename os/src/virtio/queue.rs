@@ -0,0 +1,148 @@
+use crate::config::PAGE_SIZE;
+use crate::errno::ENOMEM;
+use crate::mm::{frame_alloc_contiguous, FrameTracker, PhysAddr};
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// A split virtqueue in physically contiguous frames, laid out as the legacy
+/// interface wants it: descriptors, then the available ring, then the used
+/// ring on the next page boundary. Kernel memory is identity mapped, so the
+/// addresses handed to the device are also the ones we dereference.
+pub struct VirtQueue {
+    _frames: Vec<FrameTracker>,
+    size: u16,
+    desc: usize,
+    avail: usize,
+    used: usize,
+    free_head: u16,
+    num_free: u16,
+    avail_idx: u16,
+    last_used_idx: u16,
+}
+
+// only touched under the owning driver's lock
+unsafe impl Send for VirtQueue {}
+
+fn align_up(addr: usize) -> usize {
+    (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+impl VirtQueue {
+    /// `size` must be a power of two no larger than the device's maximum.
+    pub fn new(size: u16) -> Result<Self, isize> {
+        assert!(size.is_power_of_two());
+        let n = size as usize;
+        let used_offset = align_up(16 * n + 6 + 2 * n);
+        let pages = align_up(used_offset + 6 + 8 * n) / PAGE_SIZE;
+        let frames = frame_alloc_contiguous(pages, 1).ok_or(ENOMEM)?;
+        let base = PhysAddr::from(frames[0].ppn).0;
+        let mut queue = VirtQueue {
+            _frames: frames,
+            size,
+            desc: base,
+            avail: base + 16 * n,
+            used: base + used_offset,
+            free_head: 0,
+            num_free: size,
+            avail_idx: 0,
+            last_used_idx: 0,
+        };
+        for i in 0..size - 1 {
+            queue.desc(i).next = i + 1;
+        }
+        Ok(queue)
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+    pub fn desc_addr(&self) -> usize {
+        self.desc
+    }
+    pub fn avail_addr(&self) -> usize {
+        self.avail
+    }
+    pub fn used_addr(&self) -> usize {
+        self.used
+    }
+
+    fn desc(&mut self, i: u16) -> &mut Descriptor {
+        unsafe { &mut *((self.desc + 16 * i as usize) as *mut Descriptor) }
+    }
+
+    /// Chain `buffers` of `(phys_addr, len, device_writable)` and make them
+    /// available, returns the head to match with `pop_used`.
+    pub fn add(&mut self, buffers: &[(usize, usize, bool)]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.num_free as usize {
+            return None;
+        }
+        let head = self.free_head;
+        for (i, (addr, len, writable)) in buffers.iter().enumerate() {
+            let id = self.free_head;
+            let desc = self.desc(id);
+            desc.addr = *addr as u64;
+            desc.len = *len as u32;
+            desc.flags = if *writable { DESC_F_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                desc.flags |= DESC_F_NEXT;
+            }
+            self.free_head = desc.next;
+        }
+        self.num_free -= buffers.len() as u16;
+        let slot = (self.avail_idx % self.size) as usize;
+        unsafe {
+            write_volatile((self.avail + 4 + 2 * slot) as *mut u16, head);
+            // descriptors and ring entry before the index the device polls
+            asm!("fence iorw,iorw");
+            self.avail_idx = self.avail_idx.wrapping_add(1);
+            write_volatile((self.avail + 2) as *mut u16, self.avail_idx);
+            asm!("fence iorw,iorw");
+        }
+        Some(head)
+    }
+
+    /// Take one finished chain, returns its head and the bytes written.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used_idx = unsafe { read_volatile((self.used + 2) as *const u16) };
+        if used_idx == self.last_used_idx {
+            return None;
+        }
+        unsafe {
+            asm!("fence iorw,iorw");
+        }
+        let slot = (self.last_used_idx % self.size) as usize;
+        let elem = self.used + 4 + 8 * slot;
+        let (head, len) = unsafe {
+            (
+                read_volatile(elem as *const u32) as u16,
+                read_volatile((elem + 4) as *const u32),
+            )
+        };
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+        // give the chain back to the free list
+        let free_head = self.free_head;
+        let mut id = head;
+        loop {
+            self.num_free += 1;
+            let desc = self.desc(id);
+            if desc.flags & DESC_F_NEXT == 0 {
+                desc.next = free_head;
+                break;
+            }
+            id = desc.next;
+        }
+        self.free_head = head;
+        Some((head, len))
+    }
+}
@@ -0,0 +1,114 @@
+//! Packs the user apps into the read-only image the kernel mounts from its
+//! first virtio block device, see `os/src/fs/packfs.rs` for the layout.
+//!
+//! usage: packfs <image> <app src dir> <app elf dir> [extra files...]
+
+use std::env;
+use std::fs::{read, read_dir, File};
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::Path;
+use std::process::exit;
+
+const BLOCK_SIZE: usize = 512;
+/// "RNFS"
+const MAGIC: u32 = 0x5346_4e52;
+const VERSION: u32 = 1;
+const NAME_LEN: usize = 48;
+const ENTRY_SIZE: usize = 64;
+
+struct Entry {
+    name: String,
+    data: Vec<u8>,
+}
+
+fn blocks(len: usize) -> usize {
+    len.div_ceil(BLOCK_SIZE)
+}
+
+/// Every app under `src_dir` as the ELF of the same name in `elf_dir`.
+fn apps(src_dir: &str, elf_dir: &str) -> Result<Vec<Entry>> {
+    let mut names: Vec<_> = read_dir(src_dir)?
+        .map(|dir_entry| {
+            let path = dir_entry?.path();
+            Ok(path.file_stem().unwrap().to_string_lossy().into_owned())
+        })
+        .collect::<Result<_>>()?;
+    names.sort();
+    names
+        .into_iter()
+        .map(|name| {
+            let data = read(Path::new(elf_dir).join(&name))?;
+            Ok(Entry { name, data })
+        })
+        .collect()
+}
+
+fn file(path: &str) -> Result<Entry> {
+    let name = Path::new(path)
+        .file_name()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, path))?
+        .to_string_lossy()
+        .into_owned();
+    Ok(Entry {
+        name,
+        data: read(path)?,
+    })
+}
+
+fn pack(entries: &[Entry]) -> Result<Vec<u8>> {
+    let dir_blocks = blocks(entries.len() * ENTRY_SIZE);
+    let mut next = 1 + dir_blocks;
+    let mut dir = Vec::with_capacity(dir_blocks * BLOCK_SIZE);
+    for entry in entries {
+        if entry.name.len() >= NAME_LEN {
+            let message = format!("name too long: {}", entry.name);
+            return Err(Error::new(ErrorKind::InvalidInput, message));
+        }
+        let mut name = [0u8; NAME_LEN];
+        name[..entry.name.len()].copy_from_slice(entry.name.as_bytes());
+        dir.extend_from_slice(&name);
+        dir.extend_from_slice(&(next as u64).to_le_bytes());
+        dir.extend_from_slice(&(entry.data.len() as u64).to_le_bytes());
+        next += blocks(entry.data.len());
+    }
+
+    let mut image = Vec::with_capacity(next * BLOCK_SIZE);
+    image.extend_from_slice(&MAGIC.to_le_bytes());
+    image.extend_from_slice(&VERSION.to_le_bytes());
+    image.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+    image.extend_from_slice(&(next as u32).to_le_bytes());
+    image.resize(BLOCK_SIZE, 0);
+    image.extend_from_slice(&dir);
+    for entry in entries {
+        image.resize(blocks(image.len()) * BLOCK_SIZE, 0);
+        image.extend_from_slice(&entry.data);
+    }
+    image.resize(next * BLOCK_SIZE, 0);
+    Ok(image)
+}
+
+fn run(args: &[String]) -> Result<()> {
+    let mut entries = apps(&args[1], &args[2])?;
+    for path in &args[3..] {
+        entries.push(file(path)?);
+    }
+    let image = pack(&entries)?;
+    File::create(&args[0])?.write_all(&image)?;
+    for entry in &entries {
+        println!("{:<24} {:>8} bytes", entry.name, entry.data.len());
+    }
+    println!("{}: {} blocks", args[0], image.len() / BLOCK_SIZE);
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() < 3 {
+        eprintln!("usage: packfs <image> <app src dir> <app elf dir> [extra files...]");
+        exit(2);
+    }
+    if let Err(e) = run(&args) {
+        eprintln!("packfs: {}", e);
+        exit(1);
+    }
+}
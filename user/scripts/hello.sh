@@ -0,0 +1,3 @@
+#!/sh
+# exec'd as /hello.sh, the kernel starts `sh /hello.sh` for it
+hello_world
@@ -6,7 +6,10 @@ use core::task::{Context, Poll};
 use spin::Mutex;
 
 use crate::async_rt::{TaskId, UserTask, REACTOR};
-use crate::syscall::{syscall, SYSCALL_CLOSE, SYSCALL_PIPE, SYSCALL_READ, SYSCALL_WRITE};
+use crate::serial::SERIAL_NOTIFY_TX;
+use crate::syscall::{
+    syscall, SYSCALL_CLOSE, SYSCALL_IOCTL, SYSCALL_PIPE, SYSCALL_READ, SYSCALL_WRITE,
+};
 
 pub struct AsyncClose {
     first: bool,
@@ -124,6 +127,47 @@ impl Future for AsyncWrite {
     }
 }
 
+/// Done once a serial port sent everything written before, while
+/// `AsyncWrite` is done as soon as the kernel queued the bytes.
+pub struct AsyncDrain {
+    first: bool,
+    task_id: usize,
+    fd: usize,
+}
+
+impl AsyncDrain {
+    pub fn new(fd: usize) -> UserTask {
+        let id = TaskId::generate();
+        let future = AsyncDrain {
+            first: true,
+            task_id: id.into(),
+            fd,
+        };
+        UserTask {
+            id,
+            future: Mutex::new(Box::pin(future)),
+            reactor: REACTOR.clone(),
+        }
+    }
+}
+
+impl Future for AsyncDrain {
+    type Output = isize;
+    fn poll(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.first {
+            self.first = false;
+            // the message the kernel completes async syscalls with
+            let message = self.task_id + 1 + usize::MAX / 2;
+            match syscall(SYSCALL_IOCTL, [self.fd, SERIAL_NOTIFY_TX, message, 0]) {
+                ret if ret < 0 => Poll::Ready(ret),
+                _ => Poll::Pending,
+            }
+        } else {
+            Poll::Ready(0)
+        }
+    }
+}
+
 pub struct AsyncPipe {
     first: bool,
     task_id: usize,
@@ -1,4 +1,4 @@
-use crate::async_rt::{AsyncClose, AsyncPipe, AsyncRead, AsyncWrite};
+use crate::async_rt::{AsyncClose, AsyncDrain, AsyncPipe, AsyncRead, AsyncWrite};
 
 pub async fn sys_close(fd: usize) -> isize {
     AsyncClose::new(fd).await
@@ -14,4 +14,9 @@ pub async fn sys_read(fd: usize, buffer: &mut [u8]) -> isize {
 
 pub async fn sys_write(fd: usize, buffer: &[u8]) -> isize {
     AsyncWrite::new(fd, buffer).await
-}
\ No newline at end of file
+}
+
+/// After `sys_write` on a serial port, for when the bytes are really out.
+pub async fn sys_drain(fd: usize) -> isize {
+    AsyncDrain::new(fd).await
+}
@@ -0,0 +1,45 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+extern crate alloc;
+
+use alloc::string::String;
+use user_lib::{close, open, read, write, OpenFlags};
+
+/// Copies each file, or stdin without any, to stdout.
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    if argc < 2 {
+        return copy(0);
+    }
+    for path in &argv[1..] {
+        let mut path = String::from(*path);
+        path.push('\0');
+        let fd = open(path.as_str(), OpenFlags::RDONLY);
+        if fd < 0 {
+            println!("cat: cannot open {}: {}", path.trim_end_matches('\0'), fd);
+            return -1;
+        }
+        let exit_code = copy(fd as usize);
+        close(fd as usize);
+        if exit_code != 0 {
+            return exit_code;
+        }
+    }
+    0
+}
+
+fn copy(fd: usize) -> i32 {
+    let mut buf = [0u8; 512];
+    loop {
+        match read(fd, &mut buf) {
+            0 => return 0,
+            n if n < 0 => return -1,
+            n => {
+                write(1, &buf[..n as usize]);
+            }
+        }
+    }
+}
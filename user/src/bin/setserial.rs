@@ -0,0 +1,95 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+extern crate alloc;
+
+use alloc::string::String;
+use user_lib::serial::*;
+use user_lib::{close, open, OpenFlags};
+
+/// `setserial /dev/ttyS2 [baud N] [bits N] [parity none|odd|even] [stop N]
+/// [flow none|rtscts] [trigger N]` changes the given settings, then prints
+/// them all.
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    if argc < 2 || argc % 2 != 0 {
+        println!("usage: setserial /dev/ttySn [setting value]...");
+        return -1;
+    }
+    let mut path = String::from(argv[1]);
+    path.push('\0');
+    let fd = open(path.as_str(), OpenFlags::RDWR);
+    if fd < 0 {
+        println!("setserial: cannot open {}: {}", argv[1], fd);
+        return -1;
+    }
+    let fd = fd as usize;
+    let exit_code = run(fd, &argv[2..]);
+    close(fd);
+    exit_code
+}
+
+fn run(fd: usize, settings: &[&str]) -> i32 {
+    let mut config = SerialConfig::default();
+    if get_serial_config(fd, &mut config) < 0 {
+        println!("setserial: not a serial port");
+        return -1;
+    }
+    if !settings.is_empty() {
+        for pair in settings.chunks(2) {
+            if let Err(message) = apply(&mut config, pair[0], pair[1]) {
+                println!("setserial: {}", message);
+                return -1;
+            }
+        }
+        let ret = set_serial_config(fd, &config);
+        if ret < 0 {
+            println!("setserial: rejected: {}", ret);
+            return -1;
+        }
+    }
+    let parity = match config.parity {
+        PARITY_ODD => 'O',
+        PARITY_EVEN => 'E',
+        _ => 'N',
+    };
+    let flow = if config.flow_control == FLOW_RTS_CTS {
+        "rtscts"
+    } else {
+        "none"
+    };
+    println!(
+        "{} {}{}{} flow {} trigger {}",
+        config.baud_rate, config.data_bits, parity, config.stop_bits, flow, config.rx_trigger
+    );
+    0
+}
+
+fn apply(config: &mut SerialConfig, name: &str, value: &str) -> Result<(), &'static str> {
+    let number = || value.parse::<u32>().map_err(|_| "not a number");
+    match name {
+        "baud" => config.baud_rate = number()?,
+        "bits" => config.data_bits = number()? as u8,
+        "stop" => config.stop_bits = number()? as u8,
+        "trigger" => config.rx_trigger = number()? as u8,
+        "parity" => {
+            config.parity = match value {
+                "none" => PARITY_NONE,
+                "odd" => PARITY_ODD,
+                "even" => PARITY_EVEN,
+                _ => return Err("parity is none, odd or even"),
+            }
+        }
+        "flow" => {
+            config.flow_control = match value {
+                "none" => FLOW_NONE,
+                "rtscts" => FLOW_RTS_CTS,
+                _ => return Err("flow is none or rtscts"),
+            }
+        }
+        _ => return Err("unknown setting"),
+    }
+    Ok(())
+}
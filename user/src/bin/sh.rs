@@ -0,0 +1,92 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use user_lib::{close, exec, exit, fork, open, read, waitpid, OpenFlags};
+
+/// Runs a script one command per line, blank lines and `#` comments skipped.
+/// Stops at the first command that fails. Scripts starting with `#!/sh` can
+/// be exec'd directly, the kernel runs `sh <script> [args...]` for them.
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    if argc < 2 {
+        println!("usage: sh <script>");
+        return -1;
+    }
+    let script = match read_script(argv[1]) {
+        Some(script) => script,
+        None => {
+            println!("[sh] cannot read {}", argv[1]);
+            return -1;
+        }
+    };
+    for (number, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let exit_code = run(line);
+        if exit_code != 0 {
+            println!(
+                "[sh] {}:{}: `{}` exited with {}",
+                argv[1],
+                number + 1,
+                line,
+                exit_code
+            );
+            return exit_code;
+        }
+    }
+    0
+}
+
+fn read_script(path: &str) -> Option<String> {
+    let mut path = String::from(path);
+    path.push('\0');
+    let fd = open(path.as_str(), OpenFlags::RDONLY);
+    if fd < 0 {
+        return None;
+    }
+    let fd = fd as usize;
+    let mut script = Vec::new();
+    let mut buf = [0u8; 512];
+    loop {
+        match read(fd, &mut buf) {
+            0 => break,
+            n if n < 0 => {
+                close(fd);
+                return None;
+            }
+            n => script.extend_from_slice(&buf[..n as usize]),
+        }
+    }
+    close(fd);
+    String::from_utf8(script).ok()
+}
+
+fn run(line: &str) -> i32 {
+    let args: Vec<String> = line
+        .split_whitespace()
+        .map(|arg| {
+            let mut arg = String::from(arg);
+            arg.push('\0');
+            arg
+        })
+        .collect();
+    let mut args_addr: Vec<*const u8> = args.iter().map(|arg| arg.as_ptr()).collect();
+    args_addr.push(core::ptr::null());
+    let pid = fork();
+    if pid == 0 {
+        exec(args[0].as_str(), args_addr.as_slice());
+        println!("[sh] cannot exec {}", line);
+        exit(-4);
+    }
+    let mut exit_code: i32 = 0;
+    waitpid(pid as usize, &mut exit_code);
+    exit_code
+}
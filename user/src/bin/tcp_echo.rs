@@ -0,0 +1,46 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{accept, bind, close, listen, read, socket, write, SOCK_STREAM};
+
+const PORT: u16 = 5555;
+
+/// Echo each connection back, try `nc localhost 5555` on the host.
+#[no_mangle]
+pub fn main() -> i32 {
+    let fd = socket(SOCK_STREAM);
+    if fd < 0 {
+        println!("[tcp echo] no network: {}", fd);
+        return -1;
+    }
+    let fd = fd as usize;
+    if bind(fd, PORT) < 0 || listen(fd) < 0 {
+        println!("[tcp echo] cannot listen on {}", PORT);
+        return -1;
+    }
+    println!("[tcp echo] listening on {}", PORT);
+    loop {
+        let conn = accept(fd);
+        if conn < 0 {
+            println!("[tcp echo] accept failed: {}", conn);
+            return -1;
+        }
+        let conn = conn as usize;
+        println!("[tcp echo] connection fd {}", conn);
+        let mut buf = [0u8; 512];
+        loop {
+            let n = read(conn, &mut buf);
+            if n <= 0 {
+                break;
+            }
+            if write(conn, &buf[..n as usize]) < 0 {
+                break;
+            }
+        }
+        close(conn);
+        println!("[tcp echo] connection closed");
+    }
+}
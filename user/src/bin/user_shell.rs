@@ -0,0 +1,518 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+extern crate alloc;
+
+const LF: u8 = 0x0au8;
+const CR: u8 = 0x0du8;
+const DL: u8 = 0x7fu8;
+const BS: u8 = 0x08u8;
+const ESC: u8 = 0x1bu8;
+const CTRL_C: u8 = 0x03u8;
+
+const HISTORY_LEN: usize = 32;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use user_lib::console::getchar;
+use user_lib::termios::{tcgetattr, tcsetattr, tcsetpgrp, tcsetsid, Termios};
+use user_lib::{
+    close, dup, exec, exit, fork, getpgid, kill, open, pipe, setpgid, setsid, stop_signal,
+    try_waitpid, yield_, OpenFlags, SIGCONT, SIGHUP, WUNTRACED,
+};
+
+/// One stage of a pipeline.
+#[derive(Default)]
+struct Command {
+    args: Vec<String>,
+    input: Option<String>,
+    output: Option<String>,
+}
+
+struct Job {
+    /// 0 until it goes to the job table.
+    id: usize,
+    pgid: usize,
+    /// Processes that have not exited yet.
+    pids: Vec<usize>,
+    /// Of the last stage.
+    exit_code: i32,
+    stopped: bool,
+    line: String,
+}
+
+struct Shell {
+    /// Own process group, which gets the terminal back after each job.
+    pgid: usize,
+    /// Cooked settings of the terminal, `None` without one.
+    termios: Option<Termios>,
+    history: Vec<String>,
+    jobs: Vec<Job>,
+    next_job_id: usize,
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    println!("Rust user shell");
+    // lead a session with the console as its terminal, for job control
+    setsid();
+    tcsetsid(0);
+    let mut termios = Termios::default();
+    let mut shell = Shell {
+        pgid: getpgid(0) as usize,
+        termios: if tcgetattr(0, &mut termios) == 0 {
+            Some(termios)
+        } else {
+            None
+        },
+        history: Vec::new(),
+        jobs: Vec::new(),
+        next_job_id: 1,
+    };
+    loop {
+        shell.reap_jobs();
+        print!(">> ");
+        let line = shell.read_line();
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        shell.remember(line);
+        let (commands, background) = match parse(line) {
+            Ok(parsed) => parsed,
+            Err(message) => {
+                println!("Shell: {}", message);
+                continue;
+            }
+        };
+        if !background && commands.len() == 1 {
+            let args = &commands[0].args;
+            match args[0].as_str() {
+                "exit" => {
+                    shell.hang_up();
+                    return args.get(1).and_then(|code| code.parse().ok()).unwrap_or(0);
+                }
+                "jobs" => {
+                    shell.list_jobs();
+                    continue;
+                }
+                "fg" => {
+                    shell.foreground(args.get(1).map(String::as_str));
+                    continue;
+                }
+                "bg" => {
+                    shell.resume_in_background(args.get(1).map(String::as_str));
+                    continue;
+                }
+                "history" => {
+                    for (i, line) in shell.history.iter().enumerate() {
+                        println!("{:>4}  {}", i + 1, line);
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        let (pgid, pids) = start(&commands, !background);
+        if pids.is_empty() {
+            continue;
+        }
+        let job = Job {
+            id: 0,
+            pgid,
+            pids,
+            exit_code: 0,
+            stopped: false,
+            line: String::from(line),
+        };
+        if background {
+            let id = shell.add_job(job);
+            println!("[{}] {}", id, pgid);
+        } else {
+            shell.wait_job(job);
+        }
+    }
+}
+
+impl Shell {
+    /// Reads a line in raw mode, with backspace, the up and down arrows
+    /// going through the history and ^C dropping the line.
+    fn read_line(&self) -> String {
+        if let Some(cooked) = &self.termios {
+            let mut raw = *cooked;
+            raw.make_raw();
+            tcsetattr(0, &raw);
+        }
+        let line = self.edit_line();
+        if let Some(cooked) = &self.termios {
+            tcsetattr(0, cooked);
+        }
+        line
+    }
+
+    fn edit_line(&self) -> String {
+        let mut line = String::new();
+        // the line being typed is at `history.len()`
+        let mut index = self.history.len();
+        let mut draft = String::new();
+        loop {
+            let c = getchar();
+            match c {
+                LF | CR => {
+                    println!("");
+                    return line;
+                }
+                CTRL_C => {
+                    println!("^C");
+                    return String::new();
+                }
+                BS | DL => {
+                    if line.pop().is_some() {
+                        print!("{0} {0}", BS as char);
+                    }
+                }
+                ESC => {
+                    if getchar() != b'[' {
+                        continue;
+                    }
+                    let recalled = match getchar() {
+                        b'A' if index > 0 => {
+                            if index == self.history.len() {
+                                draft = line.clone();
+                            }
+                            index -= 1;
+                            &self.history[index]
+                        }
+                        b'B' if index < self.history.len() => {
+                            index += 1;
+                            self.history.get(index).unwrap_or(&draft)
+                        }
+                        _ => continue,
+                    };
+                    for _ in 0..line.len() {
+                        print!("{0} {0}", BS as char);
+                    }
+                    line = recalled.clone();
+                    print!("{}", line);
+                }
+                _ => {
+                    print!("{}", c as char);
+                    line.push(c as char);
+                }
+            }
+        }
+    }
+
+    fn remember(&mut self, line: &str) {
+        if self.history.last().map(String::as_str) != Some(line) {
+            if self.history.len() == HISTORY_LEN {
+                self.history.remove(0);
+            }
+            self.history.push(String::from(line));
+        }
+    }
+
+    fn give_terminal(&self, pgid: usize) {
+        if self.termios.is_some() {
+            tcsetpgrp(0, pgid);
+        }
+    }
+
+    /// Puts the job in the table, keeping the id it had there before.
+    fn add_job(&mut self, mut job: Job) -> usize {
+        if job.id == 0 {
+            job.id = self.next_job_id;
+            self.next_job_id += 1;
+        }
+        let id = job.id;
+        let idx = self.jobs.iter().position(|other| other.id > id);
+        self.jobs.insert(idx.unwrap_or(self.jobs.len()), job);
+        id
+    }
+
+    /// Collects exited and stopped children, and reports the background
+    /// jobs that changed.
+    fn reap_jobs(&mut self) {
+        loop {
+            let mut status: i32 = 0;
+            let pid = try_waitpid(-1, &mut status, WUNTRACED);
+            if pid < 0 {
+                break;
+            }
+            let pid = pid as usize;
+            if let Some(job) = self.jobs.iter_mut().find(|job| job.pids.contains(&pid)) {
+                if stop_signal(status).is_some() {
+                    if !job.stopped {
+                        job.stopped = true;
+                        println!("[{}] Stopped {}", job.id, job.line);
+                    }
+                } else {
+                    if job.pids.last() == Some(&pid) {
+                        job.exit_code = status;
+                    }
+                    job.pids.retain(|&p| p != pid);
+                }
+            }
+        }
+        self.jobs.retain(|job| {
+            if job.pids.is_empty() {
+                println!("[{}] Done({}) {}", job.id, job.exit_code, job.line);
+            }
+            !job.pids.is_empty()
+        });
+        if self.jobs.is_empty() {
+            self.next_job_id = 1;
+        }
+    }
+
+    fn list_jobs(&self) {
+        for job in self.jobs.iter() {
+            let state = if job.stopped { "Stopped" } else { "Running" };
+            println!("[{}] {} {}", job.id, state, job.line);
+        }
+    }
+
+    /// The index of job `[%]n`, of the latest job without an argument.
+    fn find_job(&self, id: Option<&str>) -> Option<usize> {
+        match id {
+            Some(id) => id
+                .trim_start_matches('%')
+                .parse::<usize>()
+                .ok()
+                .and_then(|id| self.jobs.iter().position(|job| job.id == id)),
+            None => self.jobs.len().checked_sub(1),
+        }
+    }
+
+    fn foreground(&mut self, id: Option<&str>) {
+        match self.find_job(id) {
+            Some(idx) => {
+                let mut job = self.jobs.remove(idx);
+                println!("{}", job.line);
+                if job.stopped {
+                    job.stopped = false;
+                    kill(-(job.pgid as isize), SIGCONT);
+                }
+                self.wait_job(job);
+            }
+            None => println!("Shell: fg: no such job"),
+        }
+    }
+
+    fn resume_in_background(&mut self, id: Option<&str>) {
+        match self.find_job(id).map(|idx| &mut self.jobs[idx]) {
+            Some(job) if job.stopped => {
+                job.stopped = false;
+                kill(-(job.pgid as isize), SIGCONT);
+                println!("[{}] {} &", job.id, job.line);
+            }
+            Some(job) => println!("Shell: bg: job {} is running already", job.id),
+            None => println!("Shell: bg: no such job"),
+        }
+    }
+
+    /// Waits with the job owning the terminal, until it exits or stops.
+    fn wait_job(&mut self, mut job: Job) {
+        self.give_terminal(job.pgid);
+        let last = *job.pids.last().unwrap();
+        while !job.pids.is_empty() && !job.stopped {
+            let mut changed = false;
+            for pid in job.pids.clone() {
+                let mut status: i32 = 0;
+                match try_waitpid(pid as isize, &mut status, WUNTRACED) {
+                    -2 => continue,
+                    found if found > 0 && stop_signal(status).is_some() => job.stopped = true,
+                    found => {
+                        // below 0 it was reaped already
+                        if found > 0 && pid == last {
+                            job.exit_code = status;
+                        }
+                        job.pids.retain(|&p| p != pid);
+                    }
+                }
+                changed = true;
+            }
+            if !changed {
+                yield_();
+            }
+        }
+        self.give_terminal(self.pgid);
+        if job.stopped {
+            println!("");
+            let id = self.add_job(job);
+            let job = self.jobs.iter().find(|job| job.id == id).unwrap();
+            println!("[{}] Stopped {}", id, job.line);
+        } else {
+            println!("Shell: Process {} exited with code {}", last, job.exit_code);
+        }
+    }
+
+    /// Nobody could continue the stopped jobs once the shell is gone.
+    fn hang_up(&self) {
+        for job in self.jobs.iter().filter(|job| job.stopped) {
+            kill(-(job.pgid as isize), SIGHUP);
+            kill(-(job.pgid as isize), SIGCONT);
+        }
+    }
+}
+
+/// Splits off `|`, `<`, `>` and `&` even without spaces around them.
+fn tokenize(line: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    for c in line.chars() {
+        match c {
+            ' ' | '\t' | '|' | '<' | '>' | '&' => {
+                if !token.is_empty() {
+                    tokens.push(core::mem::take(&mut token));
+                }
+                if c != ' ' && c != '\t' {
+                    tokens.push(String::from(c));
+                }
+            }
+            _ => token.push(c),
+        }
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+    tokens
+}
+
+/// The stages of the pipeline, and whether it runs in the background.
+fn parse(line: &str) -> Result<(Vec<Command>, bool), &'static str> {
+    let mut tokens = tokenize(line);
+    let background = tokens.last().map(String::as_str) == Some("&");
+    if background {
+        tokens.pop();
+    }
+    let mut commands = Vec::new();
+    let mut command = Command::default();
+    let mut tokens = tokens.into_iter();
+    while let Some(token) = tokens.next() {
+        match token.as_str() {
+            "|" => {
+                if command.args.is_empty() {
+                    return Err("empty command in pipeline");
+                }
+                commands.push(core::mem::take(&mut command));
+            }
+            "<" => command.input = Some(tokens.next().ok_or("missing file after <")?),
+            ">" => command.output = Some(tokens.next().ok_or("missing file after >")?),
+            "&" => return Err("& only goes at the end of a line"),
+            _ => command.args.push(token),
+        }
+    }
+    if command.args.is_empty() {
+        return Err("empty command in pipeline");
+    }
+    commands.push(command);
+    Ok((commands, background))
+}
+
+/// Moves `fd` to `target`.
+fn redirect(fd: usize, target: usize) {
+    close(target);
+    assert_eq!(dup(fd), target as isize);
+    close(fd);
+}
+
+fn open_file(path: &str, flags: OpenFlags) -> usize {
+    let mut path = String::from(path);
+    path.push('\0');
+    let fd = open(path.as_str(), flags);
+    if fd < 0 {
+        println!(
+            "Error when opening file {}: {}",
+            path.trim_end_matches('\0'),
+            fd
+        );
+        exit(-4);
+    }
+    fd as usize
+}
+
+/// Forks each stage into one new process group connected by pipes, which
+/// takes the terminal if it runs in the foreground. Returns the group and
+/// the pids.
+fn start(commands: &[Command], foreground: bool) -> (usize, Vec<usize>) {
+    let mut pgid = 0;
+    let mut pids = Vec::new();
+    let mut prev_read: Option<usize> = None;
+    for (i, command) in commands.iter().enumerate() {
+        let mut pipe_fd = [0usize; 2];
+        let next = if i + 1 < commands.len() {
+            if pipe(&mut pipe_fd) < 0 {
+                println!("Shell: cannot create a pipe");
+                break;
+            }
+            Some(pipe_fd)
+        } else {
+            None
+        };
+        let pid = fork();
+        if pid < 0 {
+            println!("Shell: fork failed");
+            if let Some([read_fd, write_fd]) = next {
+                close(read_fd);
+                close(write_fd);
+            }
+            break;
+        }
+        if pid == 0 {
+            setpgid(0, pgid);
+            if foreground {
+                // before it could read the terminal as a background group
+                tcsetpgrp(0, getpgid(0) as usize);
+            }
+            if let Some(fd) = prev_read {
+                redirect(fd, 0);
+            }
+            if let Some([read_fd, write_fd]) = next {
+                close(read_fd);
+                redirect(write_fd, 1);
+            }
+            if let Some(input) = &command.input {
+                redirect(open_file(input, OpenFlags::RDONLY), 0);
+            }
+            if let Some(output) = &command.output {
+                let flags = OpenFlags::CREATE | OpenFlags::WRONLY | OpenFlags::TRUNC;
+                redirect(open_file(output, flags), 1);
+            }
+            let args: Vec<String> = command
+                .args
+                .iter()
+                .map(|arg| {
+                    let mut arg = arg.clone();
+                    arg.push('\0');
+                    arg
+                })
+                .collect();
+            let mut args_addr: Vec<*const u8> = args.iter().map(|arg| arg.as_ptr()).collect();
+            args_addr.push(core::ptr::null());
+            exec(args[0].as_str(), args_addr.as_slice());
+            println!("Error when executing {}!", command.args[0]);
+            exit(-4);
+        }
+        // also here, so the group exists before either side goes on
+        setpgid(pid as usize, pgid);
+        if pgid == 0 {
+            pgid = pid as usize;
+        }
+        pids.push(pid as usize);
+        if let Some(fd) = prev_read.take() {
+            close(fd);
+        }
+        if let Some([read_fd, write_fd]) = next {
+            close(write_fd);
+            prev_read = Some(read_fd);
+        }
+    }
+    if let Some(fd) = prev_read {
+        close(fd);
+    }
+    (pgid, pids)
+}
@@ -20,7 +20,9 @@ pub use trap::{UserTrapContext, UserTrapRecord};
 #[macro_use]
 pub mod console;
 mod lang_items;
+pub mod serial;
 mod syscall;
+pub mod termios;
 mod trap;
 pub mod user_uart;
 pub mod async_rt;
@@ -83,6 +85,7 @@ bitflags! {
         const RDWR = 1 << 1;
         const CREATE = 1 << 9;
         const TRUNC = 1 << 10;
+        const NONBLOCK = 1 << 11;
     }
 }
 
@@ -131,18 +134,94 @@ impl TimeVal {
     }
 }
 
+pub const CLOCK_REALTIME: usize = 0;
+pub const CLOCK_MONOTONIC: usize = 1;
+pub const CLOCK_PROCESS_CPUTIME_ID: usize = 2;
+pub const CLOCK_THREAD_CPUTIME_ID: usize = 3;
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TimeSpec {
+    pub sec: usize,
+    pub nsec: usize,
+}
+
+impl TimeSpec {
+    pub fn as_nanos(&self) -> usize {
+        self.sec * 1_000_000_000 + self.nsec
+    }
+    pub fn as_micros(&self) -> usize {
+        self.sec * 1_000_000 + self.nsec / 1000
+    }
+    pub fn as_millis(&self) -> usize {
+        self.sec * 1000 + self.nsec / 1_000_000
+    }
+}
+
+/// Read-only page the kernel maps below `USER_TRAP_BUFFER`, same layout as in the kernel.
+const TIME_PAGE: usize = usize::MAX - 4 * 0x1000 + 1;
+
+#[repr(C)]
+struct TimePage {
+    clock_freq: usize,
+    realtime_offset: usize,
+}
+
+fn time_page() -> &'static TimePage {
+    unsafe { &*(TIME_PAGE as *const TimePage) }
+}
+
+fn rdtime() -> usize {
+    let time: usize;
+    unsafe {
+        llvm_asm!("rdtime $0" : "=r"(time) ::: "volatile");
+    }
+    time
+}
+
+fn monotonic_time() -> TimeSpec {
+    let freq = time_page().clock_freq;
+    let ticks = rdtime();
+    TimeSpec {
+        sec: ticks / freq,
+        nsec: ticks % freq * 1_000_000_000 / freq,
+    }
+}
+
+/// The monotonic and realtime clocks are read from the time page without a syscall.
+pub fn clock_gettime(clock_id: usize, ts: &mut TimeSpec) -> isize {
+    match clock_id {
+        CLOCK_MONOTONIC => *ts = monotonic_time(),
+        CLOCK_REALTIME => {
+            let offset = unsafe { core::ptr::read_volatile(&time_page().realtime_offset) };
+            let nanos = monotonic_time().as_nanos() + offset;
+            ts.sec = nanos / 1_000_000_000;
+            ts.nsec = nanos % 1_000_000_000;
+        }
+        _ => return sys_clock_gettime(clock_id, ts),
+    }
+    0
+}
+
+/// Only `CLOCK_REALTIME` is settable.
+pub fn clock_settime(clock_id: usize, ts: &TimeSpec) -> isize {
+    sys_clock_settime(clock_id, ts)
+}
+
+/// Milliseconds since boot.
 pub fn get_time() -> isize {
-    let time = TimeVal::new();
-    match sys_get_time(&time, 0) {
-        0 => ((time.sec & 0xffff) * 1000 + time.usec / 1000) as isize,
+    let mut time = TimeSpec::default();
+    match clock_gettime(CLOCK_MONOTONIC, &mut time) {
+        0 => time.as_millis() as isize,
         _ => -1,
     }
 }
 
+/// Microseconds since boot.
 pub fn get_time_us() -> isize {
-    let time = TimeVal::new();
-    match sys_get_time(&time, 0) {
-        0 => ((time.sec & 0xffff) * 1000_0000 + time.usec) as isize,
+    let mut time = TimeSpec::default();
+    match clock_gettime(CLOCK_MONOTONIC, &mut time) {
+        0 => time.as_micros() as isize,
         _ => -1,
     }
 }
@@ -151,6 +230,77 @@ pub fn getpid() -> isize {
     sys_getpid()
 }
 
+pub const SOCK_STREAM: usize = 1;
+pub const SOCK_DGRAM: usize = 2;
+
+pub fn socket(kind: usize) -> isize {
+    sys_socket(kind)
+}
+
+pub fn bind(fd: usize, port: u16) -> isize {
+    sys_bind(fd, port)
+}
+
+pub fn listen(fd: usize) -> isize {
+    sys_listen(fd)
+}
+
+pub fn accept(fd: usize) -> isize {
+    sys_accept(fd)
+}
+
+/// `addr` as a number, `0x0a000202` for 10.0.2.2.
+pub fn connect(fd: usize, addr: u32, port: u16) -> isize {
+    sys_connect(fd, addr, port)
+}
+
+/// `pid` 0 is the caller, `pgid` 0 starts a new group led by `pid`.
+pub fn setpgid(pid: usize, pgid: usize) -> isize {
+    sys_setpgid(pid, pgid)
+}
+
+pub fn getpgid(pid: usize) -> isize {
+    sys_getpgid(pid)
+}
+
+/// Starts a new session led by the caller, returns its id.
+pub fn setsid() -> isize {
+    sys_setsid()
+}
+
+pub fn getsid(pid: usize) -> isize {
+    sys_getsid(pid)
+}
+
+pub const SIGHUP: usize = 1;
+pub const SIGINT: usize = 2;
+pub const SIGQUIT: usize = 3;
+pub const SIGKILL: usize = 9;
+pub const SIGTERM: usize = 15;
+pub const SIGCONT: usize = 18;
+pub const SIGSTOP: usize = 19;
+pub const SIGTSTP: usize = 20;
+pub const SIGTTIN: usize = 21;
+
+/// `pid` 0 is the caller's process group, below -1 the group `-pid`.
+pub fn kill(pid: isize, signo: usize) -> isize {
+    sys_kill(pid, signo)
+}
+
+pub const F_GETFL: usize = 3;
+pub const F_SETFL: usize = 4;
+/// What reads on an `O_NONBLOCK` fd return when nothing is there.
+pub const EAGAIN: isize = -11;
+
+/// `F_GETFL` and `F_SETFL` take `OpenFlags::NONBLOCK` bits.
+pub fn fcntl(fd: usize, cmd: usize, arg: usize) -> isize {
+    sys_fcntl(fd, cmd, arg)
+}
+
+pub fn ioctl(fd: usize, request: usize, arg: usize) -> isize {
+    sys_ioctl(fd, request, arg)
+}
+
 pub fn fork() -> isize {
     sys_fork()
 }
@@ -165,7 +315,7 @@ pub fn spawn(path: &str) -> isize {
 
 pub fn wait(exit_code: &mut i32) -> isize {
     loop {
-        match sys_waitpid(-1, exit_code as *mut _) {
+        match sys_waitpid(-1, exit_code as *mut _, 0) {
             -2 => {
                 yield_();
             }
@@ -177,7 +327,7 @@ pub fn wait(exit_code: &mut i32) -> isize {
 
 pub fn waitpid(pid: usize, exit_code: &mut i32) -> isize {
     loop {
-        match sys_waitpid(pid as isize, exit_code as *mut _) {
+        match sys_waitpid(pid as isize, exit_code as *mut _, 0) {
             -2 => {
                 yield_();
             }
@@ -187,6 +337,24 @@ pub fn waitpid(pid: usize, exit_code: &mut i32) -> isize {
     }
 }
 
+pub const WUNTRACED: usize = 2;
+
+/// Like `waitpid` without waiting: -2 while the child is still running.
+/// `pid` below -1 matches any child in process group `-pid`, and with
+/// `WUNTRACED` a stopped child is reported too, see `stop_signal`.
+pub fn try_waitpid(pid: isize, exit_code: &mut i32, options: usize) -> isize {
+    sys_waitpid(pid, exit_code as *mut _, options)
+}
+
+/// The signal that stopped a child, for a status from `try_waitpid`.
+pub fn stop_signal(status: i32) -> Option<usize> {
+    if status & 0xff == 0x7f && status >> 8 > 0 {
+        Some((status >> 8) as usize)
+    } else {
+        None
+    }
+}
+
 pub fn sleep(period_ms: usize) {
     let start = get_time();
     while get_time() < start + period_ms as isize {
@@ -203,7 +371,46 @@ pub fn send_msg(pid: usize, msg: usize) -> isize {
 }
 
 pub fn set_timer(time_us: isize) -> isize {
-    sys_set_timer(time_us)
+    sys_set_timer(time_us, 0)
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct TimerSpec {
+    /// Next expiry in us, 0 if stopped.
+    pub next_us: usize,
+    pub interval_us: usize,
+    /// Periods missed before the last delivery.
+    pub overrun: usize,
+}
+
+/// Fires at the absolute `first_us`, then every `interval_us`.
+pub fn set_periodic_timer(first_us: isize, interval_us: usize) -> isize {
+    sys_set_timer(first_us, interval_us)
+}
+
+pub fn stop_periodic_timer() -> isize {
+    sys_set_timer(0, 0)
+}
+
+pub fn get_periodic_timer(spec: &mut TimerSpec) -> isize {
+    sys_get_timer(spec)
+}
+
+pub const TIMER_ABSTIME: usize = 1;
+
+/// Returns a timer id, or a negative error.
+pub fn timer_create() -> isize {
+    sys_timer_create()
+}
+
+/// Relative `value_us` unless `TIMER_ABSTIME` is in `flags`, 0 disarms the timer.
+pub fn timer_settime(id: usize, value_us: usize, interval_us: usize, flags: usize) -> isize {
+    sys_timer_settime(id, value_us, interval_us, flags)
+}
+
+pub fn timer_delete(id: usize) -> isize {
+    sys_timer_delete(id)
 }
 
 pub fn claim_ext_int(device_id: usize) -> isize {
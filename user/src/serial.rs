@@ -0,0 +1,44 @@
+//! Line settings of the serial ports under `/dev/ttyS<n>`.
+
+use super::ioctl;
+
+pub const PARITY_NONE: u8 = 0;
+pub const PARITY_ODD: u8 = 1;
+pub const PARITY_EVEN: u8 = 2;
+pub const FLOW_NONE: u8 = 0;
+pub const FLOW_RTS_CTS: u8 = 1;
+
+const TCSBRK: usize = 0x5409;
+const SERIAL_GET_CONFIG: usize = 0x5480;
+const SERIAL_SET_CONFIG: usize = 0x5481;
+/// Asks for a trap record with the argument as message once what was
+/// written so far is sent, `AsyncDrain` uses it.
+pub const SERIAL_NOTIFY_TX: usize = 0x5482;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SerialConfig {
+    pub baud_rate: u32,
+    /// 5 to 8.
+    pub data_bits: u8,
+    pub parity: u8,
+    /// 1 or 2.
+    pub stop_bits: u8,
+    pub flow_control: u8,
+    /// Bytes in the Rx FIFO that raise an interrupt: 1, 4, 8 or 14.
+    pub rx_trigger: u8,
+}
+
+pub fn get_serial_config(fd: usize, config: &mut SerialConfig) -> isize {
+    ioctl(fd, SERIAL_GET_CONFIG, config as *mut _ as usize)
+}
+
+/// Fails with EINVAL on settings the port cannot take, changing nothing.
+pub fn set_serial_config(fd: usize, config: &SerialConfig) -> isize {
+    ioctl(fd, SERIAL_SET_CONFIG, config as *const _ as usize)
+}
+
+/// Waits until everything written to the port is sent.
+pub fn tcdrain(fd: usize) -> isize {
+    ioctl(fd, TCSBRK, 1)
+}
@@ -1,15 +1,32 @@
-use crate::TimeVal;
+use crate::{TimeSpec, TimeVal, TimerSpec};
 
 const SYSCALL_DUP: usize = 24;
+const SYSCALL_FCNTL: usize = 25;
+pub const SYSCALL_IOCTL: usize = 29;
 const SYSCALL_OPEN: usize = 56;
 pub const SYSCALL_CLOSE: usize = 57;
 pub const SYSCALL_PIPE: usize = 59;
 pub const SYSCALL_READ: usize = 63;
 pub const SYSCALL_WRITE: usize = 64;
 const SYSCALL_EXIT: usize = 93;
+const SYSCALL_TIMER_CREATE: usize = 107;
+const SYSCALL_TIMER_SETTIME: usize = 110;
+const SYSCALL_TIMER_DELETE: usize = 111;
+const SYSCALL_CLOCK_SETTIME: usize = 112;
+const SYSCALL_CLOCK_GETTIME: usize = 113;
 const SYSCALL_YIELD: usize = 124;
+const SYSCALL_KILL: usize = 129;
+const SYSCALL_SETPGID: usize = 154;
+const SYSCALL_GETPGID: usize = 155;
+const SYSCALL_GETSID: usize = 156;
+const SYSCALL_SETSID: usize = 157;
 const SYSCALL_GET_TIME: usize = 169;
 const SYSCALL_GETPID: usize = 172;
+const SYSCALL_SOCKET: usize = 198;
+const SYSCALL_BIND: usize = 200;
+const SYSCALL_LISTEN: usize = 201;
+const SYSCALL_ACCEPT: usize = 202;
+const SYSCALL_CONNECT: usize = 203;
 const SYSCALL_FORK: usize = 220;
 const SYSCALL_EXEC: usize = 221;
 const SYSCALL_WAITPID: usize = 260;
@@ -19,6 +36,7 @@ const SYSCALL_SEND_MSG: usize = 601;
 const SYSCALL_SET_TIMER: usize = 602;
 const SYSCALL_CLAIM_EXT_INT: usize = 603;
 const SYSCALL_SET_EXT_INT_ENABLE: usize = 604;
+const SYSCALL_GET_TIMER: usize = 605;
 
 pub(crate) fn syscall(id: usize, args: [usize; 4]) -> isize {
     let mut ret: isize;
@@ -37,6 +55,14 @@ pub fn sys_dup(fd: usize) -> isize {
     syscall(SYSCALL_DUP, [fd, 0, 0, 0])
 }
 
+pub fn sys_fcntl(fd: usize, cmd: usize, arg: usize) -> isize {
+    syscall(SYSCALL_FCNTL, [fd, cmd, arg, 0])
+}
+
+pub fn sys_ioctl(fd: usize, request: usize, arg: usize) -> isize {
+    syscall(SYSCALL_IOCTL, [fd, request, arg, 0])
+}
+
 pub fn sys_open(path: &str, flags: u32) -> isize {
     syscall(SYSCALL_OPEN, [path.as_ptr() as usize, flags as usize, 0, 0])
 }
@@ -70,14 +96,63 @@ pub fn sys_yield() -> isize {
 }
 
 #[allow(unused_variables)]
+#[allow(dead_code)]
 pub fn sys_get_time(time: &TimeVal, tz: usize) -> isize {
     syscall(SYSCALL_GET_TIME, [time as *const _ as usize, tz, 0, 0])
 }
 
+pub fn sys_clock_gettime(clock_id: usize, ts: &mut TimeSpec) -> isize {
+    syscall(SYSCALL_CLOCK_GETTIME, [clock_id, ts as *mut _ as usize, 0, 0])
+}
+
+pub fn sys_clock_settime(clock_id: usize, ts: &TimeSpec) -> isize {
+    syscall(SYSCALL_CLOCK_SETTIME, [clock_id, ts as *const _ as usize, 0, 0])
+}
+
 pub fn sys_getpid() -> isize {
     syscall(SYSCALL_GETPID, [0, 0, 0, 0])
 }
 
+pub fn sys_socket(kind: usize) -> isize {
+    syscall(SYSCALL_SOCKET, [kind, 0, 0, 0])
+}
+
+pub fn sys_bind(fd: usize, port: u16) -> isize {
+    syscall(SYSCALL_BIND, [fd, port as usize, 0, 0])
+}
+
+pub fn sys_listen(fd: usize) -> isize {
+    syscall(SYSCALL_LISTEN, [fd, 0, 0, 0])
+}
+
+pub fn sys_accept(fd: usize) -> isize {
+    syscall(SYSCALL_ACCEPT, [fd, 0, 0, 0])
+}
+
+pub fn sys_connect(fd: usize, addr: u32, port: u16) -> isize {
+    syscall(SYSCALL_CONNECT, [fd, addr as usize, port as usize, 0])
+}
+
+pub fn sys_setpgid(pid: usize, pgid: usize) -> isize {
+    syscall(SYSCALL_SETPGID, [pid, pgid, 0, 0])
+}
+
+pub fn sys_getpgid(pid: usize) -> isize {
+    syscall(SYSCALL_GETPGID, [pid, 0, 0, 0])
+}
+
+pub fn sys_getsid(pid: usize) -> isize {
+    syscall(SYSCALL_GETSID, [pid, 0, 0, 0])
+}
+
+pub fn sys_setsid() -> isize {
+    syscall(SYSCALL_SETSID, [0, 0, 0, 0])
+}
+
+pub fn sys_kill(pid: isize, signo: usize) -> isize {
+    syscall(SYSCALL_KILL, [pid as usize, signo, 0, 0])
+}
+
 pub fn sys_fork() -> isize {
     syscall(SYSCALL_FORK, [0, 0, 0, 0])
 }
@@ -93,8 +168,8 @@ pub fn sys_spawn(path: &str) -> isize {
     syscall(SYSCALL_SPAWN, [path.as_ptr() as usize, 0, 0, 0])
 }
 
-pub fn sys_waitpid(pid: isize, exit_code: *mut i32) -> isize {
-    syscall(SYSCALL_WAITPID, [pid as usize, exit_code as usize, 0, 0])
+pub fn sys_waitpid(pid: isize, exit_code: *mut i32, options: usize) -> isize {
+    syscall(SYSCALL_WAITPID, [pid as usize, exit_code as usize, options, 0])
 }
 
 pub fn sys_init_user_trap() -> isize {
@@ -105,8 +180,24 @@ pub fn sys_send_msg(pid: usize, msg: usize) -> isize {
     syscall(SYSCALL_SEND_MSG, [pid as usize, msg as usize, 0, 0])
 }
 
-pub fn sys_set_timer(time_us: isize) -> isize {
-    syscall(SYSCALL_SET_TIMER, [time_us as usize, 0, 0, 0])
+pub fn sys_set_timer(time_us: isize, interval_us: usize) -> isize {
+    syscall(SYSCALL_SET_TIMER, [time_us as usize, interval_us, 0, 0])
+}
+
+pub fn sys_get_timer(spec: &mut TimerSpec) -> isize {
+    syscall(SYSCALL_GET_TIMER, [spec as *mut _ as usize, 0, 0, 0])
+}
+
+pub fn sys_timer_create() -> isize {
+    syscall(SYSCALL_TIMER_CREATE, [0, 0, 0, 0])
+}
+
+pub fn sys_timer_settime(id: usize, value_us: usize, interval_us: usize, flags: usize) -> isize {
+    syscall(SYSCALL_TIMER_SETTIME, [id, value_us, interval_us, flags])
+}
+
+pub fn sys_timer_delete(id: usize) -> isize {
+    syscall(SYSCALL_TIMER_DELETE, [id, 0, 0, 0])
 }
 
 pub fn sys_claim_ext_int(device_id: usize) -> isize {
@@ -0,0 +1,75 @@
+//! Terminal settings of the console, laid out as on Linux.
+
+use super::ioctl;
+
+pub const NCCS: usize = 19;
+
+pub const ICRNL: u32 = 0o400;
+pub const OPOST: u32 = 0o1;
+pub const ONLCR: u32 = 0o4;
+pub const ISIG: u32 = 0o1;
+pub const ICANON: u32 = 0o2;
+pub const ECHO: u32 = 0o10;
+pub const ECHOE: u32 = 0o20;
+pub const ECHOK: u32 = 0o40;
+
+pub const VINTR: usize = 0;
+pub const VQUIT: usize = 1;
+pub const VERASE: usize = 2;
+pub const VKILL: usize = 3;
+pub const VEOF: usize = 4;
+pub const VMIN: usize = 6;
+pub const VSUSP: usize = 10;
+
+const TCGETS: usize = 0x5401;
+const TCSETS: usize = 0x5402;
+const TIOCSCTTY: usize = 0x540e;
+const TIOCGPGRP: usize = 0x540f;
+const TIOCSPGRP: usize = 0x5410;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Termios {
+    pub iflag: u32,
+    pub oflag: u32,
+    pub cflag: u32,
+    pub lflag: u32,
+    pub line: u8,
+    pub cc: [u8; NCCS],
+}
+
+impl Termios {
+    /// Bytes as they come, no echo and no signals from ^C or ^Z.
+    pub fn make_raw(&mut self) {
+        self.iflag &= !ICRNL;
+        self.lflag &= !(ICANON | ECHO | ISIG);
+        self.cc[VMIN] = 1;
+    }
+}
+
+pub fn tcgetattr(fd: usize, termios: &mut Termios) -> isize {
+    ioctl(fd, TCGETS, termios as *mut _ as usize)
+}
+
+pub fn tcsetattr(fd: usize, termios: &Termios) -> isize {
+    ioctl(fd, TCSETS, termios as *const _ as usize)
+}
+
+pub fn tcgetpgrp(fd: usize) -> isize {
+    let mut pgid: u32 = 0;
+    match ioctl(fd, TIOCGPGRP, &mut pgid as *mut _ as usize) {
+        0 => pgid as isize,
+        errno => errno,
+    }
+}
+
+pub fn tcsetpgrp(fd: usize, pgid: usize) -> isize {
+    let pgid = pgid as u32;
+    ioctl(fd, TIOCSPGRP, &pgid as *const _ as usize)
+}
+
+/// Makes the terminal the controlling one of the caller's session, which
+/// the caller must lead.
+pub fn tcsetsid(fd: usize) -> isize {
+    ioctl(fd, TIOCSCTTY, 0)
+}
@@ -153,8 +153,23 @@ impl Write<u8> for BufferedSerial {
         Ok(())
     }
 
+    /// Done once `tx_buffer` is drained and THR is empty.
+    #[cfg(any(feature = "board_qemu", feature = "board_lrv"))]
     fn try_flush(&mut self) -> nb::Result<(), Self::Error> {
-        todo!()
+        let serial = &mut self.hardware;
+        if serial.is_transmitter_holding_register_empty() {
+            for _ in 0..FIFO_DEPTH {
+                if let Some(ch) = self.tx_buffer.pop_front() {
+                    serial.write_byte(ch);
+                    self.tx_count += 1;
+                }
+            }
+        }
+        if self.tx_buffer.is_empty() && serial.is_transmitter_holding_register_empty() {
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
     }
 }
 
@@ -226,8 +241,14 @@ impl Write<u8> for PollingSerial {
         Ok(())
     }
 
+    #[cfg(any(feature = "board_qemu", feature = "board_lrv"))]
     fn try_flush(&mut self) -> nb::Result<(), Self::Error> {
-        todo!()
+        if self.hardware.lsr().contains(LSR::THRE) {
+            self.tx_fifo_count = 0;
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
     }
 }
 